@@ -0,0 +1,37 @@
+//! Serves the store's metrics on a Prometheus endpoint while performing some operations.
+//!
+//! Run with `cargo run --example metrics_server --features metrics` and scrape
+//! `http://127.0.0.1:9000/metrics`.
+
+use kvs::{KvStore, Result};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn main() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:9000".parse()?;
+    PrometheusBuilder::new()
+        .with_http_listener(addr)
+        .install()?;
+
+    let temp_dir = TempDir::new()?;
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+            store.get(format!("key{}", key_id))?;
+        }
+    }
+    for key_id in 0..50 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.compact_log()?;
+
+    println!("Serving metrics on http://{}/metrics", addr);
+    loop {
+        thread::sleep(Duration::from_secs(60));
+    }
+}
@@ -2,14 +2,27 @@
 
 //! An on-disk compactable, indexed key-value log implementation.
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use crate::clock::{Clock, SystemClock};
+use crate::storage::{FileStorage, LogStorage, MemoryStorage};
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
 use failure::{Error, Fail};
+use log::{debug, error, info, warn};
+use lru::LruCache;
+#[cfg(feature = "encryption")]
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+#[cfg(feature = "encryption")]
+use ring::rand::{SecureRandom, SystemRandom};
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::hash::BuildHasher;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
 
 /// The Result type used by all functions in the AppendLog.
 pub type Result<T> = std::result::Result<T, Error>;
@@ -19,380 +32,4010 @@ pub type Result<T> = std::result::Result<T, Error>;
 /// Error when the path passed in is not a valid log file.
 pub struct InvalidLogFileError;
 
-/// Commands that can be issued into the AppendLog.
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub enum LogCommand {
-    /// Set a value into the log, this will udate the index.
-    Set,
-    /// Remove a value from the log. This value will be immediately removed from the index and removed from the file on compaction.
-    Remove,
+#[derive(Fail, Debug)]
+#[fail(display = "Corrupt log entry at offset {}", offset)]
+/// Error when a log entry fails its checksum.
+pub struct CorruptLogError {
+    offset: u64,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct LogEntry {
-    cmd: LogCommand,
-    key: Box<[u8]>,
-    val: Option<Box<[u8]>>,
+#[derive(Fail, Debug)]
+#[fail(
+    display = "Unsupported log entry version {} at offset {}",
+    version, offset
+)]
+/// Error when a log entry was written in a format this version does not know how to read.
+pub struct UnsupportedEntryVersionError {
+    offset: u64,
+    version: u8,
 }
 
-impl LogEntry {
-    fn new(cmd: LogCommand, key: &[u8], val: Option<&[u8]>) -> LogEntry {
-        let key = Box::from(key);
-        let val = match val {
-            Some(s) => Some(Box::from(s)),
-            None => None,
-        };
+#[derive(Fail, Debug)]
+#[fail(display = "Unsupported log file version {}: {:?}", version, path)]
+/// Error when a log file was written in a format version this version does not know how to read.
+pub struct UnsupportedVersionError {
+    path: PathBuf,
+    version: u8,
+}
 
-        LogEntry { cmd, key, val }
-    }
+#[derive(Fail, Debug)]
+#[fail(
+    display = "Unsupported compression {} for log entry at offset {}",
+    tag, offset
+)]
+/// Error when a log entry was compressed with an algorithm this version does not know about.
+pub struct UnsupportedCompressionError {
+    offset: u64,
+    tag: u8,
 }
 
-/// An AppendOnly, indexed log.
-///
-/// Using LogCommand's byte-slices can be appended into the log and addressed by the key that was used to add them.
-pub struct AppendLog {
-    inner: RefCell<InnerAppendLog>,
+#[derive(Fail, Debug)]
+#[fail(
+    display = "Unsupported encoding {} for log entry at offset {}",
+    tag, offset
+)]
+/// Error when a log entry was encoded in a format this version does not know about.
+pub struct UnsupportedEncodingError {
+    offset: u64,
+    tag: u8,
 }
 
-impl AppendLog {
-    /// Loads a log file from the given path.
-    pub fn load(path: &Path) -> Result<AppendLog> {
-        Ok(AppendLog {
-            inner: RefCell::new(InnerAppendLog::load(path)?),
-        })
-    }
+#[derive(Fail, Debug)]
+#[fail(
+    display = "Unable to decrypt the value of the log entry at offset {}",
+    offset
+)]
+/// Error when a value fails its authentication check, because it was encrypted with another key,
+/// was not encrypted at all, or has been changed since.
+#[cfg(feature = "encryption")]
+pub struct DecryptionError {
+    offset: u64,
+}
 
-    /// Compacts the log into the new path, closing out the old one.
-    /// Log entries can continue to be written to the AppendLog.
-    pub fn compact(&mut self, path: &Path) -> Result<()> {
-        let new_log = self.inner.get_mut().compact(path)?;
-        self.inner.replace(new_log);
-        Ok(())
-    }
+#[derive(Fail, Debug)]
+#[fail(display = "Log file is locked by another process: {:?}", path)]
+/// Error when another process already has the log file open for writing.
+pub struct LogLockedError {
+    path: PathBuf,
+}
 
-    /// Flush the logs to their storage backend.
-    pub fn flush(&mut self) -> Result<()> {
-        self.inner.get_mut().flush()
+impl LogLockedError {
+    pub(crate) fn new(path: &Path) -> LogLockedError {
+        LogLockedError {
+            path: path.to_path_buf(),
+        }
     }
+}
 
-    /// Append the given LogCommand to the log.
-    pub fn append(&mut self, cmd: LogCommand, key: &[u8], val: Option<&[u8]>) -> Result<()> {
-        self.inner.borrow_mut().append(cmd, key, val)
-    }
+#[derive(Fail, Debug)]
+#[fail(display = "Log entry of {} bytes is too large to frame", size)]
+/// Error when an encoded entry is too large for the length prefix of the log format.
+pub struct EntryTooLargeError {
+    size: usize,
+}
 
-    /// Returns true iff the value is currently in the index.
-    /// i.e. it has been added and not removed.
-    pub fn contains(&self, key: &[u8]) -> bool {
-        self.inner.borrow_mut().contains(key)
-    }
+#[derive(Fail, Debug)]
+#[fail(display = "Log file was opened read-only: {:?}", path)]
+/// Error when writing to a log that was opened with `AppendLog::open_read_only`.
+pub struct ReadOnlyError {
+    path: PathBuf,
+}
 
-    /// Fetches the value from the index.
-    pub fn fetch_by_key(&self, key: &[u8]) -> Result<Option<Box<[u8]>>> {
-        self.inner.borrow_mut().fetch_by_key(key)
+#[derive(Fail, Debug)]
+#[fail(display = "The index does not keep its keys in order")]
+/// Error when asking for the first or last key of a log whose index is hashed rather than ordered.
+pub struct UnorderedIndexError;
+
+/// The bytes a log file starts with, followed by its `FILE_VERSION`.
+const FILE_MAGIC: &[u8; 4] = b"KVLG";
+
+/// The current file format, recorded after `FILE_MAGIC` at the start of every file.
+///
+/// Files from before the header was added start straight away with their first entry, and are
+/// still read. They get a header when next compacted.
+const FILE_VERSION: u8 = 1;
+
+/// The length of the header at the start of a log file.
+const HEADER_LEN: u64 = FILE_MAGIC.len() as u64 + 1;
+
+/// The bytes a saved index file starts with, followed by its `INDEX_FILE_VERSION`.
+const INDEX_FILE_MAGIC: &[u8; 4] = b"KVIX";
+
+/// The current format of saved index files: the header, a bincode encoded `SavedIndex` and a
+/// CRC32 of it.
+///
+/// Version 1 indexes did not hold expiry times, they are ignored and the log file scanned.
+const INDEX_FILE_VERSION: u8 = 2;
+
+/// Set in the length prefix of entries that carry a format version and a checksum.
+///
+/// Entries written before checksums were added have this bit clear, as they are never 2GiB.
+const VERSIONED_ENTRY: u32 = 0x8000_0000;
+
+/// The current entry format: a version byte, a `Compression` tag byte, an `Encoding` tag byte,
+/// the compressed encoded LogEntry and a CRC32 of all four.
+///
+/// Version 3 entries have no encoding tag and are always encoded with bincode. Version 2 entries
+/// have no compression tag either and are never compressed. Version 1 entries, and those from
+/// before versioning, are also encoded as a `LogEntryV1`.
+const ENTRY_VERSION: u8 = 4;
+
+/// The number of bytes read at a time when searching past unreadable entries for the next one.
+const SALVAGE_CHUNK_LEN: usize = 64 * 1024;
+
+/// The number of bytes of appended entries held in memory before they are written to the file.
+pub const DEFAULT_WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// How the entries appended to a log are compressed.
+///
+/// Each entry records its own compression, so a log can mix entries written with any of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Entries are stored as they are.
+    #[default]
+    None,
+    /// Entries are compressed with zstd, for the best compression ratio.
+    Zstd,
+    /// Entries are compressed with LZ4, for the fastest compression.
+    Lz4,
+}
+
+impl Compression {
+    /// The tag byte recording this compression in an entry.
+    fn tag(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+        }
     }
 
-    /// Return the total length of the log - this is the total number of commands in the log.
-    /// The length of the index and log should be equal only immediately after compaction.
-    pub fn len(&self) -> usize {
-        self.inner.borrow().len()
+    fn from_tag(tag: u8) -> Option<Compression> {
+        match tag {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Zstd),
+            2 => Some(Compression::Lz4),
+            _ => None,
+        }
     }
 
-    /// Returns true iff there are zero log entries.
-    pub fn is_empty(&self) -> bool {
-        self.inner.borrow().is_empty()
+    fn compress(self, data: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data),
+            Compression::Zstd => Ok(zstd::bulk::compress(&data, 0)?),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(&data)),
+        }
     }
 
-    /// Returns the length of the index.
-    pub fn index_len(&self) -> usize {
-        self.inner.borrow().index_len()
+    fn decompress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Zstd => Ok(zstd::stream::decode_all(data)?),
+            Compression::Lz4 => Ok(lz4_flex::decompress_size_prepended(data)?),
+        }
     }
 }
 
-struct InnerAppendLog {
-    /// The index mapping all of the active entries in the Log.
-    index: HashMap<Box<[u8]>, u64>,
-    /// The file descriptor that is used for reading the entries from the log file.
-    log_file_read: File,
-    /// The file descriptor that is used to append the log entries.
-    log_file_write: File,
-    /// The number of LogEntry entries in the log.
-    entry_count: usize,
+/// How the entries appended to a log are serialized.
+///
+/// Each entry records its own encoding, as it does its compression, so a log always knows how to
+/// decode itself and can mix entries written with any of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// Entries are encoded with bincode, the most compact.
+    #[default]
+    Bincode,
+    /// Entries are encoded as JSON, which other tools can read without knowing the layout.
+    Json,
 }
 
-impl InnerAppendLog {
-    /// Creates a new, empty log.
-    // pub fn new() -> Log {
-    //     Log {
-    //         index: HashMap::new(),
-    //     }
-    // }
-
-    /// Loads a Log from a file on disk, and builds the index.
-    fn load(path: &Path) -> Result<InnerAppendLog> {
-        if !path.is_file() || !path.exists() {
-            return Err(Error::from(InvalidLogFileError {}));
+impl Encoding {
+    /// The tag byte recording this encoding in an entry.
+    fn tag(self) -> u8 {
+        match self {
+            Encoding::Bincode => 0,
+            Encoding::Json => 1,
         }
+    }
 
-        let mut log = InnerAppendLog {
-            index: HashMap::new(),
-            log_file_read: OpenOptions::new()
-                .read(true)
-                .write(false)
-                .create(false)
-                .open(path)?,
-            log_file_write: OpenOptions::new()
-                .read(true)
-                .append(true)
-                .create(false)
-                .open(path)?,
-            entry_count: 0,
-        };
-        log.build_index()?;
-        Ok(log)
+    fn from_tag(tag: u8) -> Option<Encoding> {
+        match tag {
+            0 => Some(Encoding::Bincode),
+            1 => Some(Encoding::Json),
+            _ => None,
+        }
     }
 
-    /// Compacts the current Log to the new path specified.
-    ///
-    /// It is still possible to write to this log.
-    fn compact(&mut self, path: &Path) -> Result<InnerAppendLog> {
-        if path.exists() {
-            // We don't want to clobber anything when we compact.
-            return Err(Error::from(InvalidLogFileError {}));
+    /// The codec that encodes and decodes entries in this encoding.
+    fn codec(self) -> &'static dyn Codec {
+        match self {
+            Encoding::Bincode => &BincodeCodec,
+            Encoding::Json => &JsonCodec,
         }
+    }
+}
 
-        eprintln!("Compacting into file: {:?}", path);
+/// Serializes log entries to bytes and back, in one `Encoding`.
+trait Codec {
+    fn encode(&self, entry: &LogEntry) -> Result<Vec<u8>>;
+    fn decode(&self, data: &[u8]) -> Result<LogEntry>;
+}
 
-        // Create a new log as the compaction target.
-        let write_file = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(path)?;
-        let mut log = InnerAppendLog {
-            index: HashMap::new(),
-            log_file_read: OpenOptions::new().read(true).write(false).open(path)?,
-            log_file_write: write_file,
-            entry_count: 0,
-        };
+/// The `Encoding::Bincode` codec.
+struct BincodeCodec;
 
-        for (k, _) in self.index.clone().into_iter() {
-            match self.fetch_by_key(&k)? {
-                Some(bytes) => {
-                    log.append(LogCommand::Set, &k, Some(bytes.as_ref()))?;
-                }
-                None => {
-                    // This "should not occur" as the index tracks what is added and removed
-                    // but in the event where we get back a None from fetch_by_key then we
-                    // drop it here on compact.
-                }
-            }
-        }
+impl Codec for BincodeCodec {
+    fn encode(&self, entry: &LogEntry) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(entry)?)
+    }
 
-        log.build_index()?;
-        Ok(log)
+    fn decode(&self, data: &[u8]) -> Result<LogEntry> {
+        Ok(bincode::deserialize(data)?)
     }
+}
 
-    /// Flushes any buffered LogEntries to disk.
-    fn flush(&mut self) -> Result<()> {
-        Ok(())
+/// The `Encoding::Json` codec.
+struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode(&self, entry: &LogEntry) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(entry)?)
     }
 
-    /// Appends the LogEntry to the Log and updates the index as required.
-    ///
-    /// If the command is LogCommand::Remove then the key should be None.
-    fn append(&mut self, cmd: LogCommand, key: &[u8], val: Option<&[u8]>) -> Result<()> {
-        let entry = LogEntry::new(cmd.clone(), key, val);
+    fn decode(&self, data: &[u8]) -> Result<LogEntry> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+/// Commands that can be issued into the AppendLog.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LogCommand {
+    /// Set a value into the log, this will udate the index.
+    Set,
+    /// Remove a value from the log. This value will be immediately removed from the index and removed from the file on compaction.
+    Remove,
+}
 
-        // Append the file to the log.
-        let offset = self.log_file_write.seek(SeekFrom::Current(0))?;
-        let mut w = BufWriter::new(&self.log_file_write);
-        let entry_encoded = bincode::serialize(&entry)?;
-        w.write_u32::<BigEndian>(entry_encoded.len() as u32)?;
-        w.write_all(&entry_encoded)?;
+/// A command, key and value to append as part of `AppendLog::append_batch`.
+pub type BatchEntry<'a> = (LogCommand, &'a [u8], Option<&'a [u8]>);
 
-        self.entry_count += 1;
+/// A page of keys returned by `AppendLog::list_keys`, and the cursor for the next page.
+pub type KeyPage = (Vec<Vec<u8>>, Option<Vec<u8>>);
+
+/// The command recorded by a LogEntry, either a LogCommand or a marker framing a batch.
+///
+/// The first variants are encoded the same as the LogCommands, which is all entries from before
+/// batches were framed can hold.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum EntryCommand {
+    Set,
+    Remove,
+    /// Starts a batch of this many entries, they are only applied once the CommitBatch after
+    /// them is read.
+    BeginBatch(u64),
+    /// Ends a batch.
+    CommitBatch,
+}
 
-        // Now update the index.
+impl From<LogCommand> for EntryCommand {
+    fn from(cmd: LogCommand) -> EntryCommand {
         match cmd {
-            LogCommand::Set => {
-                self.index.insert(entry.key, offset);
-            }
-            LogCommand::Remove => {
-                self.index.remove(&entry.key);
-            }
+            LogCommand::Set => EntryCommand::Set,
+            LogCommand::Remove => EntryCommand::Remove,
         }
-
-        Ok(())
     }
+}
 
-    /// Returns true if the provided key resides in the index.
-    fn contains(&self, key: &[u8]) -> bool {
-        self.index.contains_key(key)
-    }
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct LogEntry {
+    cmd: EntryCommand,
+    key: Box<[u8]>,
+    val: Option<Box<[u8]>>,
+    /// When the entry expires, in milliseconds since the unix epoch, or None if it never does.
+    expires_at: Option<u64>,
+}
 
-    /// Returns a given LogEntry referenced by the key String, or None if it does not exist.
-    fn fetch_by_key(&mut self, key: &[u8]) -> Result<Option<Box<[u8]>>> {
-        let offset = match self.index.get(key) {
-            Some(o) => *o,
-            None => return Ok(None),
-        };
+/// The shape of a LogEntry before entries could expire.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct LogEntryV1 {
+    cmd: LogCommand,
+    key: Box<[u8]>,
+    val: Option<Box<[u8]>>,
+}
 
-        self.log_file_read.seek(SeekFrom::Start(offset))?;
-        let mut reader = BufReader::new(&self.log_file_read);
+impl From<LogEntryV1> for LogEntry {
+    fn from(e: LogEntryV1) -> LogEntry {
+        LogEntry {
+            cmd: e.cmd.into(),
+            key: e.key,
+            val: e.val,
+            expires_at: None,
+        }
+    }
+}
 
-        let len = reader.read_u32::<BigEndian>()?;
-        let mut entry_data: Vec<u8> = vec![0u8; len as usize];
-        reader.read_exact(entry_data.as_mut_slice())?;
-        let entry: LogEntry = bincode::deserialize(&entry_data)?;
+impl LogEntry {
+    fn new(cmd: LogCommand, key: &[u8], val: Option<&[u8]>, expires_at: Option<u64>) -> LogEntry {
+        let key = Box::from(key);
+        let val = val.map(Box::from);
 
-        Ok(entry.val)
+        LogEntry {
+            cmd: cmd.into(),
+            key,
+            val,
+            expires_at,
+        }
     }
 
-    /// The current length of the log in LogEntries.
-    fn len(&self) -> usize {
-        self.entry_count
+    /// A marker entry, which has no key or value.
+    fn marker(cmd: EntryCommand) -> LogEntry {
+        LogEntry {
+            cmd,
+            key: Box::default(),
+            val: None,
+            expires_at: None,
+        }
     }
 
-    /// Returns true if this is an empty log.
-    fn is_empty(&self) -> bool {
-        self.entry_count == 0
+    /// Returns true if the entry has an expiry at or before `now`, in unix milliseconds.
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|e| e <= now)
     }
 
-    /// The number of entries in the index.
+    /// Writes the entry framed by its length prefix, version, compression, encoding and checksum.
     ///
-    /// This is the number of entries that are addressable from the current state of the log.
-    fn index_len(&self) -> usize {
-        self.index.len()
+    /// The framed entry is handed to the writer in a single `write_all`, so nothing is written
+    /// if it fails to encode. Returns the number of bytes it takes up in the log.
+    fn write_to(
+        &self,
+        w: &mut impl Write,
+        compression: Compression,
+        encoding: Encoding,
+    ) -> Result<u64> {
+        let entry_encoded = compression.compress(encoding.codec().encode(self)?)?;
+        let header = [ENTRY_VERSION, compression.tag(), encoding.tag()];
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(&header);
+        crc.update(&entry_encoded);
+
+        // The length covers everything between the version byte and the checksum.
+        let len = header.len() - 1 + entry_encoded.len();
+        // The top bit of the prefix is the version flag, so the length has to fit in the rest.
+        if len > (!VERSIONED_ENTRY) as usize {
+            return Err(Error::from(EntryTooLargeError {
+                size: entry_encoded.len(),
+            }));
+        }
+        let mut framed = Vec::with_capacity(4 + 1 + len + 4);
+        framed.write_u32::<BigEndian>(len as u32 | VERSIONED_ENTRY)?;
+        framed.extend_from_slice(&header);
+        framed.extend_from_slice(&entry_encoded);
+        framed.write_u32::<BigEndian>(crc.finalize())?;
+        w.write_all(&framed)?;
+        Ok(framed.len() as u64)
     }
 
-    /// Constructs the index for the append log.
-    ///
-    /// This traverses the entire file and indexes the values that are in there.
-    /// Mapping from the key of LogEntry to the offset within the file that the key refers to.
+    /// Reads the entry at `offset` from the reader, which must be positioned at that offset.
     ///
-    /// This requires parsing all LogEntries to build the index, so duplicate keys may be parsed
-    /// if the log has not been compacted.
-    fn build_index(&mut self) -> Result<()> {
-        // Seek to the start of the file for indexing.
-        self.log_file_write.seek(SeekFrom::Start(0))?;
-
-        let mut reader = BufReader::new(&self.log_file_write);
-        let mut read_count = 0;
-        loop {
-            if read_count >= self.log_file_write.metadata()?.len() {
-                break;
-            }
-            // This is the offset we will store for this entry.
-            let entry_offset = read_count;
-            let len = reader.read_u32::<BigEndian>()?;
-            read_count += 4;
+    /// Returns the entry and the number of bytes it takes up in the log.
+    fn read_from(r: &mut impl Read, offset: u64) -> Result<(LogEntry, u64)> {
+        let len = r.read_u32::<BigEndian>()?;
+        if len & VERSIONED_ENTRY == 0 {
+            // An entry from before checksums, there is nothing to verify.
             let mut entry_data: Vec<u8> = vec![0u8; len as usize];
+            r.read_exact(entry_data.as_mut_slice())?;
+            let entry: LogEntryV1 = bincode::deserialize(&entry_data)?;
+            return Ok((entry.into(), 4 + u64::from(len)));
+        }
 
-            reader.read_exact(entry_data.as_mut_slice())?;
-            read_count += u64::from(len);
-
-            // Deserialize the entry and update the index.
-            let entry: LogEntry = bincode::deserialize(&entry_data)?;
-            self.entry_count += 1;
+        // Read the whole entry before looking at any of it, so a partially written entry is
+        // always reported as an unexpected EOF.
+        // The buffer only grows with the bytes actually read, so a garbage length can't cause a
+        // huge allocation.
+        let len = (len & !VERSIONED_ENTRY) as usize;
+        let mut data: Vec<u8> = Vec::new();
+        r.by_ref().take(1 + len as u64 + 4).read_to_end(&mut data)?;
+        if data.len() < 1 + len + 4 {
+            return Err(Error::from(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
+        let (versioned_data, mut checksum) = data.split_at(1 + len);
 
-            match entry.cmd {
-                LogCommand::Set => {
-                    self.index.insert(entry.key, entry_offset);
-                }
-                LogCommand::Remove => {
-                    self.index.remove(&entry.key);
-                }
-            }
+        let version = versioned_data[0];
+        if version == 0 || version > ENTRY_VERSION {
+            return Err(Error::from(UnsupportedEntryVersionError {
+                offset,
+                version,
+            }));
+        }
+        if crc32fast::hash(versioned_data) != checksum.read_u32::<BigEndian>()? {
+            return Err(Error::from(CorruptLogError { offset }));
         }
 
-        eprintln!("Index built with {} entries:", self.index.len());
-        Ok(())
+        let entry = match version {
+            1 => bincode::deserialize::<LogEntryV1>(&versioned_data[1..])?.into(),
+            2 => bincode::deserialize(&versioned_data[1..])?,
+            3 => {
+                let compression = LogEntry::compression_at(versioned_data, offset)?;
+                bincode::deserialize(&compression.decompress(&versioned_data[2..])?)?
+            }
+            _ => {
+                let compression = LogEntry::compression_at(versioned_data, offset)?;
+                let tag = *versioned_data.get(2).ok_or(CorruptLogError { offset })?;
+                let encoding =
+                    Encoding::from_tag(tag).ok_or(UnsupportedEncodingError { offset, tag })?;
+                let data = compression.decompress(&versioned_data[3..])?;
+                encoding.codec().decode(&data)?
+            }
+        };
+        Ok((entry, 4 + data.len() as u64))
     }
-}
 
-impl Drop for InnerAppendLog {
-    fn drop(&mut self) {
-        match self.flush() {
-            Ok(_) => {}
-            Err(e) => {
-                eprintln!("Error when dropping Log on flush(): {}", e);
-            }
-        }
+    /// Returns the compression recorded by the tag after the version byte of an entry.
+    fn compression_at(versioned_data: &[u8], offset: u64) -> Result<Compression> {
+        let tag = *versioned_data.get(1).ok_or(CorruptLogError { offset })?;
+        Ok(Compression::from_tag(tag).ok_or(UnsupportedCompressionError { offset, tag })?)
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use filepath::FilePath;
-    use std::path::PathBuf;
+/// The index of a log file as it was when the log was closed, saved next to it so it can be
+/// loaded rather than rebuilt from every entry in the file.
+#[derive(Serialize, Deserialize)]
+struct SavedIndex<K> {
+    /// The length of the log file the index covers, entries after this are not in it.
+    log_len: u64,
+    /// The bytes before `log_len` in the log file, the checksum of the last entry in the index,
+    /// to tell if the file has been replaced since.
+    tail: Vec<u8>,
+    /// The entries in the log file, as counted by `Segment::entry_count`.
+    entry_count: usize,
+    /// The removes in the log file, as counted by `Segment::remove_count`.
+    remove_count: usize,
+    /// Each key with the offset, length and expiry time of its entry.
+    entries: Vec<(K, u64, u32, Option<u64>)>,
+}
 
-    fn create_empty_temp_file() -> PathBuf {
-        let f = tempfile::tempfile().unwrap();
+impl SavedIndex<Box<[u8]>> {
+    /// Decodes the contents of an index file, or returns None if they are not a valid index.
+    fn parse(data: &[u8]) -> Option<SavedIndex<Box<[u8]>>> {
+        let header_len = INDEX_FILE_MAGIC.len() + 1;
+        if data.len() < header_len + 4
+            || &data[..INDEX_FILE_MAGIC.len()] != INDEX_FILE_MAGIC
+            || data[INDEX_FILE_MAGIC.len()] != INDEX_FILE_VERSION
         {
-            OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(f.path().unwrap().as_path())
-                .unwrap();
+            return None;
         }
-        f.path().unwrap()
+        let (payload, mut checksum) = data[header_len..].split_at(data.len() - header_len - 4);
+        if crc32fast::hash(payload) != checksum.read_u32::<BigEndian>().ok()? {
+            return None;
+        }
+        bincode::deserialize(payload).ok()
     }
+}
 
-    #[test]
-    fn log_load_empty_file() {
-        let p = create_empty_temp_file();
-        InnerAppendLog::load(p.as_path()).unwrap();
+/// Counts of the operations on a log, carried over to the log that replaces it on compaction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LogCounters {
+    /// The number of values fetched by key.
+    pub reads: u64,
+    /// The number of entries appended, not counting those copied by compactions.
+    pub writes: u64,
+    /// The number of compactions.
+    pub compactions: u64,
+}
+
+/// What `AppendLog::verify` found in the log files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// The number of entries, including batch markers, that were read with a valid checksum.
+    pub good_entries: usize,
+    /// The number of runs of unreadable entries, counted as `open_with_recovery` counts them.
+    pub corrupt_entries: usize,
+    /// The number of keys in the index that do not point at a good entry.
+    pub orphaned_entries: usize,
+}
+
+impl VerifyReport {
+    /// Returns true if nothing wrong was found.
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_entries == 0 && self.orphaned_entries == 0
     }
+}
 
-    #[test]
-    fn log_write_and_read() {
-        let p = create_empty_temp_file();
+/// Where an entry is in the log: the segment file holding it, its offset in that file and the
+/// number of bytes it takes up there, along with when it expires.
+///
+/// A log that is not segmented has a single segment, so only the offset varies. Keeping the
+/// expiry time here lets the index leave out expired keys without reading their entries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Location {
+    /// The sequence number of the segment, later segments hold later entries.
+    pub segment: u64,
+    /// The offset of the entry in the segment file.
+    pub offset: u64,
+    /// The length of the entry in the segment file, including its framing.
+    pub len: u32,
+    /// The time the entry expires at in milliseconds since the unix epoch, or None if it never
+    /// does.
+    pub expires_at: Option<u64>,
+}
 
-        {
-            let mut log = InnerAppendLog::load(p.as_path()).unwrap();
-            log.append(LogCommand::Set, b"aaaa", Some(b"1111")).unwrap();
-            log.append(LogCommand::Set, b"bbbb", Some(b"2222")).unwrap();
-            log.append(LogCommand::Set, b"cccc", Some(b"3333")).unwrap();
-            log.append(LogCommand::Set, b"dddd", Some(b"4444")).unwrap();
+impl Location {
+    /// Returns true if the entry has expired by `now`, in milliseconds since the unix epoch.
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|e| e <= now)
+    }
+}
 
-            assert_eq!(
-                log.fetch_by_key(b"aaaa").unwrap().unwrap().as_ref(),
-                b"1111"
-            );
-            assert_eq!(
-                log.fetch_by_key(b"bbbb").unwrap().unwrap().as_ref(),
-                b"2222"
-            );
-            assert_eq!(
-                log.fetch_by_key(b"cccc").unwrap().unwrap().as_ref(),
-                b"3333"
-            );
-            assert_eq!(
-                log.fetch_by_key(b"dddd").unwrap().unwrap().as_ref(),
-                b"4444"
-            );
+/// An AppendOnly, indexed log.
+///
+/// Using LogCommand's byte-slices can be appended into the log and addressed by the key that was used to add them.
+///
+/// The log is `Send` and `Sync`. Its state is behind a `Mutex` that is only held to look up and
+/// update it, entries are read from the files without it, so fetches through a shared log run in
+/// parallel.
+pub struct AppendLog {
+    inner: Mutex<InnerAppendLog>,
+}
 
-            log.append(LogCommand::Remove, b"aaaa", None).unwrap();
-            assert_eq!(log.fetch_by_key(b"aaaa").unwrap(), None);
+impl Drop for AppendLog {
+    fn drop(&mut self) {
+        let inner = self.inner.get_mut().unwrap_or_else(PoisonError::into_inner);
+        if let Err(e) = inner.save_index() {
+            error!("Error when dropping Log on save_index(): {}", e);
         }
+    }
+}
 
-        {
-            let mut log = InnerAppendLog::load(p.as_path()).unwrap();
+impl AppendLog {
+    /// Loads a log file from the given path.
+    pub fn load(path: &Path) -> Result<AppendLog> {
+        Ok(AppendLog {
+            inner: Mutex::new(InnerAppendLog::load(path, false, false)?),
+        })
+    }
+
+    /// Loads a log file from the given path for reading only.
+    ///
+    /// The file is neither locked nor opened for writing, so this can be done while another
+    /// process has the log open. Appending to, compacting or renaming the log returns a
+    /// `ReadOnlyError`. The index is built from the file as it is when opened, entries appended
+    /// by another process afterwards are not seen.
+    pub fn open_read_only(path: &Path) -> Result<AppendLog> {
+        Ok(AppendLog {
+            inner: Mutex::new(InnerAppendLog::load(path, false, true)?),
+        })
+    }
+
+    /// Checks every entry in the log file at the path as `verify` does, without opening it as a
+    /// log.
+    ///
+    /// The file is read only, neither locked nor truncated, and no index is built, so a file too
+    /// damaged to load can still be checked. With no index, no orphaned entries are reported.
+    pub fn verify_file(path: &Path) -> Result<VerifyReport> {
+        InnerAppendLog::<RandomState>::load(path, true, true)?.verify()
+    }
+
+    /// Loads a log file from the given path, skipping over entries that can't be read rather
+    /// than failing, e.g. after the file was damaged on disk.
+    ///
+    /// Returns the log along with the number of unreadable runs of entries skipped. The skipped
+    /// entries are left in the file until the log is compacted.
+    pub fn open_with_recovery(path: &Path) -> Result<(AppendLog, usize)> {
+        let mut inner = InnerAppendLog::load(path, true, false)?;
+        let dropped = inner.ensure_index_salvaging(true)?;
+        if dropped > 0 {
+            warn!(
+                "Skipped {} unreadable runs of entries in {:?}",
+                dropped, path
+            );
+        }
+        Ok((
+            AppendLog {
+                inner: Mutex::new(inner),
+            },
+            dropped,
+        ))
+    }
+
+    /// Opens a log file from the given path without building the index.
+    ///
+    /// The index is built on the first call that needs it (`fetch_by_key`, `contains`, `len`,
+    /// `index_len` or `compact`), so write-only users never pay for scanning the file.
+    pub fn open_lazy(path: &Path) -> Result<AppendLog> {
+        Ok(AppendLog {
+            inner: Mutex::new(InnerAppendLog::load(path, true, false)?),
+        })
+    }
+
+    /// Creates an empty log kept in memory rather than in a file, under the path as its name.
+    ///
+    /// The log behaves as one loaded from a file, compacting it into another path keeps the
+    /// new log in memory too. Nothing is written to disk, and the entries are lost once it is
+    /// dropped.
+    pub fn in_memory(path: &Path) -> Result<AppendLog> {
+        AppendLog::with_storage(path, Box::new(MemoryStorage::default()))
+    }
+
+    /// Opens a log kept in the storage rather than in a file, under the path as its name, and
+    /// builds the index from what it holds.
+    ///
+    /// Compacting the log copies it into the storage from `LogStorage::create_empty`. Nothing
+    /// is done at the path unless that returns None, when the compacted log is written to a
+    /// file there.
+    pub fn with_storage(path: &Path, storage: Box<dyn LogStorage>) -> Result<AppendLog> {
+        Ok(AppendLog {
+            inner: Mutex::new(InnerAppendLog::with_storage(path, storage)?),
+        })
+    }
+
+    /// Returns true if the log is not kept in a file, as with one created by `in_memory` or
+    /// `with_storage`.
+    pub fn is_in_memory(&self) -> bool {
+        self.inner.lock().unwrap().in_memory
+    }
+
+    /// Opens the log split across the `<prefix>.<seq>.seg` segment files in the directory,
+    /// creating the first segment if there are none.
+    ///
+    /// Entries are appended to the newest segment, a new one is started once it holds
+    /// `max_segment_bytes`. Space is reclaimed with `compact_segments` rather than `compact`.
+    pub fn open_segmented(dir: &Path, prefix: &str, max_segment_bytes: u64) -> Result<AppendLog> {
+        Ok(AppendLog {
+            inner: Mutex::new(InnerAppendLog::load_segmented(
+                dir,
+                prefix,
+                max_segment_bytes,
+            )?),
+        })
+    }
+
+    /// Returns the path of the segment file with the sequence number in a segmented log.
+    pub fn segment_path(dir: &Path, prefix: &str, segment: u64) -> PathBuf {
+        dir.join(format!("{}.{}.seg", prefix, segment))
+    }
+
+    /// Returns the path of the file the index of the log file at the path is saved to.
+    pub fn index_path(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".idx");
+        PathBuf::from(name)
+    }
+
+    /// Saves the index next to the log file, so the log is loaded without reading every entry
+    /// when it is next opened. This is done when the log is dropped.
+    ///
+    /// Entries appended after the index is saved are read from the file when it is opened, so
+    /// saving is only ever a matter of how much of the file has to be read. Segmented and
+    /// read-only logs, and logs whose index has not been built, are not saved.
+    pub fn save_index(&mut self) -> Result<()> {
+        self.inner.get_mut().unwrap().save_index()
+    }
+
+    /// Returns true if the log was opened with `open_segmented`.
+    pub fn is_segmented(&self) -> bool {
+        self.inner.lock().unwrap().segmenting.is_some()
+    }
+
+    /// Returns the path of the file backing the log, the newest segment of a segmented log.
+    pub fn path(&self) -> PathBuf {
+        self.inner.lock().unwrap().active().path.clone()
+    }
+
+    /// Compacts the log into the new path, closing out the old one.
+    /// Log entries can continue to be written to the AppendLog.
+    ///
+    /// This takes `&mut self` so nothing can be appended while the live entries are copied. An
+    /// append landing in the old log during the copy would be lost with it, e.g. a concurrent
+    /// remove would leave its key live in the new log.
+    pub fn compact(&mut self, path: &Path) -> Result<()> {
+        self.compact_with_progress(path, |_, _| {})
+    }
+
+    /// Compacts the log as `compact` does, calling `progress` with the number of live entries
+    /// copied so far and the total to copy, after each one is copied.
+    pub fn compact_with_progress(
+        &mut self,
+        path: &Path,
+        progress: impl FnMut(usize, usize),
+    ) -> Result<()> {
+        let inner = self.inner.get_mut().unwrap();
+        let new_log = inner.compact(path, progress)?;
+        *inner = new_log;
+        Ok(())
+    }
+
+    /// Rewrites, in place, each segment of a segmented log that holds more than `ratio` entries
+    /// per entry that is still needed, and returns the number of segments rewritten.
+    ///
+    /// Segments left with no entries are removed. The newest segment is still being appended to
+    /// and is never rewritten, so no compaction copies more than a segment at a time.
+    pub fn compact_segments(&mut self, ratio: usize) -> Result<usize> {
+        self.compact_segments_with_progress(ratio, |_, _| {})
+    }
+
+    /// Compacts the segments as `compact_segments` does, calling `progress` with the number of
+    /// entries in the sealed segments checked so far and the total in all of them, after each
+    /// segment is checked.
+    pub fn compact_segments_with_progress(
+        &mut self,
+        ratio: usize,
+        progress: impl FnMut(usize, usize),
+    ) -> Result<usize> {
+        self.inner
+            .get_mut()
+            .unwrap()
+            .compact_segments(ratio, progress)
+    }
+
+    /// Replaces the log with a new empty one at the path, closing out the old one.
+    pub fn clear(&mut self, path: &Path) -> Result<()> {
+        let inner = self.inner.get_mut().unwrap();
+        let new_log = inner.create_empty(path)?;
+        *inner = new_log;
+        Ok(())
+    }
+
+    /// Writes the live entries of the log to a new log at the path, leaving this log unchanged.
+    ///
+    /// Entries can't be appended while they are copied, so the copy is the log as it was at a
+    /// single point in time.
+    pub fn snapshot_to(&self, path: &Path) -> Result<()> {
+        self.inner
+            .lock()
+            .unwrap()
+            .copy_live(path, None, |_, _| {})
+            .map(|_| ())
+    }
+
+    /// Replaces all of the segments of a segmented log with a new empty one.
+    pub fn clear_segments(&mut self) -> Result<()> {
+        self.inner.get_mut().unwrap().clear_segments()
+    }
+
+    /// Moves the file backing the log to the new path, replacing any file already there.
+    pub fn rename(&mut self, path: &Path) -> Result<()> {
+        let inner = self.inner.get_mut().unwrap();
+        let in_memory = inner.in_memory;
+        let segment = inner.active_mut();
+        segment.writer()?;
+        if in_memory {
+            segment.path = path.to_path_buf();
+            return Ok(());
+        }
+        fs::rename(&segment.path, path)?;
+        // A saved index at either path is for a different file now.
+        for p in &[&segment.path, path] {
+            match fs::remove_file(AppendLog::index_path(p)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(Error::from(e)),
+                _ => {}
+            }
+        }
+        segment.path = path.to_path_buf();
+        Ok(())
+    }
+
+    /// Returns a ReadOnlyError if the log was opened read-only, and can't be written to.
+    pub fn writable(&self) -> Result<()> {
+        self.inner.lock().unwrap().writer().map(|_| ())
+    }
+
+    /// Sets the compression of entries appended from now on, existing entries are unchanged
+    /// until they are rewritten by a compaction.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.inner.get_mut().unwrap().compression = compression;
+    }
+
+    /// Sets the encoding of entries appended from now on, existing entries are unchanged until
+    /// they are rewritten by a compaction.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.inner.get_mut().unwrap().encoding = encoding;
+    }
+
+    /// Encrypts the values of entries appended from now on with AES-256-GCM under the key, and
+    /// decrypts the values fetched.
+    ///
+    /// Only values are encrypted, keys are stored as they are so the index can be built without
+    /// the key. Fetching a value that was not encrypted with this key fails with a
+    /// `DecryptionError`.
+    #[cfg(feature = "encryption")]
+    pub fn set_encryption_key(&mut self, key: &[u8; 32]) {
+        let key = UnboundKey::new(&AES_256_GCM, key).expect("AES-256 keys are 32 bytes");
+        self.inner.get_mut().unwrap().encryption_key = Some(LessSafeKey::new(key));
+    }
+
+    /// Flush the logs to their storage backend.
+    ///
+    /// Appended entries are buffered, this writes out the buffer and syncs the file so the entries
+    /// survive a crash or power loss. It is also done when the log is dropped.
+    pub fn flush(&mut self) -> Result<()> {
+        self.inner.get_mut().unwrap().flush()
+    }
+
+    /// Append the given LogCommand to the log.
+    pub fn append(&mut self, cmd: LogCommand, key: &[u8], val: Option<&[u8]>) -> Result<()> {
+        self.inner.lock().unwrap().append(cmd, key, val, None)
+    }
+
+    /// Append the given LogCommand to the log, with the value expiring at `expires_at`
+    /// milliseconds since the unix epoch.
+    ///
+    /// Once expired the key is treated as absent and dropped from the index when it is next
+    /// fetched.
+    pub fn append_with_expiry(
+        &mut self,
+        cmd: LogCommand,
+        key: &[u8],
+        val: Option<&[u8]>,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        self.inner.lock().unwrap().append(cmd, key, val, expires_at)
+    }
+
+    /// Append all of the given LogCommands to the log, in order, under a single lock.
+    ///
+    /// The batch is applied as a unit: if the process dies before all of it is written, none of
+    /// it is applied when the log is next loaded.
+    pub fn append_batch(&mut self, entries: &[BatchEntry]) -> Result<()> {
+        self.inner.get_mut().unwrap().append_batch(entries)
+    }
+
+    /// Returns true iff the value is currently in the index.
+    /// i.e. it has been added and not removed.
+    pub fn contains(&self, key: &[u8]) -> Result<bool> {
+        self.inner.lock().unwrap().contains(key)
+    }
+
+    /// Fetches the value from the index.
+    ///
+    /// The entry is read from the file without holding the log's lock, so fetches made at the
+    /// same time through a shared log read in parallel rather than one after another.
+    pub fn fetch_by_key(&self, key: &[u8]) -> Result<Option<Box<[u8]>>> {
+        let (location, readers, mut reader) = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.counters.reads += 1;
+            let location = match inner.lookup(key)? {
+                Lookup::Missing => return Ok(None),
+                Lookup::Cached(entry) => return Ok(entry.val),
+                Lookup::Unread(location) => location,
+            };
+            let segment = inner.segment_mut(location.segment).ok_or(CorruptLogError {
+                offset: location.offset,
+            })?;
+            (location, segment.readers.clone(), segment.take_reader()?)
+        };
+
+        let entry = reader.read_at(location.offset);
+        readers.put(reader);
+        let entry = self
+            .inner
+            .lock()
+            .unwrap()
+            .read_done(key, location, entry?)?;
+        Ok(entry.and_then(|e| e.val))
+    }
+
+    /// Returns a reader over the value of the key, or None if it is not set.
+    ///
+    /// A value stored without compression or encryption in the bincode encoding is read from the
+    /// log file as the reader is read from, so large values need not be held in memory. Any
+    /// other value is fetched whole first.
+    pub fn value_reader(&self, key: &[u8]) -> Result<Option<ValueReader>> {
+        let clock;
+        let (location, file, encrypted) = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.counters.reads += 1;
+            let location = match inner.lookup(key)? {
+                Lookup::Missing => return Ok(None),
+                Lookup::Cached(entry) => return Ok(entry.val.map(ValueReader::buffered)),
+                Lookup::Unread(location) => location,
+            };
+            clock = inner.clock.clone();
+            #[cfg(feature = "encryption")]
+            let encrypted = inner.encryption_key.is_some();
+            #[cfg(not(feature = "encryption"))]
+            let encrypted = false;
+            let segment = inner.segment_mut(location.segment).ok_or(CorruptLogError {
+                offset: location.offset,
+            })?;
+            segment.flush_buffer()?;
+            (
+                location,
+                segment.reader.get_ref().file.open_reader()?,
+                encrypted,
+            )
+        };
+
+        if !encrypted {
+            if let Some((value, expires_at)) = StreamedValue::open(file, location, key)? {
+                if expires_at.is_none_or(|at| at > clock.now_millis()) {
+                    return Ok(Some(ValueReader {
+                        source: ValueSource::Streamed(value),
+                    }));
+                }
+            }
+        }
+        // Expired entries are dropped from the index by the fetch.
+        let entry = self.inner.lock().unwrap().fetch_entry(key)?;
+        Ok(entry.and_then(|e| e.val).map(ValueReader::buffered))
+    }
+
+    /// Fetches the values of each of the keys under a single lock, in the order of the keys.
+    pub fn fetch_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Box<[u8]>>>> {
+        let mut inner = self.inner.lock().unwrap();
+        keys.iter().map(|k| inner.fetch_by_key(k)).collect()
+    }
+
+    /// Return the total length of the log - this is the total number of commands in the log.
+    /// The length of the index and log should be equal only immediately after compaction.
+    pub fn len(&self) -> Result<usize> {
+        self.inner.lock().unwrap().len()
+    }
+
+    /// Returns true iff there are zero log entries.
+    pub fn is_empty(&self) -> Result<bool> {
+        self.inner.lock().unwrap().is_empty()
+    }
+
+    /// Returns the length of the index.
+    pub fn index_len(&self) -> Result<usize> {
+        self.inner.lock().unwrap().index_len()
+    }
+
+    /// Returns the number of live keys, the length of the index less any keys that have expired
+    /// but not yet been dropped from it.
+    pub fn live_len(&self) -> Result<usize> {
+        self.inner.lock().unwrap().live_len()
+    }
+
+    /// Returns the number of remove entries in the log, counted in `len` but never in `index_len`.
+    pub fn tombstone_count(&self) -> Result<usize> {
+        self.inner.lock().unwrap().tombstone_count()
+    }
+
+    /// Returns the length of the log files in bytes, including entries still in the write buffer.
+    pub fn byte_len(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.segments.iter().map(|s| s.len).sum()
+    }
+
+    /// Keeps up to `capacity` recently fetched entries in memory, so fetching them again does not
+    /// read and decode them from the file. A capacity of zero disables the cache.
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        self.inner.get_mut().unwrap().set_cache_capacity(capacity);
+    }
+
+    /// Holds up to `size` bytes of appended entries in memory, writing them to the file together
+    /// once the buffer fills or the log is flushed, `DEFAULT_WRITE_BUFFER_SIZE` by default.
+    ///
+    /// Buffered entries are indexed and can be fetched as soon as they are appended. A size of
+    /// zero writes each entry to the file as it is appended.
+    pub fn set_write_buffer_size(&mut self, size: usize) {
+        self.inner.get_mut().unwrap().write_buffer_size = size;
+    }
+
+    /// Reads the `count` most recently written live entries into the cache, so the first fetches
+    /// of them do not read the file, returning the number cached.
+    ///
+    /// Nothing is cached without a cache, and no more entries than it holds.
+    pub fn warm_cache(&self, count: usize) -> Result<usize> {
+        self.inner.lock().unwrap().warm_cache(count)
+    }
+
+    /// Measures entry expiry against the clock rather than the system's.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.inner.get_mut().unwrap().clock = clock;
+    }
+
+    /// Returns the current time of the log's clock, in milliseconds since the unix epoch.
+    pub fn now_millis(&self) -> u64 {
+        self.inner.lock().unwrap().clock.now_millis()
+    }
+
+    /// Returns the counts of operations on the log since it was opened.
+    pub fn counters(&self) -> LogCounters {
+        self.inner.lock().unwrap().counters
+    }
+
+    /// Returns the number of bytes in the log files taken up by entries the index does not point
+    /// at: overwritten and expired values, removes and batch markers.
+    ///
+    /// This is roughly what a compaction would free, a segmented log keeps the removes it
+    /// still needs.
+    pub fn reclaimable_bytes(&self) -> Result<u64> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ensure_index()?;
+        let now = inner.clock.now_millis();
+        // Expired entries still count as live until they are next fetched or purged.
+        let expired: u64 = inner
+            .index
+            .iter()
+            .filter(|(_, location)| location.is_expired(now))
+            .map(|(_, location)| u64::from(location.len))
+            .sum();
+        let unindexed: u64 = inner
+            .segments
+            .iter()
+            .map(|s| s.len - s.start.min(s.len) - s.live_bytes)
+            .sum();
+        Ok(unindexed + expired)
+    }
+
+    /// Reads every entry in the log files, checking its length and checksum, and checks that the
+    /// index points at entries that were read.
+    ///
+    /// Nothing is written, the entries still in the write buffer are flushed first. The index is
+    /// only checked once it has been built, as it has unless the log was opened lazily.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        self.inner.lock().unwrap().verify()
+    }
+
+    /// Returns the number of fetches answered from the cache, without reading the file.
+    pub fn cache_hits(&self) -> u64 {
+        self.inner.lock().unwrap().cache_hits
+    }
+
+    /// Returns the number of reads the log has made from its files, while building the index and
+    /// fetching values.
+    pub fn disk_reads(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .segments
+            .iter()
+            .map(|s| s.readers.reads.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Returns every live key in the log, in no particular order.
+    pub fn keys(&self) -> Result<Vec<Vec<u8>>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ensure_index()?;
+        let now = inner.clock.now_millis();
+        Ok(inner
+            .index
+            .iter()
+            .filter(|(_, location)| !location.is_expired(now))
+            .map(|(k, _)| k.to_vec())
+            .collect())
+    }
+
+    /// Returns the value of every live key in the log, in the order they are stored in.
+    ///
+    /// The values are read in order of their location, so the log files are read through from
+    /// start to end rather than jumping about as they would in key order.
+    pub fn values(&self) -> Result<Vec<Box<[u8]>>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ensure_index()?;
+        let mut keys: Vec<(Location, Box<[u8]>)> = inner
+            .index
+            .iter()
+            .map(|(key, location)| (*location, key.clone()))
+            .collect();
+        keys.sort_unstable();
+
+        let mut values = Vec::with_capacity(keys.len());
+        for (_, key) in keys {
+            if let Some(val) = inner.fetch_by_key(&key)? {
+                values.push(val);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Appends a removal for every live key whose entry has expired, returning the keys removed.
+    ///
+    /// Expired keys are otherwise only dropped when next fetched, so keys that are never read
+    /// again stay in the index until this is called.
+    pub fn purge_expired(&mut self) -> Result<Vec<Vec<u8>>> {
+        self.inner.get_mut().unwrap().purge_expired()
+    }
+
+    /// Returns the offset of the key's current entry in its segment file, or None if the key is
+    /// not in the log or has expired.
+    ///
+    /// The offset is only meaningful until the next compaction, which moves the entries.
+    pub fn offset_of(&self, key: &[u8]) -> Result<Option<u64>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ensure_index()?;
+        let now = inner.clock.now_millis();
+        Ok(inner
+            .index
+            .get(key)
+            .filter(|l| !l.is_expired(now))
+            .map(|l| l.offset))
+    }
+
+    /// Returns a point-in-time copy of the index, mapping each live key to its location in the log.
+    ///
+    /// A reader holding its own copy of the index and its own handle on the log file can serve
+    /// reads without contending on this log. The trade-off is staleness: the copy does not see
+    /// any writes appended after it was taken, and its locations are invalidated by a compaction.
+    pub fn clone_index(&self) -> Result<HashMap<Box<[u8]>, Location>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ensure_index()?;
+        let now = inner.clock.now_millis();
+        Ok(inner
+            .index
+            .iter()
+            .filter(|(_, location)| !location.is_expired(now))
+            .map(|(k, location)| (k.clone(), *location))
+            .collect())
+    }
+
+    /// Returns the smallest live key, or None if there are none.
+    ///
+    /// Only an ordered index can answer this without checking every key, a hashed one returns an
+    /// `UnorderedIndexError`.
+    pub fn first_key(&self) -> Result<Option<Vec<u8>>> {
+        self.inner.lock().unwrap().end_key(false)
+    }
+
+    /// Returns the largest live key, or None if there are none, as `first_key` does.
+    pub fn last_key(&self) -> Result<Option<Vec<u8>>> {
+        self.inner.lock().unwrap().end_key(true)
+    }
+
+    /// Returns the live keys from `start`, inclusive, to `end`, exclusive, in sorted order.
+    ///
+    /// With an ordered index only the keys in the range are visited, otherwise every key is
+    /// checked and the matches sorted.
+    pub fn keys_in_range(&self, start: &[u8], end: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ensure_index()?;
+        let now = inner.clock.now_millis();
+        Ok(inner.index.range(start, end, now))
+    }
+
+    /// Returns up to `limit` keys after `start_after` in sorted order, from the first key if it
+    /// is None, along with the cursor to pass as `start_after` for the next page.
+    ///
+    /// The cursor is None once there are no more keys. A limit of zero returns no keys and
+    /// `start_after` again as the cursor. Needs an ordered index, a hashed one returns an
+    /// `UnorderedIndexError`.
+    pub fn list_keys(&self, start_after: Option<&[u8]>, limit: usize) -> Result<KeyPage> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ensure_index()?;
+        let now = inner.clock.now_millis();
+        let map = match &inner.index {
+            Index::Ordered(map) => map,
+            Index::Hashed(_) => return Err(Error::from(UnorderedIndexError)),
+        };
+        if limit == 0 {
+            return Ok((Vec::new(), start_after.map(<[u8]>::to_vec)));
+        }
+        let from = match start_after {
+            Some(key) => Bound::Excluded(key),
+            None => Bound::Unbounded,
+        };
+        let mut keys = map
+            .range::<[u8], _>((from, Bound::Unbounded))
+            .filter(|(_, location)| !location.is_expired(now))
+            .map(|(k, _)| k);
+        let page: Vec<Vec<u8>> = keys.by_ref().take(limit).map(|k| k.to_vec()).collect();
+        let cursor = match keys.next() {
+            Some(_) => page.last().cloned(),
+            None => None,
+        };
+        Ok((page, cursor))
+    }
+
+    /// Sets whether the index keeps its keys in order, which makes `keys_in_range` cheap at the
+    /// cost of slower lookups than the default hashed index.
+    pub fn set_ordered_index(&mut self, ordered: bool) {
+        self.inner.get_mut().unwrap().set_ordered_index(ordered);
+    }
+}
+
+/// One of the files a log is made up of.
+///
+/// Only the newest segment of a log is appended to, the older ones are sealed.
+struct Segment {
+    /// The sequence number of the segment.
+    id: u64,
+    /// The path of the segment file.
+    path: PathBuf,
+    /// The file descriptor that is used for scanning the entries of the segment, and that holds
+    /// the segment's lock.
+    reader: BufReader<CountingFile>,
+    /// The file descriptors that are used for fetching entries from the segment.
+    readers: Arc<ReaderPool>,
+    /// The file descriptor that is used to append the log entries, buffered until `flush`.
+    ///
+    /// None once the segment is sealed, or if the log was opened read-only.
+    writer: Option<BufWriter<Box<dyn LogStorage>>>,
+    /// The offset the first entry is at, past the header, or 0 for a file without one.
+    start: u64,
+    /// The offset the next entry will be written at, counting entries still in the buffer.
+    len: u64,
+    /// The number of LogEntry entries in the segment.
+    entry_count: usize,
+    /// The number of those entries that are removes.
+    remove_count: usize,
+    /// The number of those entries the index points at.
+    live_count: usize,
+    /// The number of bytes taken up by the entries the index points at.
+    live_bytes: u64,
+}
+
+impl Segment {
+    /// Opens an existing segment file for reading, locking it against other writers if `lock`
+    /// is set.
+    fn open(id: u64, path: &Path, lock: bool) -> Result<Segment> {
+        let file = FileStorage::open(path, lock)?;
+        Segment::with_storage(id, path, Box::new(file))
+    }
+
+    /// Opens a segment kept in the storage for appending.
+    ///
+    /// The path is only a name for the segment, nothing is done there.
+    fn open_in(id: u64, path: &Path, storage: Box<dyn LogStorage>) -> Result<Segment> {
+        let mut segment = Segment::with_storage(id, path, storage)?;
+        segment.open_writer()?;
+        Ok(segment)
+    }
+
+    /// Opens a segment on its storage for reading.
+    fn with_storage(id: u64, path: &Path, mut file: Box<dyn LogStorage>) -> Result<Segment> {
+        let len = file.len()?;
+        let start = Segment::read_header(&mut *file, path)?;
+        let readers = Arc::new(ReaderPool::default());
+
+        Ok(Segment {
+            id,
+            path: path.to_path_buf(),
+            reader: BufReader::new(CountingFile::new(file, readers.reads.clone())),
+            readers,
+            writer: None,
+            start,
+            len,
+            entry_count: 0,
+            remove_count: 0,
+            live_count: 0,
+            live_bytes: 0,
+        })
+    }
+
+    /// Creates a new empty segment file, open for appending.
+    fn create(id: u64, path: &Path) -> Result<Segment> {
+        if path.exists() {
+            // We don't want to clobber anything.
+            return Err(Error::from(InvalidLogFileError {}));
+        }
+
+        OpenOptions::new().append(true).create(true).open(path)?;
+        let mut segment = Segment::open(id, path, true)?;
+        segment.open_writer()?;
+        Ok(segment)
+    }
+
+    /// Reads the header of the segment file, returning the offset its first entry is at.
+    ///
+    /// An empty file, or one whose header was cut short while it was written, gets a header
+    /// written when it is opened for appending.
+    fn read_header(file: &mut dyn LogStorage, path: &Path) -> Result<u64> {
+        let mut header = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.take(HEADER_LEN).read_to_end(&mut header)?;
+        if !header.starts_with(FILE_MAGIC) && !FILE_MAGIC.starts_with(&header) {
+            // Written before the header was added, the first entry is at the start.
+            return Ok(0);
+        }
+        match header.get(FILE_MAGIC.len()) {
+            None | Some(&FILE_VERSION) => Ok(HEADER_LEN),
+            Some(&version) => Err(Error::from(UnsupportedVersionError {
+                path: path.to_path_buf(),
+                version,
+            })),
+        }
+    }
+
+    /// Opens the segment file for appending, writing its header first if it has none yet.
+    fn open_writer(&mut self) -> Result<()> {
+        let mut file = self.reader.get_ref().file.open_writer()?;
+        if self.len < self.start {
+            file.set_len(0)?;
+            let mut header = FILE_MAGIC.to_vec();
+            header.push(FILE_VERSION);
+            file.write_all(&header)?;
+            self.len = HEADER_LEN;
+        }
+        self.writer = Some(BufWriter::with_capacity(DEFAULT_WRITE_BUFFER_SIZE, file));
+        Ok(())
+    }
+
+    /// Replaces the write buffer with one of `capacity` bytes, if it is a different size, writing
+    /// out whatever the old one holds.
+    fn resize_write_buffer(&mut self, capacity: usize) -> Result<()> {
+        let w = match self.writer.take() {
+            Some(w) if w.capacity() != capacity => w,
+            w => {
+                self.writer = w;
+                return Ok(());
+            }
+        };
+        match w.into_inner() {
+            Ok(file) => {
+                self.writer = Some(BufWriter::with_capacity(capacity, file));
+                Ok(())
+            }
+            Err(e) => {
+                let (err, w) = e.into_parts();
+                self.writer = Some(w);
+                Err(Error::from(err))
+            }
+        }
+    }
+
+    /// Truncates whatever part of a failed write reached the storage, so it ends with the last
+    /// entry written, less those still in the write buffer.
+    ///
+    /// The buffered entries are kept, to be written by the next flush.
+    fn roll_back_write(&mut self) -> Result<()> {
+        let len = self.len;
+        let w = self.writer()?;
+        let written = len - w.buffer().len() as u64;
+        w.get_mut().set_len(written)?;
+        Ok(())
+    }
+
+    /// Returns the write handle, or a ReadOnlyError if the segment can't be written to.
+    fn writer(&mut self) -> Result<&mut BufWriter<Box<dyn LogStorage>>> {
+        match self.writer.as_mut() {
+            Some(w) => Ok(w),
+            None => Err(Error::from(ReadOnlyError {
+                path: self.path.clone(),
+            })),
+        }
+    }
+
+    /// Flushes any buffered LogEntries to disk, and waits for the disk to have them.
+    fn flush(&mut self) -> Result<()> {
+        if let Some(w) = self.writer.as_mut() {
+            w.flush()?;
+            w.get_mut().sync()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the segment and closes the write handle, nothing is appended to it again.
+    fn seal(&mut self) -> Result<()> {
+        self.flush()?;
+        self.writer = None;
+        Ok(())
+    }
+
+    /// Reads the LogEntry at the offset.
+    fn read_at(&mut self, offset: u64) -> Result<LogEntry> {
+        let mut reader = self.take_reader()?;
+        let entry = reader.read_at(offset);
+        self.readers.put(reader);
+        entry
+    }
+
+    /// Writes out the write buffer, without syncing, so the entries in it can be read.
+    fn flush_buffer(&mut self) -> Result<()> {
+        // The entry may still be in the write buffer, which a read handle can't see.
+        if let Some(w) = self.writer.as_mut() {
+            w.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the last few bytes before `len` in the file, the checksum of the entry ending there.
+    fn tail(&mut self, len: u64) -> Result<Vec<u8>> {
+        let from = len.saturating_sub(4);
+        let mut tail = vec![0u8; (len - from) as usize];
+        self.flush_buffer()?;
+        self.reader.seek(SeekFrom::Start(from))?;
+        self.reader.read_exact(&mut tail)?;
+        Ok(tail)
+    }
+
+    /// Writes the index of the segment to its index file, replacing what was there.
+    ///
+    /// The file is written next to it and moved into place, so it is never seen half written.
+    fn save_index(&mut self, entries: Vec<(&[u8], u64, u32, Option<u64>)>) -> Result<()> {
+        self.flush()?;
+        let saved = SavedIndex {
+            log_len: self.len,
+            tail: self.tail(self.len)?,
+            entry_count: self.entry_count,
+            remove_count: self.remove_count,
+            entries,
+        };
+        let data = bincode::serialize(&saved)?;
+
+        let path = AppendLog::index_path(&self.path);
+        let mut tmp_name = path.clone().into_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_name);
+        let mut w = BufWriter::new(File::create(&tmp_path)?);
+        w.write_all(INDEX_FILE_MAGIC)?;
+        w.write_all(&[INDEX_FILE_VERSION])?;
+        w.write_all(&data)?;
+        w.write_u32::<BigEndian>(crc32fast::hash(&data))?;
+        w.flush()?;
+        w.get_ref().sync_data()?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// Reads the saved index of the segment, if it has one that covers no more than the first
+    /// `end` bytes of the file as it is now.
+    ///
+    /// A saved index that can't be used is ignored, and the file scanned instead.
+    fn saved_index(&mut self, end: u64) -> Option<SavedIndex<Box<[u8]>>> {
+        let path = AppendLog::index_path(&self.path);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Unable to read the saved index {:?}: {}", path, e);
+                return None;
+            }
+        };
+        let saved = match SavedIndex::parse(&data) {
+            Some(saved) => saved,
+            None => {
+                warn!("Ignoring the unreadable saved index {:?}", path);
+                return None;
+            }
+        };
+        if saved.log_len < self.start || saved.log_len > end {
+            debug!("Ignoring the saved index {:?}, the log has shrunk", path);
+            return None;
+        }
+        match self.tail(saved.log_len) {
+            Ok(tail) if tail == saved.tail => Some(saved),
+            _ => {
+                debug!("Ignoring the saved index {:?}, the log was replaced", path);
+                None
+            }
+        }
+    }
+
+    /// Takes a reader for fetching entries out of the pool, opening a new one if none are free.
+    ///
+    /// It should be given back to the pool once done with.
+    fn take_reader(&mut self) -> Result<SegmentReader> {
+        self.flush_buffer()?;
+        if let Some(reader) = self.readers.readers.lock().unwrap().pop() {
+            return Ok(reader);
+        }
+        let file = self.reader.get_ref().file.open_reader()?;
+        Ok(SegmentReader {
+            reader: BufReader::new(CountingFile::new(file, self.readers.reads.clone())),
+            pos: None,
+        })
+    }
+
+    /// Reads the entries from the offset `from`, which must be the offset of an entry, up to
+    /// `end` in order, passing each to `f` along with its offset and length.
+    ///
+    /// Returns the offset reading stopped at, and whether it stopped at a final entry that runs
+    /// past `end`.
+    fn scan(
+        &mut self,
+        from: u64,
+        end: u64,
+        mut f: impl FnMut(LogEntry, u64, u64) -> Result<()>,
+    ) -> Result<(u64, bool)> {
+        self.reader.seek(SeekFrom::Start(from))?;
+
+        let len = end.saturating_sub(from);
+        let mut reader = BufReader::new(Read::take(self.reader.get_mut(), len));
+        let mut read_count = from;
+        while read_count < end {
+            // This is the offset we will store for this entry.
+            let entry_offset = read_count;
+            let (entry, entry_len) = match LogEntry::read_from(&mut reader, entry_offset) {
+                Ok(e) => e,
+                Err(e) => match e.downcast_ref::<io::Error>() {
+                    Some(io_err) if io_err.kind() == io::ErrorKind::UnexpectedEof => {
+                        return Ok((read_count, true));
+                    }
+                    _ => return Err(e),
+                },
+            };
+            read_count += entry_len;
+            f(entry, entry_offset, entry_len)?;
+        }
+        Ok((read_count, false))
+    }
+
+    /// Reads the entries in the first `end` bytes of the segment as `scan` does, but skips over
+    /// any that can't be read rather than failing.
+    ///
+    /// After an unreadable entry, reading resumes at the next offset holding an entry with a
+    /// valid checksum. Returns the offset just past the last good entry, whether anything after
+    /// it was left unread, and the number of unreadable runs skipped. A final entry that runs
+    /// past `end` is a partial append rather than damage, and isn't counted.
+    fn salvage_scan(
+        &mut self,
+        end: u64,
+        mut f: impl FnMut(LogEntry, u64, u64) -> Result<()>,
+    ) -> Result<(u64, bool, usize)> {
+        let end = end.min(self.reader.seek(SeekFrom::End(0))?);
+        let mut offset = self.start;
+        let mut good_end = offset;
+        let mut dropped = 0;
+        self.reader.seek(SeekFrom::Start(offset))?;
+        while offset < end {
+            let err = match LogEntry::read_from(
+                &mut Read::take(&mut self.reader, end - offset),
+                offset,
+            ) {
+                Ok((entry, entry_len)) => {
+                    f(entry, offset, entry_len)?;
+                    offset += entry_len;
+                    good_end = offset;
+                    continue;
+                }
+                Err(e) => e,
+            };
+
+            let next = self.next_entry_offset(offset + 1, end)?;
+            let torn = match err.downcast_ref::<io::Error>() {
+                Some(io_err) => io_err.kind() == io::ErrorKind::UnexpectedEof,
+                None => false,
+            };
+            match next {
+                None if torn => break,
+                None => {
+                    warn!(
+                        "Unreadable entries from offset {} to the end of {:?}: {}",
+                        offset, self.path, err
+                    );
+                    dropped += 1;
+                    break;
+                }
+                Some(next) => {
+                    warn!(
+                        "Unreadable entries from offset {} to {} of {:?}: {}",
+                        offset, next, self.path, err
+                    );
+                    dropped += 1;
+                    offset = next;
+                    self.reader.seek(SeekFrom::Start(offset))?;
+                }
+            }
+        }
+        Ok((good_end, good_end < end, dropped))
+    }
+
+    /// Returns the first offset from `from` up to `end` holding an entry with a valid checksum,
+    /// for `salvage_scan` to resume at.
+    ///
+    /// The segment is searched `SALVAGE_CHUNK_LEN` bytes at a time, and only offsets that could
+    /// start a checksummed entry of a known version are tried, so garbage is skipped over without
+    /// reading the rest of the segment for each byte of it.
+    fn next_entry_offset(&mut self, from: u64, end: u64) -> Result<Option<u64>> {
+        let mut chunk = vec![0u8; SALVAGE_CHUNK_LEN];
+        let mut pos = from;
+        while pos < end {
+            let n = (end - pos).min(SALVAGE_CHUNK_LEN as u64) as usize;
+            self.reader.seek(SeekFrom::Start(pos))?;
+            self.reader.read_exact(&mut chunk[..n])?;
+            for i in 0..n {
+                // Only entries with a checksum can be told apart from garbage.
+                if chunk[i] & 0x80 == 0 {
+                    continue;
+                }
+                if let Some(&version) = chunk[..n].get(i + 4) {
+                    if version == 0 || version > ENTRY_VERSION {
+                        continue;
+                    }
+                }
+                let o = pos + i as u64;
+                self.reader.seek(SeekFrom::Start(o))?;
+                if LogEntry::read_from(&mut Read::take(&mut self.reader, end - o), o).is_ok() {
+                    return Ok(Some(o));
+                }
+            }
+            pos += n as u64;
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        match self.flush() {
+            Ok(_) => {}
+            Err(e) => {
+                error!("Error when dropping Log on flush(): {}", e);
+            }
+        }
+    }
+}
+
+/// Where a segmented log creates its segments, and when.
+struct Segmenting {
+    /// The directory holding the segment files.
+    dir: PathBuf,
+    /// The file name prefix of the segment files, they are named `<prefix>.<seq>.seg`.
+    prefix: String,
+    /// A new segment is started once the newest one holds this many bytes.
+    max_bytes: u64,
+}
+
+/// The log and its index, generic over the hasher of the index so tests can force collisions.
+struct InnerAppendLog<S = RandomState> {
+    /// The index mapping all of the active entries in the Log.
+    index: Index<S>,
+    /// The files of the log, oldest first. Entries are appended to the last one.
+    ///
+    /// There is always at least one.
+    segments: Vec<Segment>,
+    /// How new segments are started, or None if the log is a single file.
+    segmenting: Option<Segmenting>,
+    /// Whether the log is kept in storage other than a file at its path, e.g. in memory.
+    in_memory: bool,
+    /// Recently fetched entries by their location, or None if caching is disabled.
+    ///
+    /// Entries never change once written, so a cached entry only goes stale when its key is
+    /// written again and the index moves to a new location.
+    cache: Option<LruCache<Location, LogEntry>>,
+    /// The number of fetches answered from the cache.
+    cache_hits: u64,
+    /// The counts of operations on the log.
+    counters: LogCounters,
+    /// The compression of appended entries.
+    compression: Compression,
+    /// The encoding of appended entries.
+    encoding: Encoding,
+    /// The number of bytes of appended entries buffered before they are written to the file.
+    write_buffer_size: usize,
+    /// The clock entry expiry is measured against.
+    clock: Arc<dyn Clock>,
+    /// The key values are encrypted with, or None if they are stored as they are.
+    #[cfg(feature = "encryption")]
+    encryption_key: Option<LessSafeKey>,
+    /// The segments and their lengths that `build_index` still has to scan, or None once the
+    /// index is built.
+    unindexed: Option<Vec<(u64, u64)>>,
+    /// Entries appended before the index was built, merged into the index once it is.
+    pending: Vec<(LogCommand, Box<[u8]>, Location)>,
+}
+
+impl<S> InnerAppendLog<S> {
+    /// Flushes any buffered LogEntries to disk, and waits for the disk to have them.
+    fn flush(&mut self) -> Result<()> {
+        // Only the newest segment is ever written to.
+        self.active_mut().flush()
+    }
+
+    /// Saves the index of a single file log next to the file, if it is writable and indexed.
+    ///
+    /// An empty file has nothing to read, so its index isn't saved either.
+    fn save_index(&mut self) -> Result<()> {
+        let active = self.active();
+        if self.segmenting.is_some()
+            || self.in_memory
+            || self.unindexed.is_some()
+            || active.writer.is_none()
+            || active.len <= active.start
+        {
+            return Ok(());
+        }
+        let entries = match &self.index {
+            Index::Hashed(map) => map
+                .iter()
+                .map(|(k, l)| (&k[..], l.offset, l.len, l.expires_at))
+                .collect(),
+            Index::Ordered(map) => map
+                .iter()
+                .map(|(k, l)| (&k[..], l.offset, l.len, l.expires_at))
+                .collect(),
+        };
+        let segment = self
+            .segments
+            .last_mut()
+            .expect("a log always has a segment");
+        segment.save_index(entries)
+    }
+
+    /// Returns the write handle, or a ReadOnlyError if the log was opened read-only.
+    fn writer(&mut self) -> Result<&mut BufWriter<Box<dyn LogStorage>>> {
+        self.active_mut().writer()
+    }
+
+    /// The segment entries are appended to.
+    fn active(&self) -> &Segment {
+        self.segments.last().expect("a log always has a segment")
+    }
+
+    /// The segment entries are appended to.
+    fn active_mut(&mut self) -> &mut Segment {
+        self.segments
+            .last_mut()
+            .expect("a log always has a segment")
+    }
+
+    /// The segment with the sequence number, if it is still part of the log.
+    fn segment_mut(&mut self, id: u64) -> Option<&mut Segment> {
+        let i = self.segments.binary_search_by_key(&id, |s| s.id).ok()?;
+        Some(&mut self.segments[i])
+    }
+}
+
+impl<S: BuildHasher + Default + Clone> InnerAppendLog<S> {
+    /// Loads a Log from a file on disk, and builds the index unless `lazy` is set.
+    ///
+    /// A `read_only` log has no write handle and does not lock the file.
+    fn load(path: &Path, lazy: bool, read_only: bool) -> Result<InnerAppendLog<S>> {
+        if !path.is_file() || !path.exists() {
+            return Err(Error::from(InvalidLogFileError {}));
+        }
+
+        let mut segment = Segment::open(0, path, !read_only)?;
+        if !read_only {
+            segment.open_writer()?;
+        }
+        InnerAppendLog::from_segments(vec![segment], None, lazy)
+    }
+
+    /// Opens a Log kept in the storage, and builds the index.
+    fn with_storage(path: &Path, storage: Box<dyn LogStorage>) -> Result<InnerAppendLog<S>> {
+        let segment = Segment::open_in(0, path, storage)?;
+        let mut log = InnerAppendLog::from_segments(vec![segment], None, true)?;
+        log.in_memory = true;
+        log.ensure_index()?;
+        Ok(log)
+    }
+
+    /// Loads a Log from the segment files in the directory, and builds the index.
+    fn load_segmented(dir: &Path, prefix: &str, max_bytes: u64) -> Result<InnerAppendLog<S>> {
+        let mut segments = Vec::new();
+        for id in segment_ids(dir, prefix)? {
+            segments.push(Segment::open(
+                id,
+                &AppendLog::segment_path(dir, prefix, id),
+                true,
+            )?);
+        }
+        match segments.last_mut() {
+            Some(active) => active.open_writer()?,
+            None => {
+                let path = AppendLog::segment_path(dir, prefix, 0);
+                info!("No segments found, starting new one: {:?}", path);
+                segments.push(Segment::create(0, &path)?);
+            }
+        }
+
+        let segmenting = Segmenting {
+            dir: dir.to_path_buf(),
+            prefix: prefix.to_owned(),
+            max_bytes,
+        };
+        InnerAppendLog::from_segments(segments, Some(segmenting), false)
+    }
+
+    fn from_segments(
+        segments: Vec<Segment>,
+        segmenting: Option<Segmenting>,
+        lazy: bool,
+    ) -> Result<InnerAppendLog<S>> {
+        let unindexed = segments.iter().map(|s| (s.id, s.len)).collect();
+        let mut log = InnerAppendLog {
+            index: Index::new(false),
+            segments,
+            segmenting,
+            in_memory: false,
+            cache: None,
+            cache_hits: 0,
+            counters: LogCounters::default(),
+            compression: Compression::None,
+            encoding: Encoding::Bincode,
+            write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+            clock: Arc::new(SystemClock),
+            #[cfg(feature = "encryption")]
+            encryption_key: None,
+            unindexed: Some(unindexed),
+            pending: Vec::new(),
+        };
+        if !lazy {
+            log.ensure_index()?;
+        }
+        Ok(log)
+    }
+
+    /// Compacts the current Log to the new path specified.
+    ///
+    /// It is still possible to write to this log.
+    fn compact(
+        &mut self,
+        path: &Path,
+        progress: impl FnMut(usize, usize),
+    ) -> Result<InnerAppendLog<S>> {
+        self.writer()?;
+        info!("Compacting into file: {:?}", path);
+        let storage = self.create_storage()?;
+        let mut log = self.copy_live(path, storage, progress)?;
+        log.counters = self.counters;
+        log.counters.compactions += 1;
+
+        Ok(log)
+    }
+
+    /// Writes a new log at the path, or in the storage if one is given, holding only the live
+    /// entries of this one, which is left as it is.
+    fn copy_live(
+        &mut self,
+        path: &Path,
+        storage: Option<Box<dyn LogStorage>>,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<InnerAppendLog<S>> {
+        self.ensure_index()?;
+        let mut log = self.empty_at(path, storage)?;
+        let total = self.index.len();
+        let keys: Vec<Box<[u8]>> = self.index.iter().map(|(k, _)| k.clone()).collect();
+        for (done, k) in keys.into_iter().enumerate() {
+            match self.fetch_entry(&k)? {
+                Some(entry) => {
+                    log.append(LogCommand::Set, &k, entry.val.as_deref(), entry.expires_at)?;
+                }
+                None => {
+                    // This "should not occur" as the index tracks what is added and removed
+                    // but in the event where we get back a None from fetch_by_key then we
+                    // drop it here on compact.
+                }
+            }
+            progress(done + 1, total);
+        }
+        // The old log may be removed once this returns, the copy has to be on disk before then.
+        log.flush()?;
+        Ok(log)
+    }
+
+    /// Rewrites the sealed segments holding more than `ratio` entries per entry still needed.
+    ///
+    /// Returns the number of segments rewritten.
+    fn compact_segments(
+        &mut self,
+        ratio: usize,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<usize> {
+        self.ensure_index()?;
+
+        let sealed = &self.segments[..self.segments.len() - 1];
+        let total: usize = sealed.iter().map(|s| s.entry_count).sum();
+        let mut done = 0;
+        let mut rewritten = 0;
+        let mut i = 0;
+        while i + 1 < self.segments.len() {
+            let segment = &self.segments[i];
+            done += segment.entry_count;
+            // A remove only has to be kept while an older segment may hold the value it removed.
+            let keep_removes = i > 0;
+            let mut needed = segment.live_count;
+            if keep_removes {
+                needed += segment.remove_count;
+            }
+            if segment.entry_count <= ratio * needed {
+                i += 1;
+                progress(done, total);
+                continue;
+            }
+
+            rewritten += 1;
+            if self.rewrite_segment(i, keep_removes)? {
+                i += 1;
+            }
+            progress(done, total);
+        }
+
+        if rewritten > 0 {
+            self.counters.compactions += 1;
+        }
+        Ok(rewritten)
+    }
+
+    /// Rewrites the sealed segment at `i` with only its live entries, and its removes of keys
+    /// that are not live if `keep_removes` is set.
+    ///
+    /// The new segment is written next to the old one and moved over it once complete. A segment
+    /// left with no entries is removed instead, and false is returned.
+    fn rewrite_segment(&mut self, i: usize, keep_removes: bool) -> Result<bool> {
+        let id = self.segments[i].id;
+        let path = self.segments[i].path.clone();
+        let mut tmp_name = path.clone().into_os_string();
+        tmp_name.push(".compact");
+        let tmp_path = PathBuf::from(tmp_name);
+        if tmp_path.exists() {
+            // Left behind by a compaction that did not complete.
+            fs::remove_file(&tmp_path)?;
+        }
+        info!("Compacting segment: {:?}", path);
+
+        let mut new_segment = Segment::create(id, &tmp_path)?;
+        let compression = self.compression;
+        let encoding = self.encoding;
+        let now = self.clock.now_millis();
+        let mut moved = Vec::new();
+        let mut expired = Vec::new();
+        let index = &self.index;
+        let segment = &mut self.segments[i];
+        let end = segment.len;
+        let start = segment.start;
+        segment.scan(start, end, |entry, offset, len| {
+            let keep = match entry.cmd {
+                EntryCommand::Set => {
+                    if index.get(&entry.key)
+                        != Some(&Location {
+                            segment: id,
+                            offset,
+                            len: len as u32,
+                            expires_at: entry.expires_at,
+                        })
+                    {
+                        false
+                    } else if entry.is_expired(now) {
+                        expired.push(entry.key.clone());
+                        false
+                    } else {
+                        true
+                    }
+                }
+                EntryCommand::Remove => keep_removes && !index.contains_key(&entry.key),
+                // Every batch in a sealed segment is complete, or was dropped when it was loaded.
+                EntryCommand::BeginBatch(_) | EntryCommand::CommitBatch => false,
+            };
+            if keep {
+                let new_offset = new_segment.len;
+                let new_len = entry.write_to(new_segment.writer()?, compression, encoding)?;
+                new_segment.len += new_len;
+                new_segment.entry_count += 1;
+                match entry.cmd {
+                    EntryCommand::Remove => new_segment.remove_count += 1,
+                    _ => moved.push((entry.key, new_offset, new_len, entry.expires_at)),
+                }
+            }
+            Ok(())
+        })?;
+        new_segment.seal()?;
+        new_segment.live_count = moved.len();
+        new_segment.live_bytes = moved.iter().map(|(_, _, len, _)| len).sum();
+
+        let kept = new_segment.entry_count > 0;
+        if kept {
+            fs::rename(&tmp_path, &path)?;
+            new_segment.path = path;
+            self.segments[i] = new_segment;
+        } else {
+            drop(new_segment);
+            fs::remove_file(&tmp_path)?;
+            self.segments.remove(i);
+            fs::remove_file(&path)?;
+        }
+
+        for (key, offset, len, expires_at) in moved {
+            self.index.insert(
+                key,
+                Location {
+                    segment: id,
+                    offset,
+                    len: len as u32,
+                    expires_at,
+                },
+            );
+        }
+        for key in expired {
+            self.index.remove(&key);
+        }
+        // Every offset in the segment has moved.
+        if let Some(cache) = self.cache.as_mut() {
+            cache.clear();
+        }
+        Ok(kept)
+    }
+
+    /// Creates an empty log at the path with the same settings as this one, to replace it.
+    fn create_empty(&mut self, path: &Path) -> Result<InnerAppendLog<S>> {
+        self.writer()?;
+        let storage = self.create_storage()?;
+        self.empty_at(path, storage)
+    }
+
+    /// Creates the storage for a log to replace this one, or None if it is to be a file.
+    fn create_storage(&self) -> Result<Option<Box<dyn LogStorage>>> {
+        Ok(self.active().reader.get_ref().file.create_empty()?)
+    }
+
+    /// Creates a new empty log at the path, or in the storage if one is given, with the same
+    /// settings as this one, which may be read-only.
+    fn empty_at(
+        &self,
+        path: &Path,
+        storage: Option<Box<dyn LogStorage>>,
+    ) -> Result<InnerAppendLog<S>> {
+        let in_memory = storage.is_some();
+        let segment = match storage {
+            Some(storage) => Segment::open_in(0, path, storage)?,
+            None => Segment::create(0, path)?,
+        };
+        let mut log = InnerAppendLog {
+            index: Index::new(self.index.is_ordered()),
+            segments: vec![segment],
+            in_memory,
+            segmenting: None,
+            cache: self.cache.as_ref().map(|c| LruCache::new(c.cap())),
+            cache_hits: 0,
+            counters: self.counters,
+            compression: self.compression,
+            encoding: self.encoding,
+            write_buffer_size: self.write_buffer_size,
+            clock: self.clock.clone(),
+            #[cfg(feature = "encryption")]
+            encryption_key: self.encryption_key.clone(),
+            unindexed: None,
+            pending: Vec::new(),
+        };
+        log.flush()?;
+        Ok(log)
+    }
+
+    /// Replaces every segment of a segmented log with a new empty one.
+    fn clear_segments(&mut self) -> Result<()> {
+        self.writer()?;
+        let segmenting = match &self.segmenting {
+            Some(s) => s,
+            None => return Err(Error::from(InvalidLogFileError {})),
+        };
+        let id = self.active().id + 1;
+        let segment = Segment::create(
+            id,
+            &AppendLog::segment_path(&segmenting.dir, &segmenting.prefix, id),
+        )?;
+
+        for old in std::mem::replace(&mut self.segments, vec![segment]) {
+            let path = old.path.clone();
+            drop(old);
+            fs::remove_file(path)?;
+        }
+        self.index.clear();
+        self.unindexed = None;
+        self.pending.clear();
+        if let Some(cache) = self.cache.as_mut() {
+            cache.clear();
+        }
+        Ok(())
+    }
+
+    /// Starts a new segment for appends if the log is segmented and the newest one is full.
+    fn roll_segment_if_full(&mut self) -> Result<()> {
+        let active = self.active();
+        let path = match &self.segmenting {
+            Some(s) if active.len >= s.max_bytes => {
+                AppendLog::segment_path(&s.dir, &s.prefix, active.id + 1)
+            }
+            _ => return Ok(()),
+        };
+        let segment = Segment::create(active.id + 1, &path)?;
+
+        // The full segment is never appended to again.
+        self.active_mut().seal()?;
+        self.segments.push(segment);
+        Ok(())
+    }
+
+    /// Appends the LogEntry to the Log and updates the index as required.
+    ///
+    /// If the command is LogCommand::Remove then the key should be None.
+    fn append(
+        &mut self,
+        cmd: LogCommand,
+        key: &[u8],
+        val: Option<&[u8]>,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        let entry = LogEntry::new(cmd.clone(), key, val, expires_at);
+        self.roll_segment_if_full()?;
+        let location = self.write_entry(&entry)?;
+        self.counters.writes += 1;
+        self.index_or_pend(cmd, entry.key, location);
+        Ok(())
+    }
+
+    /// Appends each of the LogEntries to the Log in order, they share the write buffer.
+    ///
+    /// The entries are framed by BeginBatch and CommitBatch markers, and are only applied to the
+    /// index once all of them are written. A batch that fails to write leaves nothing of itself
+    /// in the log, not even its BeginBatch.
+    fn append_batch(&mut self, entries: &[BatchEntry]) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        // A batch is kept within one segment, so loading a segment never has half of one.
+        self.roll_segment_if_full()?;
+        let mut framed = Vec::with_capacity(entries.len() + 2);
+        framed.push(LogEntry::marker(EntryCommand::BeginBatch(
+            entries.len() as u64
+        )));
+        for (cmd, key, val) in entries {
+            framed.push(LogEntry::new(cmd.clone(), key, *val, None));
+        }
+        framed.push(LogEntry::marker(EntryCommand::CommitBatch));
+        let locations = self.write_entries(&framed)?;
+
+        self.counters.writes += entries.len() as u64;
+        let written = framed.into_iter().zip(locations).skip(1);
+        for ((cmd, _, _), (entry, location)) in entries.iter().zip(written) {
+            self.index_or_pend(cmd.clone(), entry.key, location);
+        }
+        Ok(())
+    }
+
+    /// Writes the entry to the newest segment without indexing it, and returns its location.
+    fn write_entry(&mut self, entry: &LogEntry) -> Result<Location> {
+        let locations = self.write_entries(std::slice::from_ref(entry))?;
+        Ok(locations[0])
+    }
+
+    /// Writes the entries to the newest segment in order without indexing them, and returns
+    /// their locations.
+    ///
+    /// The entries are framed into one buffer handed to the writer in a single `write_all`, and
+    /// whatever part of a failed write reached the storage is truncated, so either all of them
+    /// are written or none are. They reach the file once the buffer fills or is flushed.
+    fn write_entries(&mut self, entries: &[LogEntry]) -> Result<Vec<Location>> {
+        let compression = self.compression;
+        let encoding = self.encoding;
+        let mut framed = Vec::new();
+        let mut spans = Vec::with_capacity(entries.len());
+        for entry in entries {
+            #[cfg(feature = "encryption")]
+            let encrypted = self.encrypt(entry)?;
+            #[cfg(feature = "encryption")]
+            let entry = encrypted.as_ref().unwrap_or(entry);
+
+            let offset = framed.len() as u64;
+            let len = entry.write_to(&mut framed, compression, encoding)?;
+            spans.push((offset, len));
+        }
+
+        let write_buffer_size = self.write_buffer_size;
+        let segment = self.active_mut();
+        // A new segment starts with the default buffer.
+        segment.resize_write_buffer(write_buffer_size)?;
+        if let Err(e) = segment.writer()?.write_all(&framed) {
+            // Leave the storage as it was, so no partial entry is left in the log.
+            if let Err(rollback) = segment.roll_back_write() {
+                error!("Unable to roll back a failed write: {}", rollback);
+            }
+            return Err(Error::from(e));
+        }
+
+        let start = segment.len;
+        segment.len += framed.len() as u64;
+        let mut locations = Vec::with_capacity(entries.len());
+        for (entry, (offset, len)) in entries.iter().zip(spans) {
+            match entry.cmd {
+                EntryCommand::Set => segment.entry_count += 1,
+                EntryCommand::Remove => {
+                    segment.entry_count += 1;
+                    segment.remove_count += 1;
+                }
+                // Markers don't count as entries.
+                EntryCommand::BeginBatch(_) | EntryCommand::CommitBatch => {}
+            }
+            locations.push(Location {
+                segment: segment.id,
+                offset: start + offset,
+                len: len as u32,
+                expires_at: entry.expires_at,
+            });
+        }
+        Ok(locations)
+    }
+
+    /// Returns a copy of the entry with its value encrypted, or None if values aren't encrypted
+    /// or the entry has no value.
+    ///
+    /// The value is sealed under a random nonce, which is stored ahead of it. The entry's key is
+    /// authenticated along with the value, so a value moved to another key fails to decrypt.
+    #[cfg(feature = "encryption")]
+    fn encrypt(&self, entry: &LogEntry) -> Result<Option<LogEntry>> {
+        let (key, val) = match (&self.encryption_key, &entry.val) {
+            (Some(key), Some(val)) => (key, val),
+            _ => return Ok(None),
+        };
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| io::Error::other("unable to generate a nonce"))?;
+        let mut sealed = val.to_vec();
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(&entry.key),
+            &mut sealed,
+        )
+        .map_err(|_| io::Error::other("unable to encrypt a value"))?;
+
+        let mut data = nonce.to_vec();
+        data.append(&mut sealed);
+        Ok(Some(LogEntry {
+            val: Some(data.into_boxed_slice()),
+            ..entry.clone()
+        }))
+    }
+
+    /// Decrypts the value of the entry read from the location, if values are encrypted.
+    #[cfg(feature = "encryption")]
+    fn decrypt(&self, mut entry: LogEntry, location: Location) -> Result<LogEntry> {
+        let (key, val) = match (&self.encryption_key, entry.val.as_mut()) {
+            (Some(key), Some(val)) => (key, val),
+            _ => return Ok(entry),
+        };
+        let err = || DecryptionError {
+            offset: location.offset,
+        };
+        if val.len() < NONCE_LEN {
+            return Err(Error::from(err()));
+        }
+        let (nonce, sealed) = val.split_at_mut(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| err())?;
+        let plain = key
+            .open_in_place(nonce, Aad::from(&entry.key), sealed)
+            .map_err(|_| err())?;
+        entry.val = Some(plain.to_vec().into_boxed_slice());
+        Ok(entry)
+    }
+
+    /// Updates the index with an appended entry, or holds on to it until the index is built.
+    fn index_or_pend(&mut self, cmd: LogCommand, key: Box<[u8]>, location: Location) {
+        if self.unindexed.is_some() {
+            self.pending.push((cmd, key, location));
+        } else {
+            self.index_entry(cmd, key, location);
+        }
+    }
+
+    /// Applies a LogCommand appended at the location to the index, keeping the live counts of
+    /// the segments and the cache in step.
+    fn index_entry(&mut self, cmd: LogCommand, key: Box<[u8]>, location: Location) {
+        if let LogCommand::Set = cmd {
+            if let Some(segment) = self.segment_mut(location.segment) {
+                segment.live_count += 1;
+                segment.live_bytes += u64::from(location.len);
+            }
+        }
+        if let Some(old) = update_index(&mut self.index, cmd, key, location) {
+            self.unindex(old);
+        }
+    }
+
+    /// Returns true if the provided key resides in the index and has not expired.
+    fn contains(&mut self, key: &[u8]) -> Result<bool> {
+        self.ensure_index()?;
+        let now = self.clock.now_millis();
+        Ok(self.index.get(key).is_some_and(|l| !l.is_expired(now)))
+    }
+
+    /// Appends a removal for each indexed entry that has expired, returning their keys.
+    ///
+    /// The expiry times are in the index, so no entries are read. The removals are appended in
+    /// order of the entries' locations, oldest first.
+    fn purge_expired(&mut self) -> Result<Vec<Vec<u8>>> {
+        self.ensure_index()?;
+        let now = self.clock.now_millis();
+        let mut expired: Vec<(Location, Box<[u8]>)> = self
+            .index
+            .iter()
+            .filter(|(_, location)| location.is_expired(now))
+            .map(|(key, location)| (*location, key.clone()))
+            .collect();
+        expired.sort_unstable();
+
+        let mut purged = Vec::with_capacity(expired.len());
+        for (_, key) in expired {
+            self.append(LogCommand::Remove, &key, None, None)?;
+            purged.push(key.into_vec());
+        }
+        Ok(purged)
+    }
+
+    /// Returns the largest key of an ordered index if `last` is set, otherwise the smallest.
+    ///
+    /// Expired keys are dropped from the index on the way to the first live one, going by the
+    /// expiry kept in the index, so no values are read.
+    fn end_key(&mut self, last: bool) -> Result<Option<Vec<u8>>> {
+        self.ensure_index()?;
+        let now = self.clock.now_millis();
+        loop {
+            let end = match &self.index {
+                Index::Ordered(map) if last => map.iter().next_back(),
+                Index::Ordered(map) => map.iter().next(),
+                Index::Hashed(_) => return Err(Error::from(UnorderedIndexError)),
+            };
+            let (key, location) = match end {
+                Some((key, location)) => (key.clone(), *location),
+                None => return Ok(None),
+            };
+            if !location.is_expired(now) {
+                return Ok(Some(key.into_vec()));
+            }
+            self.index.remove(&key);
+            self.unindex(location);
+        }
+    }
+
+    /// Returns the value referenced by the key, or None if it does not exist or has expired.
+    fn fetch_by_key(&mut self, key: &[u8]) -> Result<Option<Box<[u8]>>> {
+        self.counters.reads += 1;
+        Ok(self.fetch_entry(key)?.and_then(|e| e.val))
+    }
+
+    /// Returns the LogEntry referenced by the key, or None if it does not exist.
+    ///
+    /// An expired entry is removed from the index and treated as if it did not exist.
+    fn fetch_entry(&mut self, key: &[u8]) -> Result<Option<LogEntry>> {
+        let location = match self.lookup(key)? {
+            Lookup::Missing => return Ok(None),
+            Lookup::Cached(entry) => return Ok(Some(entry)),
+            Lookup::Unread(location) => location,
+        };
+        let entry = self
+            .segment_mut(location.segment)
+            .ok_or(CorruptLogError {
+                offset: location.offset,
+            })?
+            .read_at(location.offset)?;
+        self.read_done(key, location, entry)
+    }
+
+    /// Finds where the entry for the key is, answering from the cache if it holds the entry.
+    fn lookup(&mut self, key: &[u8]) -> Result<Lookup> {
+        self.ensure_index()?;
+        let location = match self.index.get(key) {
+            Some(l) => *l,
+            None => return Ok(Lookup::Missing),
+        };
+
+        if let Some(entry) = self.cache.as_mut().and_then(|c| c.get(&location)) {
+            let entry = entry.clone();
+            self.cache_hits += 1;
+            return Ok(match self.unexpired(key, location, entry) {
+                Some(entry) => Lookup::Cached(entry),
+                None => Lookup::Missing,
+            });
+        }
+        Ok(Lookup::Unread(location))
+    }
+
+    /// Caches the entry read from the location found by `lookup`, returning it if it is unexpired.
+    ///
+    /// The key may have been written to since the lookup if the entry was read without the lock,
+    /// in which case the entry is still returned, as it was the key's value when looked up.
+    fn read_done(
+        &mut self,
+        key: &[u8],
+        location: Location,
+        entry: LogEntry,
+    ) -> Result<Option<LogEntry>> {
+        #[cfg(feature = "encryption")]
+        let entry = self.decrypt(entry, location)?;
+
+        if self.index.get(key) == Some(&location) {
+            if let Some(cache) = self.cache.as_mut() {
+                cache.put(location, entry.clone());
+            }
+        }
+        Ok(self.unexpired(key, location, entry))
+    }
+
+    /// Returns the entry fetched for the key, or None after dropping it if it has expired.
+    fn unexpired(&mut self, key: &[u8], location: Location, entry: LogEntry) -> Option<LogEntry> {
+        if entry.is_expired(self.clock.now_millis()) {
+            if self.index.get(key) == Some(&location) {
+                self.index.remove(key);
+                self.unindex(location);
+            }
+            return None;
+        }
+        Some(entry)
+    }
+
+    /// Moves the index to an ordered or a hashed map, which does not change what it holds.
+    fn set_ordered_index(&mut self, ordered: bool) {
+        if self.index.is_ordered() == ordered {
+            return;
+        }
+        let mut index = Index::new(ordered);
+        for (key, location) in self.index.iter() {
+            index.insert(key.clone(), *location);
+        }
+        self.index = index;
+    }
+
+    /// Reads the live entries with the highest locations, the most recently written, into the
+    /// cache.
+    fn warm_cache(&mut self, count: usize) -> Result<usize> {
+        self.ensure_index()?;
+        let count = count.min(self.cache.as_ref().map_or(0, |c| c.cap().get()));
+        let mut keys: Vec<(Location, Box<[u8]>)> = self
+            .index
+            .iter()
+            .map(|(key, location)| (*location, key.clone()))
+            .collect();
+        keys.sort_unstable();
+
+        let mut cached = 0;
+        // Read oldest first, so the most recent entry is the last to be evicted.
+        for (location, key) in keys.into_iter().rev().take(count).rev() {
+            let entry = self
+                .segment_mut(location.segment)
+                .ok_or(CorruptLogError {
+                    offset: location.offset,
+                })?
+                .read_at(location.offset)?;
+            if self.read_done(&key, location, entry)?.is_some() {
+                cached += 1;
+            }
+        }
+        Ok(cached)
+    }
+
+    /// Sets the number of entries kept in the cache, zero disables it.
+    fn set_cache_capacity(&mut self, capacity: usize) {
+        match (NonZeroUsize::new(capacity), self.cache.as_mut()) {
+            (None, _) => self.cache = None,
+            (Some(cap), Some(cache)) => cache.resize(cap),
+            (Some(cap), None) => self.cache = Some(LruCache::new(cap)),
+        }
+    }
+
+    /// Accounts for the entry at the location no longer being live, dropping it from the cache.
+    fn unindex(&mut self, location: Location) {
+        if let Some(segment) = self.segment_mut(location.segment) {
+            segment.live_count -= 1;
+            segment.live_bytes -= u64::from(location.len);
+        }
+        if let Some(cache) = self.cache.as_mut() {
+            cache.pop(&location);
+        }
+    }
+
+    /// The current length of the log in LogEntries.
+    fn len(&mut self) -> Result<usize> {
+        self.ensure_index()?;
+        Ok(self.segments.iter().map(|s| s.entry_count).sum())
+    }
+
+    /// Returns true if this is an empty log.
+    fn is_empty(&mut self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Reads every entry in the segments, and checks the index against the good ones.
+    fn verify(&mut self) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let mut good = HashSet::new();
+        for segment in self.segments.iter_mut() {
+            segment.flush_buffer()?;
+            let id = segment.id;
+            let (_, _, corrupt) = segment.salvage_scan(segment.len, |entry, offset, len| {
+                report.good_entries += 1;
+                if let EntryCommand::Set = entry.cmd {
+                    good.insert(Location {
+                        segment: id,
+                        offset,
+                        len: len as u32,
+                        expires_at: entry.expires_at,
+                    });
+                }
+                Ok(())
+            })?;
+            report.corrupt_entries += corrupt;
+        }
+        if self.unindexed.is_none() {
+            report.orphaned_entries = self
+                .index
+                .iter()
+                .filter(|(_, location)| !good.contains(*location))
+                .count();
+        }
+        Ok(report)
+    }
+
+    /// The number of remove entries in the log.
+    fn tombstone_count(&mut self) -> Result<usize> {
+        self.ensure_index()?;
+        Ok(self.segments.iter().map(|s| s.remove_count).sum())
+    }
+
+    /// The number of entries in the index.
+    ///
+    /// This is the number of entries that are addressable from the current state of the log.
+    fn index_len(&mut self) -> Result<usize> {
+        self.ensure_index()?;
+        Ok(self.index.len())
+    }
+
+    /// The number of entries in the index that have not expired.
+    fn live_len(&mut self) -> Result<usize> {
+        self.ensure_index()?;
+        let now = self.clock.now_millis();
+        Ok(self
+            .index
+            .iter()
+            .filter(|(_, location)| !location.is_expired(now))
+            .count())
+    }
+
+    /// Builds the index if it has not been built yet, merging in any entries appended since the
+    /// log was opened.
+    fn ensure_index(&mut self) -> Result<()> {
+        self.ensure_index_salvaging(false).map(|_| ())
+    }
+
+    /// Builds the index as `ensure_index` does, skipping unreadable entries if `salvage` is set.
+    ///
+    /// Returns the number of unreadable runs of entries skipped.
+    fn ensure_index_salvaging(&mut self, salvage: bool) -> Result<usize> {
+        let mut dropped = 0;
+        if let Some(unindexed) = self.unindexed.clone() {
+            dropped = self.build_index(&unindexed, salvage)?;
+            for (cmd, key, location) in std::mem::take(&mut self.pending) {
+                self.index_entry(cmd, key, location);
+            }
+            self.unindexed = None;
+        }
+        Ok(dropped)
+    }
+
+    /// Constructs the index for the append log.
+    ///
+    /// This traverses the entire file and indexes the values that are in there.
+    /// Mapping from the key of LogEntry to the location within the log that the key refers to.
+    ///
+    /// This requires parsing all LogEntries to build the index, so duplicate keys may be parsed
+    /// if the log has not been compacted.
+    ///
+    /// Only the given segments are scanned, up to their given lengths. Anything after that was
+    /// appended through this log and is tracked in `pending`.
+    ///
+    /// A final entry that runs past the end was only partly written, e.g. the process died during
+    /// an append. Indexing stops before it and the newest segment is truncated back to the last
+    /// complete entry so that new entries are not appended after the partial one. A read-only log
+    /// leaves the file as it is, the entry may still be being written by another process.
+    ///
+    /// With `salvage` set, entries that can't be read are skipped rather than failing, and the
+    /// number of unreadable runs skipped is returned.
+    fn build_index(&mut self, unindexed: &[(u64, u64)], salvage: bool) -> Result<usize> {
+        let mut index = Index::new(self.index.is_ordered());
+        let newest = self.active().id;
+        // Only a log in a single file can have a saved index.
+        let single_file = self.segmenting.is_none() && !self.in_memory;
+        let mut dropped = 0;
+        for &(id, end) in unindexed {
+            let segment = match self.segments.iter_mut().find(|s| s.id == id) {
+                Some(s) => s,
+                None => continue,
+            };
+            let mut entry_count = 0;
+            let mut remove_count = 0;
+            // Only the entries after those in a saved index have to be read.
+            let mut from = segment.start;
+            if single_file && !salvage {
+                if let Some(saved) = segment.saved_index(end) {
+                    debug!(
+                        "Loaded {} keys from the saved index of {:?}",
+                        saved.entries.len(),
+                        segment.path
+                    );
+                    for (key, offset, len, expires_at) in saved.entries {
+                        let location = Location {
+                            segment: id,
+                            offset,
+                            len,
+                            expires_at,
+                        };
+                        index.insert(key, location);
+                    }
+                    entry_count = saved.entry_count;
+                    remove_count = saved.remove_count;
+                    from = saved.log_len;
+                }
+            }
+            // The open batch: the offset of its BeginBatch, the number of its entries still to
+            // come and the entries so far.
+            let mut batch: Option<(u64, u64, Vec<_>)> = None;
+            let on_entry = |entry: LogEntry, offset, len: u64| {
+                let location = Location {
+                    segment: id,
+                    offset,
+                    len: len as u32,
+                    expires_at: entry.expires_at,
+                };
+                let cmd = match entry.cmd {
+                    EntryCommand::BeginBatch(len) => {
+                        // A batch that is still open was never committed.
+                        batch = Some((offset, len, Vec::new()));
+                        return Ok(());
+                    }
+                    EntryCommand::CommitBatch => {
+                        if let Some((_, 0, entries)) = batch.take() {
+                            for (cmd, key, location) in entries {
+                                update_index(&mut index, cmd, key, location);
+                            }
+                        }
+                        return Ok(());
+                    }
+                    EntryCommand::Set => LogCommand::Set,
+                    EntryCommand::Remove => LogCommand::Remove,
+                };
+
+                entry_count += 1;
+                if let LogCommand::Remove = cmd {
+                    remove_count += 1;
+                }
+                match batch.as_mut() {
+                    Some((_, remaining, entries)) if *remaining > 0 => {
+                        *remaining -= 1;
+                        entries.push((cmd, entry.key, location));
+                    }
+                    _ => {
+                        // Any batch still open was never committed, this entry is not part of it.
+                        batch = None;
+                        // Update the index with the entry.
+                        update_index(&mut index, cmd, entry.key, location);
+                    }
+                }
+                Ok(())
+            };
+            let (read_count, truncated) = if salvage {
+                let (read_count, truncated, segment_dropped) =
+                    segment.salvage_scan(end, on_entry)?;
+                dropped += segment_dropped;
+                (read_count, truncated)
+            } else {
+                segment.scan(from, end, on_entry)?
+            };
+            segment.entry_count += entry_count;
+            segment.remove_count += remove_count;
+
+            if truncated && id != newest && !salvage {
+                // Segments are synced before a newer one is started, so this can't be an append
+                // that was cut short.
+                return Err(Error::from(CorruptLogError { offset: read_count }));
+            }
+            // A batch still open at the end was cut short along with the process writing it.
+            let uncommitted = batch.as_ref().map(|(offset, _, _)| *offset);
+            if (truncated || uncommitted.is_some()) && segment.writer.is_some() {
+                let len = uncommitted.unwrap_or(read_count);
+                if !self.pending.is_empty() {
+                    // Entries have already been appended after the partial one, it can't be cut off.
+                    return Err(Error::from(CorruptLogError { offset: len }));
+                }
+                warn!(
+                    "Truncating partially written entries from offset {} of {:?}",
+                    len, segment.path
+                );
+                segment.writer()?.get_mut().set_len(len)?;
+                segment.len = len;
+                // The fetch readers may have buffered the entries cut off.
+                segment.readers.readers.lock().unwrap().clear();
+                if let Some((_, _, entries)) = &batch {
+                    segment.entry_count -= entries.len();
+                    segment.remove_count -= entries
+                        .iter()
+                        .filter(|(cmd, _, _)| matches!(cmd, LogCommand::Remove))
+                        .count();
+                }
+            }
+        }
+
+        self.index = index;
+        for segment in self.segments.iter_mut() {
+            segment.live_count = 0;
+            segment.live_bytes = 0;
+        }
+        let mut live_counts: HashMap<u64, (usize, u64)> = HashMap::new();
+        for (_, location) in self.index.iter() {
+            let (count, bytes) = live_counts.entry(location.segment).or_default();
+            *count += 1;
+            *bytes += u64::from(location.len);
+        }
+        for (id, (live_count, live_bytes)) in live_counts {
+            if let Some(segment) = self.segment_mut(id) {
+                segment.live_count = live_count;
+                segment.live_bytes = live_bytes;
+            }
+        }
+
+        debug!("Index built with {} entries:", self.index.len());
+        Ok(dropped)
+    }
+}
+
+/// Returns the sequence numbers of the `<prefix>.<seq>.seg` files in the directory, in order.
+fn segment_ids(dir: &Path, prefix: &str) -> Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    for dent in dir.read_dir()? {
+        let name = dent?.file_name();
+        let id = name
+            .to_str()
+            .and_then(|n| n.strip_prefix(prefix))
+            .and_then(|n| n.strip_prefix('.'))
+            .and_then(|n| n.strip_suffix(".seg"))
+            .and_then(|n| n.parse().ok());
+        if let Some(id) = id {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// Where the entry for a key was found by `InnerAppendLog::lookup`.
+enum Lookup {
+    /// The key is not in the index, or its cached entry has expired.
+    Missing,
+    /// The entry was in the cache.
+    Cached(LogEntry),
+    /// The entry has to be read from the location.
+    Unread(Location),
+}
+
+/// The readers a segment fetches entries with, shared so they can be used outside the log's lock.
+///
+/// A fetch takes a reader, opening a new one if all are in use, and puts it back once done. The
+/// last reader put back is the next one taken, so a run of fetches reuses the same buffer.
+#[derive(Default)]
+struct ReaderPool {
+    readers: Mutex<Vec<SegmentReader>>,
+    /// The reads made from the segment file, by all of its readers.
+    reads: Arc<AtomicU64>,
+}
+
+impl ReaderPool {
+    /// Gives a reader taken with `Segment::take_reader` back to the pool.
+    fn put(&self, reader: SegmentReader) {
+        self.readers.lock().unwrap().push(reader);
+    }
+}
+
+/// A file descriptor for fetching entries from a segment.
+///
+/// The buffer is kept between fetches, as entries never change once written.
+struct SegmentReader {
+    reader: BufReader<CountingFile>,
+    /// The offset `reader` is positioned at, or None if it has to be seeked to an offset.
+    pos: Option<u64>,
+}
+
+impl SegmentReader {
+    /// Reads the LogEntry at the offset.
+    fn read_at(&mut self, offset: u64) -> Result<LogEntry> {
+        match self.pos.take() {
+            Some(pos) if pos == offset => {}
+            // A short hop keeps whatever of the buffer is still ahead of the new position.
+            Some(pos) => self.reader.seek_relative(offset as i64 - pos as i64)?,
+            None => {
+                self.reader.seek(SeekFrom::Start(offset))?;
+            }
+        }
+        let (entry, entry_len) = LogEntry::read_from(&mut self.reader, offset)?;
+        self.pos = Some(offset + entry_len);
+        Ok(entry)
+    }
+}
+
+/// A reader over the value of an entry, returned by `AppendLog::value_reader`.
+pub struct ValueReader {
+    source: ValueSource,
+}
+
+impl ValueReader {
+    fn buffered(val: Box<[u8]>) -> ValueReader {
+        ValueReader {
+            source: ValueSource::Buffered(io::Cursor::new(val)),
+        }
+    }
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.source {
+            ValueSource::Buffered(c) => c.read(buf),
+            ValueSource::Streamed(s) => s.read(buf),
+        }
+    }
+}
+
+enum ValueSource {
+    /// A value fetched whole.
+    Buffered(io::Cursor<Box<[u8]>>),
+    /// A value read from the log file as it is consumed.
+    Streamed(StreamedValue),
+}
+
+/// The value of an uncompressed, bincode encoded entry, read straight out of the segment file.
+///
+/// The checksum covers the whole entry, so it can only be checked once the last of the value
+/// has been read. A mismatch is returned as an `InvalidData` error from that final read.
+struct StreamedValue {
+    reader: io::Take<BufReader<Box<dyn LogStorage>>>,
+    /// The checksum of the entry up to the part of the value read so far.
+    crc: crc32fast::Hasher,
+    /// The bytes of the entry after the value, ending with its checksum.
+    tail: Vec<u8>,
+    /// The offset of the entry, for reporting corruption.
+    offset: u64,
+    checked: bool,
+}
+
+impl StreamedValue {
+    /// Opens the value of the entry for the key at the location, returning it with when the entry
+    /// expires.
+    ///
+    /// Returns None if the entry is not one whose value can be read in place.
+    fn open(
+        file: Box<dyn LogStorage>,
+        location: Location,
+        key: &[u8],
+    ) -> Result<Option<(StreamedValue, Option<u64>)>> {
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(location.offset))?;
+        let corrupt = || CorruptLogError {
+            offset: location.offset,
+        };
+
+        let len = reader.read_u32::<BigEndian>()?;
+        let mut header = [0u8; 3];
+        reader.read_exact(&mut header)?;
+        if len & VERSIONED_ENTRY == 0
+            || header[0] != ENTRY_VERSION
+            || Compression::from_tag(header[1]) != Some(Compression::None)
+            || Encoding::from_tag(header[2]) != Some(Encoding::Bincode)
+        {
+            return Ok(None);
+        }
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(&header);
+
+        // The bincode layout of a LogEntry: the command, the length prefixed key, then the value
+        // as an option tag followed by its length prefixed bytes.
+        let mut prefix = vec![0u8; 4 + 8];
+        reader.read_exact(&mut prefix)?;
+        let key_len = (&prefix[4..]).read_u64::<LittleEndian>()?;
+        if key_len != key.len() as u64 {
+            return Ok(None);
+        }
+        let key_start = prefix.len();
+        prefix.resize(key_start + key.len() + 1 + 8, 0);
+        reader.read_exact(&mut prefix[key_start..])?;
+        let (read_key, val_prefix) = prefix[key_start..].split_at(key.len());
+        if read_key != key || val_prefix[0] != 1 {
+            return Ok(None);
+        }
+        let val_len = (&val_prefix[1..]).read_u64::<LittleEndian>()?;
+        crc.update(&prefix);
+
+        let val_start = 4 + header.len() as u64 + prefix.len() as u64;
+        let tail_len = u64::from(location.len)
+            .checked_sub(val_start + val_len)
+            .filter(|l| *l >= 4)
+            .ok_or_else(corrupt)?;
+        let mut tail = vec![0u8; tail_len as usize];
+        reader.seek(SeekFrom::Start(location.offset + val_start + val_len))?;
+        reader.read_exact(&mut tail)?;
+        let expires_at = match tail[0] {
+            0 => None,
+            _ => Some((&tail[1..]).read_u64::<LittleEndian>()?),
+        };
+        reader.seek(SeekFrom::Start(location.offset + val_start))?;
+
+        let value = StreamedValue {
+            reader: reader.take(val_len),
+            crc,
+            tail,
+            offset: location.offset,
+            checked: false,
+        };
+        Ok(Some((value, expires_at)))
+    }
+}
+
+impl Read for StreamedValue {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.crc.update(&buf[..n]);
+        if n == 0 && !buf.is_empty() && !self.checked {
+            if self.reader.limit() > 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            self.checked = true;
+            let (rest, mut checksum) = self.tail.split_at(self.tail.len() - 4);
+            let mut crc = self.crc.clone();
+            crc.update(rest);
+            if crc.finalize() != checksum.read_u32::<BigEndian>()? {
+                let err = CorruptLogError {
+                    offset: self.offset,
+                };
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string()));
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// A handle on a segment that counts the reads made from it, so the effect of buffering on
+/// reads can be seen.
+struct CountingFile {
+    file: Box<dyn LogStorage>,
+    reads: Arc<AtomicU64>,
+}
+
+impl CountingFile {
+    fn new(file: Box<dyn LogStorage>, reads: Arc<AtomicU64>) -> CountingFile {
+        CountingFile { file, reads }
+    }
+}
+
+impl Read for CountingFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.file.read(buf)
+    }
+}
+
+impl Seek for CountingFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+/// The index of a log, mapping each live key to the location of its entry.
+///
+/// A hashed index is the quickest to look keys up in, an ordered one keeps the keys sorted so a
+/// range of them can be found without sorting every key.
+#[derive(Clone)]
+enum Index<S> {
+    Hashed(HashMap<Box<[u8]>, Location, S>),
+    Ordered(BTreeMap<Box<[u8]>, Location>),
+}
+
+impl<S: BuildHasher + Default> Index<S> {
+    fn new(ordered: bool) -> Index<S> {
+        if ordered {
+            Index::Ordered(BTreeMap::new())
+        } else {
+            Index::Hashed(HashMap::default())
+        }
+    }
+
+    fn is_ordered(&self) -> bool {
+        matches!(self, Index::Ordered(_))
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&Location> {
+        match self {
+            Index::Hashed(map) => map.get(key),
+            Index::Ordered(map) => map.get(key),
+        }
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    fn insert(&mut self, key: Box<[u8]>, location: Location) -> Option<Location> {
+        match self {
+            Index::Hashed(map) => map.insert(key, location),
+            Index::Ordered(map) => map.insert(key, location),
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Location> {
+        match self {
+            Index::Hashed(map) => map.remove(key),
+            Index::Ordered(map) => map.remove(key),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Index::Hashed(map) => map.len(),
+            Index::Ordered(map) => map.len(),
+        }
+    }
+
+    fn clear(&mut self) {
+        match self {
+            Index::Hashed(map) => map.clear(),
+            Index::Ordered(map) => map.clear(),
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&Box<[u8]>, &Location)> + '_> {
+        match self {
+            Index::Hashed(map) => Box::new(map.iter()),
+            Index::Ordered(map) => Box::new(map.iter()),
+        }
+    }
+
+    /// Returns the keys from `start`, inclusive, to `end`, exclusive, in sorted order, leaving
+    /// out those expired by `now`.
+    fn range(&self, start: &[u8], end: &[u8], now: u64) -> Vec<Vec<u8>> {
+        if start >= end {
+            return Vec::new();
+        }
+        match self {
+            Index::Hashed(map) => {
+                let mut keys: Vec<Vec<u8>> = map
+                    .iter()
+                    .filter(|(k, l)| &k[..] >= start && &k[..] < end && !l.is_expired(now))
+                    .map(|(k, _)| k.to_vec())
+                    .collect();
+                keys.sort_unstable();
+                keys
+            }
+            Index::Ordered(map) => map
+                .range::<[u8], _>((Bound::Included(start), Bound::Excluded(end)))
+                .filter(|(_, l)| !l.is_expired(now))
+                .map(|(k, _)| k.to_vec())
+                .collect(),
+        }
+    }
+}
+
+/// Applies a single LogCommand for the key at the given location to the index.
+///
+/// Returns the location of the entry the command superseded, if the key had one.
+fn update_index<S: BuildHasher + Default>(
+    index: &mut Index<S>,
+    cmd: LogCommand,
+    key: Box<[u8]>,
+    location: Location,
+) -> Option<Location> {
+    match cmd {
+        LogCommand::Set => index.insert(key, location),
+        LogCommand::Remove => index.remove(&key),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::hash::{BuildHasherDefault, Hasher};
+    use tempfile::TempPath;
+
+    /// A hasher that maps every key to the same hash, so every pair of keys collides.
+    #[derive(Default)]
+    struct CollidingHasher;
+
+    impl Hasher for CollidingHasher {
+        fn finish(&self) -> u64 {
+            0
+        }
+
+        fn write(&mut self, _bytes: &[u8]) {}
+    }
+
+    /// Creates an empty file that is removed again when the returned path is dropped.
+    fn create_empty_temp_file() -> TempPath {
+        tempfile::NamedTempFile::new().unwrap().into_temp_path()
+    }
+
+    #[test]
+    fn log_load_empty_file() {
+        let p = create_empty_temp_file();
+        InnerAppendLog::<RandomState>::load(&p, false, false).unwrap();
+    }
+
+    /// Writes to a log from `open`, then checks what it reads back, before and after opening
+    /// it again.
+    fn check_write_and_read(open: impl Fn() -> InnerAppendLog<RandomState>) {
+        {
+            let mut log = open();
+            log.append(LogCommand::Set, b"aaaa", Some(b"1111"), None)
+                .unwrap();
+            log.append(LogCommand::Set, b"bbbb", Some(b"2222"), None)
+                .unwrap();
+            log.append(LogCommand::Set, b"cccc", Some(b"3333"), None)
+                .unwrap();
+            log.append(LogCommand::Set, b"dddd", Some(b"4444"), None)
+                .unwrap();
+
+            assert_eq!(
+                log.fetch_by_key(b"aaaa").unwrap().unwrap().as_ref(),
+                b"1111"
+            );
+            assert_eq!(
+                log.fetch_by_key(b"bbbb").unwrap().unwrap().as_ref(),
+                b"2222"
+            );
+            assert_eq!(
+                log.fetch_by_key(b"cccc").unwrap().unwrap().as_ref(),
+                b"3333"
+            );
+            assert_eq!(
+                log.fetch_by_key(b"dddd").unwrap().unwrap().as_ref(),
+                b"4444"
+            );
+
+            log.append(LogCommand::Remove, b"aaaa", None, None).unwrap();
+            assert_eq!(log.fetch_by_key(b"aaaa").unwrap(), None);
+        }
+
+        {
+            let mut log = open();
+
+            assert_eq!(log.fetch_by_key(b"aaaa").unwrap(), None);
+            assert_eq!(
+                log.fetch_by_key(b"bbbb").unwrap().unwrap().as_ref(),
+                b"2222"
+            );
+            assert_eq!(
+                log.fetch_by_key(b"cccc").unwrap().unwrap().as_ref(),
+                b"3333"
+            );
+            assert_eq!(
+                log.fetch_by_key(b"dddd").unwrap().unwrap().as_ref(),
+                b"4444"
+            );
+        }
+    }
+
+    #[test]
+    fn log_write_and_read() {
+        let p = create_empty_temp_file();
+        check_write_and_read(|| InnerAppendLog::load(&p, false, false).unwrap());
+    }
+
+    /// A trivial `LogStorage` over a shared buffer, for logs kept outside of the crate.
+    #[derive(Clone, Default)]
+    struct VecStorage {
+        data: Arc<Mutex<Vec<u8>>>,
+        pos: u64,
+        /// The number of bytes that can still be written, or None for no limit.
+        room: Arc<Mutex<Option<usize>>>,
+        /// The number of calls to `write`.
+        writes: Arc<AtomicU64>,
+    }
+
+    impl Read for VecStorage {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let data = self.data.lock().unwrap();
+            let start = data.len().min(self.pos as usize);
+            let n = (data.len() - start).min(buf.len());
+            buf[..n].copy_from_slice(&data[start..start + n]);
+            self.pos += n as u64;
+            Ok(n)
+        }
+    }
+
+    impl Write for VecStorage {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            let mut room = self.room.lock().unwrap();
+            let n = room.map_or(buf.len(), |room| room.min(buf.len()));
+            if n == 0 && !buf.is_empty() {
+                return Err(io::Error::other("no space left"));
+            }
+            if let Some(room) = room.as_mut() {
+                *room -= n;
+            }
+            self.data.lock().unwrap().extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for VecStorage {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.pos = match pos {
+                SeekFrom::Start(offset) => offset,
+                SeekFrom::End(offset) => (self.len()? as i64 + offset) as u64,
+                SeekFrom::Current(offset) => (self.pos as i64 + offset) as u64,
+            };
+            Ok(self.pos)
+        }
+    }
 
-            assert_eq!(log.fetch_by_key(b"aaaa").unwrap(), None);
+    impl LogStorage for VecStorage {
+        fn len(&self) -> io::Result<u64> {
+            Ok(self.data.lock().unwrap().len() as u64)
+        }
+
+        fn open_reader(&self) -> io::Result<Box<dyn LogStorage>> {
+            Ok(Box::new(VecStorage {
+                data: self.data.clone(),
+                pos: 0,
+                room: self.room.clone(),
+                writes: self.writes.clone(),
+            }))
+        }
+
+        fn open_writer(&self) -> io::Result<Box<dyn LogStorage>> {
+            self.open_reader()
+        }
+
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            self.data.lock().unwrap().resize(len as usize, 0);
+            Ok(())
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn log_write_and_read_custom_storage() {
+        let storage = VecStorage::default();
+        check_write_and_read(|| {
+            InnerAppendLog::with_storage(Path::new("log"), Box::new(storage.clone())).unwrap()
+        });
+        assert!(!Path::new("log").exists());
+    }
+
+    #[test]
+    fn log_buffers_small_writes() {
+        let storage = VecStorage::default();
+        let mut log = InnerAppendLog::<RandomState>::with_storage(
+            Path::new("log"),
+            Box::new(storage.clone()),
+        )
+        .unwrap();
+        let writes = storage.writes.load(Ordering::Relaxed);
+        for i in 0..5000 {
+            let key = format!("key{}", i);
+            log.append(LogCommand::Set, key.as_bytes(), Some(b"val"), None)
+                .unwrap();
+        }
+        log.flush().unwrap();
+        let writes = storage.writes.load(Ordering::Relaxed) - writes;
+        assert!(writes <= 50, "{} writes for 5000 entries", writes);
+
+        for i in 0..5000 {
+            let key = format!("key{}", i);
             assert_eq!(
-                log.fetch_by_key(b"bbbb").unwrap().unwrap().as_ref(),
-                b"2222"
+                log.fetch_by_key(key.as_bytes()).unwrap().unwrap().as_ref(),
+                b"val"
             );
+        }
+
+        // Without a buffer, every entry is written by itself.
+        log.write_buffer_size = 0;
+        let writes = storage.writes.load(Ordering::Relaxed);
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            log.append(LogCommand::Set, key.as_bytes(), Some(b"new"), None)
+                .unwrap();
+        }
+        assert_eq!(storage.writes.load(Ordering::Relaxed) - writes, 10);
+        assert_eq!(log.fetch_by_key(b"key9").unwrap().unwrap().as_ref(), b"new");
+    }
+
+    #[test]
+    fn log_short_write_leaves_storage_unchanged() {
+        let storage = VecStorage::default();
+        let mut log = InnerAppendLog::<RandomState>::with_storage(
+            Path::new("log"),
+            Box::new(storage.clone()),
+        )
+        .unwrap();
+        log.append(LogCommand::Set, b"aaaa", Some(b"1111"), None)
+            .unwrap();
+        log.flush().unwrap();
+        let len = storage.len().unwrap();
+
+        // A value larger than the write buffer is written straight through, and only part of it
+        // fits.
+        *storage.room.lock().unwrap() = Some(100);
+        let big = vec![7u8; 64 * 1024];
+        assert!(log
+            .append(LogCommand::Set, b"bbbb", Some(&big), None)
+            .is_err());
+        assert_eq!(storage.len().unwrap(), len);
+        assert_eq!(log.len().unwrap(), 1);
+        assert_eq!(log.fetch_by_key(b"bbbb").unwrap(), None);
+
+        *storage.room.lock().unwrap() = None;
+        log.append(LogCommand::Set, b"cccc", Some(b"3333"), None)
+            .unwrap();
+        log.flush().unwrap();
+        drop(log);
+
+        let mut log =
+            InnerAppendLog::<RandomState>::with_storage(Path::new("log"), Box::new(storage))
+                .unwrap();
+        assert_eq!(log.len().unwrap(), 2);
+        assert_eq!(
+            log.fetch_by_key(b"cccc").unwrap().unwrap().as_ref(),
+            b"3333"
+        );
+    }
+
+    #[test]
+    fn log_short_batch_write_leaves_storage_unchanged() {
+        let storage = VecStorage::default();
+        let mut log = InnerAppendLog::<RandomState>::with_storage(
+            Path::new("log"),
+            Box::new(storage.clone()),
+        )
+        .unwrap();
+        log.append(LogCommand::Set, b"aaaa", Some(b"1111"), None)
+            .unwrap();
+        log.flush().unwrap();
+        let len = storage.len().unwrap();
+
+        // Only the start of the batch fits, its BeginBatch must not be left behind.
+        *storage.room.lock().unwrap() = Some(100);
+        let big = vec![7u8; 64 * 1024];
+        let batch: [BatchEntry; 2] = [
+            (LogCommand::Set, b"bbbb", Some(b"2222")),
+            (LogCommand::Set, b"big", Some(&big)),
+        ];
+        assert!(log.append_batch(&batch).is_err());
+        assert_eq!(storage.len().unwrap(), len);
+        assert_eq!(log.len().unwrap(), 1);
+        assert_eq!(log.fetch_by_key(b"bbbb").unwrap(), None);
+
+        // Later appends are not mistaken for the rest of the failed batch.
+        *storage.room.lock().unwrap() = None;
+        for key in [b"cccc", b"dddd", b"eeee"] {
+            log.append(LogCommand::Set, key, Some(b"3333"), None)
+                .unwrap();
+        }
+        log.flush().unwrap();
+        drop(log);
+
+        let mut log =
+            InnerAppendLog::<RandomState>::with_storage(Path::new("log"), Box::new(storage))
+                .unwrap();
+        assert_eq!(log.len().unwrap(), 4);
+        for key in [b"cccc", b"dddd", b"eeee"] {
+            assert_eq!(log.fetch_by_key(key).unwrap().unwrap().as_ref(), b"3333");
+        }
+        assert_eq!(log.fetch_by_key(b"bbbb").unwrap(), None);
+    }
+
+    #[test]
+    fn log_counts_tombstones() {
+        let p = create_empty_temp_file();
+        {
+            let mut log = AppendLog::load(&p).unwrap();
+            log.append(LogCommand::Set, b"aaaa", Some(b"1111")).unwrap();
+            log.append(LogCommand::Set, b"bbbb", Some(b"2222")).unwrap();
+            log.append(LogCommand::Remove, b"aaaa", None).unwrap();
+
+            assert_eq!(log.tombstone_count().unwrap(), 1);
+            assert_eq!(log.index_len().unwrap(), 1);
+            assert_eq!(log.len().unwrap(), 3);
+        }
+
+        // The removes are counted again when the log is loaded.
+        let log = AppendLog::load(&p).unwrap();
+        assert_eq!(log.tombstone_count().unwrap(), 1);
+        assert_eq!(log.len().unwrap(), 3);
+    }
+
+    #[test]
+    fn log_buffers_appends_until_flush() {
+        let p = create_empty_temp_file();
+        let mut log = AppendLog::load(&p).unwrap();
+        log.append(LogCommand::Set, b"aaaa", Some(b"1111")).unwrap();
+        assert_eq!(p.metadata().unwrap().len(), HEADER_LEN);
+
+        // Reads see entries that are still buffered.
+        assert_eq!(
+            log.fetch_by_key(b"aaaa").unwrap().unwrap().as_ref(),
+            b"1111"
+        );
+
+        log.append(LogCommand::Set, b"bbbb", Some(b"2222")).unwrap();
+        log.flush().unwrap();
+        let len = p.metadata().unwrap().len();
+        assert_eq!(len, log.byte_len());
+
+        // Offsets of entries appended after a flush still line up with the file.
+        log.append(LogCommand::Set, b"cccc", Some(b"3333")).unwrap();
+        assert_eq!(
+            log.inner.lock().unwrap().index.get(b"cccc").unwrap().offset,
+            len
+        );
+        assert_eq!(
+            log.fetch_by_key(b"cccc").unwrap().unwrap().as_ref(),
+            b"3333"
+        );
+    }
+
+    #[test]
+    fn log_reuses_read_buffer() {
+        let p = create_empty_temp_file();
+        {
+            let mut log = AppendLog::load(&p).unwrap();
+            for key_id in 0..10_000 {
+                let key = format!("key{}", key_id);
+                log.append(LogCommand::Set, key.as_bytes(), Some(b"value"))
+                    .unwrap();
+            }
+        }
+
+        let log = AppendLog::load(&p).unwrap();
+        let mut index: Vec<_> = log.clone_index().unwrap().into_iter().collect();
+        index.sort_by_key(|(_, location)| *location);
+        let reads = log.disk_reads();
+        for (key, _) in &index {
+            assert_eq!(log.fetch_by_key(key).unwrap().unwrap().as_ref(), b"value");
+        }
+        // Fetching in log order reads each buffer's worth of entries from the file once.
+        let fetch_reads = log.disk_reads() - reads;
+        assert!(fetch_reads < 10_000 / 10, "{} reads", fetch_reads);
+
+        // Jumping back and forth still finds the right entries.
+        for (key, _) in index.iter().rev().step_by(97) {
+            assert_eq!(log.fetch_by_key(key).unwrap().unwrap().as_ref(), b"value");
+        }
+    }
+
+    #[test]
+    fn log_caches_entries() {
+        let p = create_empty_temp_file();
+        let mut log = AppendLog::load(&p).unwrap();
+        log.set_cache_capacity(2);
+        log.append(LogCommand::Set, b"aaaa", Some(b"1111")).unwrap();
+        log.append(LogCommand::Set, b"bbbb", Some(b"2222")).unwrap();
+        log.append(LogCommand::Set, b"cccc", Some(b"3333")).unwrap();
+
+        log.fetch_by_key(b"aaaa").unwrap();
+        assert_eq!(log.cache_hits(), 0);
+        assert_eq!(
+            log.fetch_by_key(b"aaaa").unwrap().unwrap().as_ref(),
+            b"1111"
+        );
+        assert_eq!(log.cache_hits(), 1);
+
+        // An overwrite moves the key to a new offset, the old entry is dropped from the cache.
+        log.append(LogCommand::Set, b"aaaa", Some(b"4444")).unwrap();
+        assert_eq!(
+            log.fetch_by_key(b"aaaa").unwrap().unwrap().as_ref(),
+            b"4444"
+        );
+        assert_eq!(log.cache_hits(), 1);
+        log.append(LogCommand::Remove, b"aaaa", None).unwrap();
+        assert_eq!(log.fetch_by_key(b"aaaa").unwrap(), None);
+
+        // The least recently used entry is evicted once the cache is full.
+        log.fetch_by_key(b"bbbb").unwrap();
+        log.fetch_by_key(b"cccc").unwrap();
+        log.fetch_by_key(b"bbbb").unwrap();
+        assert_eq!(log.cache_hits(), 2);
+        log.set_cache_capacity(1);
+        log.fetch_by_key(b"cccc").unwrap();
+        assert_eq!(log.cache_hits(), 2);
+
+        log.set_cache_capacity(0);
+        log.fetch_by_key(b"cccc").unwrap();
+        assert_eq!(log.cache_hits(), 2);
+    }
+
+    #[test]
+    fn log_open_lazy() {
+        let p = create_empty_temp_file();
+
+        {
+            let mut log = InnerAppendLog::<RandomState>::load(&p, false, false).unwrap();
+            log.append(LogCommand::Set, b"aaaa", Some(b"1111"), None)
+                .unwrap();
+            log.append(LogCommand::Set, b"bbbb", Some(b"2222"), None)
+                .unwrap();
+        }
+
+        let mut log = AppendLog::open_lazy(&p).unwrap();
+        assert!(log.inner.lock().unwrap().unindexed.is_some());
+
+        // Appends before the index is built are merged in once it is.
+        log.append(LogCommand::Set, b"cccc", Some(b"3333")).unwrap();
+        log.append(LogCommand::Remove, b"aaaa", None).unwrap();
+        assert!(log.inner.lock().unwrap().unindexed.is_some());
+
+        assert_eq!(log.fetch_by_key(b"aaaa").unwrap(), None);
+        assert!(log.inner.lock().unwrap().unindexed.is_none());
+        assert_eq!(
+            log.fetch_by_key(b"bbbb").unwrap().unwrap().as_ref(),
+            b"2222"
+        );
+        assert_eq!(
+            log.fetch_by_key(b"cccc").unwrap().unwrap().as_ref(),
+            b"3333"
+        );
+        assert_eq!(log.len().unwrap(), 4);
+        assert_eq!(log.index_len().unwrap(), 2);
+    }
+
+    #[test]
+    fn log_indexes_large_log() {
+        let p = create_empty_temp_file();
+        let mut expected = HashMap::new();
+        {
+            let mut log = AppendLog::load(&p).unwrap();
+            for i in 0..20_000u32 {
+                let key = format!("key{}", i % 5000).into_bytes();
+                if i % 7 == 0 {
+                    log.append(LogCommand::Remove, &key, None).unwrap();
+                    expected.remove(&key);
+                } else {
+                    let val = i.to_string().into_bytes();
+                    log.append(LogCommand::Set, &key, Some(&val)).unwrap();
+                    expected.insert(key, val);
+                }
+            }
+        }
+
+        let log = AppendLog::load(&p).unwrap();
+        assert_eq!(log.len().unwrap(), 20_000);
+        assert_eq!(log.index_len().unwrap(), expected.len());
+        for (key, val) in &expected {
+            assert_eq!(log.fetch_by_key(key).unwrap().unwrap().as_ref(), &val[..]);
+        }
+
+        // Building the index later reads the same entries.
+        let index = log.clone_index().unwrap();
+        drop(log);
+        let lazy = AppendLog::open_lazy(&p).unwrap();
+        assert_eq!(lazy.clone_index().unwrap(), index);
+        assert_eq!(lazy.len().unwrap(), 20_000);
+    }
+
+    #[test]
+    fn log_index_hash_collision() {
+        type CollidingState = BuildHasherDefault<CollidingHasher>;
+        let p = create_empty_temp_file();
+
+        let hasher = CollidingState::default();
+        let (k1, k2): (&[u8], &[u8]) = (b"aaaa", b"bbbb");
+        assert_eq!(hasher.hash_one(k1), hasher.hash_one(k2));
+
+        {
+            let mut log = InnerAppendLog::<CollidingState>::load(&p, false, false).unwrap();
+            log.append(LogCommand::Set, k1, Some(b"1111"), None)
+                .unwrap();
+            log.append(LogCommand::Set, k2, Some(b"2222"), None)
+                .unwrap();
+
+            assert_ne!(log.index.get(k1), log.index.get(k2));
+            assert_eq!(log.fetch_by_key(k1).unwrap().unwrap().as_ref(), b"1111");
+            assert_eq!(log.fetch_by_key(k2).unwrap().unwrap().as_ref(), b"2222");
+        }
+
+        // The index built from disk must resolve the same collision.
+        let mut log = InnerAppendLog::<CollidingState>::load(&p, false, false).unwrap();
+        assert_eq!(log.index_len().unwrap(), 2);
+        assert_ne!(log.index.get(k1), log.index.get(k2));
+        assert_eq!(log.fetch_by_key(k1).unwrap().unwrap().as_ref(), b"1111");
+        assert_eq!(log.fetch_by_key(k2).unwrap().unwrap().as_ref(), b"2222");
+    }
+
+    #[test]
+    fn log_clone_index() {
+        let p = create_empty_temp_file();
+        let mut log = AppendLog::load(&p).unwrap();
+        log.append(LogCommand::Set, b"aaaa", Some(b"1111")).unwrap();
+        log.append(LogCommand::Set, b"bbbb", Some(b"2222")).unwrap();
+
+        let index = log.clone_index().unwrap();
+        log.append(LogCommand::Set, b"cccc", Some(b"3333")).unwrap();
+        log.append(LogCommand::Remove, b"aaaa", None).unwrap();
+
+        // The copy is a snapshot and does not see the later writes.
+        assert_eq!(index.len(), 2);
+        assert!(index.contains_key(b"aaaa".as_ref()));
+        assert!(!index.contains_key(b"cccc".as_ref()));
+        assert_eq!(log.index_len().unwrap(), 2);
+        assert!(!log.contains(b"aaaa").unwrap());
+    }
+
+    #[test]
+    fn log_rejects_unsupported_file_version() {
+        let p = create_empty_temp_file();
+        {
+            let mut log = AppendLog::load(&p).unwrap();
+            log.append(LogCommand::Set, b"aaaa", Some(b"1111")).unwrap();
+        }
+        let data = fs::read(&p).unwrap();
+        assert_eq!(&data[..4], FILE_MAGIC);
+        assert_eq!(data[4], FILE_VERSION);
+        let log = AppendLog::load(&p).unwrap();
+        assert_eq!(log.offset_of(b"aaaa").unwrap(), Some(HEADER_LEN));
+        drop(log);
+
+        let mut file = OpenOptions::new().write(true).open(&p).unwrap();
+        file.seek(SeekFrom::Start(4)).unwrap();
+        file.write_all(&[FILE_VERSION + 1]).unwrap();
+        drop(file);
+        let err = AppendLog::load(&p).err().unwrap();
+        let err = err.downcast::<UnsupportedVersionError>().unwrap();
+        assert_eq!(err.version, FILE_VERSION + 1);
+    }
+
+    #[test]
+    fn log_offset_of() {
+        let p = create_empty_temp_file();
+        let mut log = AppendLog::load(&p).unwrap();
+        log.append(LogCommand::Set, b"aaaa", Some(b"1111")).unwrap();
+        log.append(LogCommand::Set, b"bbbb", Some(b"2222")).unwrap();
+        log.append(LogCommand::Set, b"aaaa", Some(b"3333")).unwrap();
+        log.flush().unwrap();
+
+        assert_eq!(log.offset_of(b"cccc").unwrap(), None);
+        let offset = log.offset_of(b"aaaa").unwrap().unwrap();
+        assert!(offset > 0);
+        let mut f = File::open(&p).unwrap();
+        f.seek(SeekFrom::Start(offset)).unwrap();
+        let (entry, _) = LogEntry::read_from(&mut f, offset).unwrap();
+        assert_eq!(entry.key.as_ref(), b"aaaa");
+        assert_eq!(entry.val.as_deref(), Some(b"3333".as_ref()));
+    }
+
+    #[test]
+    fn log_value_reader_checks_streamed_value() {
+        let p = create_empty_temp_file();
+        let mut log = AppendLog::load(&p).unwrap();
+        log.append(LogCommand::Set, b"aaaa", Some(b"1111")).unwrap();
+        log.flush().unwrap();
+
+        let mut val = Vec::new();
+        let mut reader = log.value_reader(b"aaaa").unwrap().unwrap();
+        assert!(matches!(reader.source, ValueSource::Streamed(_)));
+        reader.read_to_end(&mut val).unwrap();
+        assert_eq!(val, b"1111");
+
+        // Flip the last byte of the value, which is followed by the expiry and the checksum.
+        let len = fs::metadata(&p).unwrap().len();
+        let mut f = OpenOptions::new().write(true).open(&p).unwrap();
+        f.seek(SeekFrom::Start(len - 4 - 1 - 1)).unwrap();
+        f.write_all(b"2").unwrap();
+        drop(f);
+
+        let mut reader = log.value_reader(b"aaaa").unwrap().unwrap();
+        let err = reader.read_to_end(&mut Vec::new()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn log_detects_corrupt_entry() {
+        let p = create_empty_temp_file();
+
+        let corrupt_offset = {
+            let mut log = InnerAppendLog::<RandomState>::load(&p, false, false).unwrap();
+            log.append(LogCommand::Set, b"aaaa", Some(b"1111"), None)
+                .unwrap();
+            log.append(LogCommand::Set, b"bbbb", Some(b"2222"), None)
+                .unwrap();
+            log.append(LogCommand::Set, b"cccc", Some(b"3333"), None)
+                .unwrap();
+            log.index.get(b"bbbb").unwrap().offset
+        };
+
+        // Flip a bit in the last byte of the second entry's value.
+        let mut data = fs::read(&p).unwrap();
+        let value = data.windows(4).position(|w| w == b"2222").unwrap();
+        data[value + 3] ^= 0x01;
+        fs::write(&p, data).unwrap();
+
+        let err = InnerAppendLog::<RandomState>::load(&p, false, false)
+            .err()
+            .unwrap();
+        let err = err.downcast::<CorruptLogError>().unwrap();
+        assert_eq!(err.offset, corrupt_offset);
+    }
+
+    #[test]
+    fn log_salvage_skips_damage_longer_than_a_chunk() {
+        let p = create_empty_temp_file();
+        let big = vec![0x7fu8; 3 * SALVAGE_CHUNK_LEN];
+        {
+            let mut log = InnerAppendLog::<RandomState>::load(&p, false, false).unwrap();
+            log.append(LogCommand::Set, b"aaaa", Some(b"1111"), None)
+                .unwrap();
+            log.append(LogCommand::Set, b"bbbb", Some(&big), None)
+                .unwrap();
+            log.append(LogCommand::Set, b"cccc", Some(b"3333"), None)
+                .unwrap();
+        }
+
+        // Flip a bit in the first byte of the second entry's value, several chunks before the
+        // third entry.
+        let mut data = fs::read(&p).unwrap();
+        let value = data.windows(4).position(|w| w == [0x7f; 4]).unwrap();
+        data[value] ^= 0x01;
+        fs::write(&p, &data).unwrap();
+
+        let report = AppendLog::verify_file(&p).unwrap();
+        assert_eq!(report.good_entries, 2);
+        assert_eq!(report.corrupt_entries, 1);
+        assert_eq!(fs::read(&p).unwrap(), data);
+
+        let (log, dropped) = AppendLog::open_with_recovery(&p).unwrap();
+        assert_eq!(dropped, 1);
+        assert_eq!(log.fetch_by_key(b"bbbb").unwrap(), None);
+        assert_eq!(
+            log.fetch_by_key(b"cccc").unwrap().unwrap().as_ref(),
+            b"3333"
+        );
+    }
+
+    #[test]
+    fn log_reads_entries_without_checksums() {
+        let p = create_empty_temp_file();
+
+        // Write an entry in the format from before checksums were added.
+        {
+            let entry = LogEntryV1 {
+                cmd: LogCommand::Set,
+                key: Box::from(b"aaaa".as_ref()),
+                val: Some(Box::from(b"1111".as_ref())),
+            };
+            let entry_encoded = bincode::serialize(&entry).unwrap();
+            let mut f = OpenOptions::new().append(true).open(&p).unwrap();
+            f.write_u32::<BigEndian>(entry_encoded.len() as u32)
+                .unwrap();
+            f.write_all(&entry_encoded).unwrap();
+        }
+
+        {
+            let mut log = InnerAppendLog::<RandomState>::load(&p, false, false).unwrap();
             assert_eq!(
-                log.fetch_by_key(b"cccc").unwrap().unwrap().as_ref(),
-                b"3333"
+                log.fetch_by_key(b"aaaa").unwrap().unwrap().as_ref(),
+                b"1111"
             );
+            log.append(LogCommand::Set, b"bbbb", Some(b"2222"), None)
+                .unwrap();
+        }
+
+        // A log mixing both formats loads as well.
+        let mut log = InnerAppendLog::<RandomState>::load(&p, false, false).unwrap();
+        assert_eq!(
+            log.fetch_by_key(b"aaaa").unwrap().unwrap().as_ref(),
+            b"1111"
+        );
+        assert_eq!(
+            log.fetch_by_key(b"bbbb").unwrap().unwrap().as_ref(),
+            b"2222"
+        );
+    }
+
+    /// Appends an entry with the given version byte and encoded body, framed as `write_to` does.
+    fn append_versioned_entry(p: &Path, version: u8, body: &[u8]) {
+        let mut versioned_data = vec![version];
+        versioned_data.extend(body);
+        let mut f = OpenOptions::new().append(true).open(p).unwrap();
+        f.write_u32::<BigEndian>(body.len() as u32 | VERSIONED_ENTRY)
+            .unwrap();
+        f.write_all(&versioned_data).unwrap();
+        f.write_u32::<BigEndian>(crc32fast::hash(&versioned_data))
+            .unwrap();
+    }
+
+    #[test]
+    fn log_reads_version_1_entries() {
+        let p = create_empty_temp_file();
+
+        // Write an entry in the format from before entries could expire.
+        let entry = LogEntryV1 {
+            cmd: LogCommand::Set,
+            key: Box::from(b"aaaa".as_ref()),
+            val: Some(Box::from(b"1111".as_ref())),
+        };
+        append_versioned_entry(&p, 1, &bincode::serialize(&entry).unwrap());
+
+        let mut log = InnerAppendLog::<RandomState>::load(&p, false, false).unwrap();
+        let entry = log.fetch_entry(b"aaaa").unwrap().unwrap();
+        assert_eq!(entry.val.unwrap().as_ref(), b"1111");
+        assert_eq!(entry.expires_at, None);
+    }
+
+    #[test]
+    fn log_reads_version_2_entries() {
+        let p = create_empty_temp_file();
+
+        // Write an entry in the format from before entries could be compressed.
+        let entry = LogEntry::new(LogCommand::Set, b"aaaa", Some(b"1111"), Some(u64::MAX));
+        append_versioned_entry(&p, 2, &bincode::serialize(&entry).unwrap());
+
+        let mut log = InnerAppendLog::<RandomState>::load(&p, false, false).unwrap();
+        let entry = log.fetch_entry(b"aaaa").unwrap().unwrap();
+        assert_eq!(entry.val.unwrap().as_ref(), b"1111");
+        assert_eq!(entry.expires_at, Some(u64::MAX));
+    }
+
+    #[test]
+    fn log_mixes_compressions() {
+        let p = create_empty_temp_file();
+        let val = vec![b'a'; 4096];
+
+        {
+            let mut log = AppendLog::load(&p).unwrap();
+            for (key, compression) in [
+                (b"none", Compression::None),
+                (b"zstd", Compression::Zstd),
+                (b"lz4_", Compression::Lz4),
+            ] {
+                log.set_compression(compression);
+                log.append(LogCommand::Set, key, Some(&val)).unwrap();
+            }
+        }
+
+        let log = AppendLog::load(&p).unwrap();
+        for key in [b"none", b"zstd", b"lz4_"] {
+            assert_eq!(log.fetch_by_key(key).unwrap().unwrap().as_ref(), &val[..]);
+        }
+        // Only the uncompressed entry takes up its full size.
+        assert!(p.metadata().unwrap().len() < 2 * 4096);
+
+        // An unknown compression tag is reported rather than misread.
+        let p = create_empty_temp_file();
+        append_versioned_entry(&p, ENTRY_VERSION, &[7, 0, 0, 0]);
+        let err = InnerAppendLog::<RandomState>::load(&p, false, false)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.downcast::<UnsupportedCompressionError>().unwrap().tag,
+            7
+        );
+    }
+
+    #[test]
+    fn log_mixes_encodings() {
+        let p = create_empty_temp_file();
+
+        // An entry from before encodings were recorded is read as bincode.
+        let entry = LogEntry::new(LogCommand::Set, b"old_", Some(b"0000"), None);
+        let mut body = vec![Compression::None.tag()];
+        body.extend(bincode::serialize(&entry).unwrap());
+        append_versioned_entry(&p, 3, &body);
+        {
+            let mut log = AppendLog::load(&p).unwrap();
+            for (key, compression, encoding) in [
+                (b"bin_", Compression::None, Encoding::Bincode),
+                (b"json", Compression::None, Encoding::Json),
+                (b"zjsn", Compression::Zstd, Encoding::Json),
+            ] {
+                log.set_compression(compression);
+                log.set_encoding(encoding);
+                log.append(LogCommand::Set, key, Some(key)).unwrap();
+            }
+        }
+
+        let log = AppendLog::load(&p).unwrap();
+        assert_eq!(
+            log.fetch_by_key(b"old_").unwrap().unwrap().as_ref(),
+            b"0000"
+        );
+        for key in [b"bin_", b"json", b"zjsn"] {
+            assert_eq!(log.fetch_by_key(key).unwrap().unwrap().as_ref(), key);
+        }
+        // The uncompressed JSON entry can be read without knowing the layout.
+        let data = fs::read(&p).unwrap();
+        assert!(data.windows(6).any(|w| w == b"\"key\":"));
+
+        // An unknown encoding tag is reported rather than misread.
+        let p = create_empty_temp_file();
+        append_versioned_entry(&p, ENTRY_VERSION, &[0, 7, 0, 0]);
+        let err = InnerAppendLog::<RandomState>::load(&p, false, false)
+            .err()
+            .unwrap();
+        assert_eq!(err.downcast::<UnsupportedEncodingError>().unwrap().tag, 7);
+    }
+
+    #[test]
+    fn log_expires_entries() {
+        let p = create_empty_temp_file();
+        let now = SystemClock.now_millis();
+
+        let mut log = InnerAppendLog::<RandomState>::load(&p, false, false).unwrap();
+        log.append(LogCommand::Set, b"aaaa", Some(b"1111"), Some(now - 1))
+            .unwrap();
+        log.append(LogCommand::Set, b"bbbb", Some(b"2222"), Some(now + 60_000))
+            .unwrap();
+        assert_eq!(log.index_len().unwrap(), 2);
+
+        // The expired entry is dropped from the index once it is fetched.
+        assert_eq!(log.fetch_by_key(b"aaaa").unwrap(), None);
+        assert!(!log.contains(b"aaaa").unwrap());
+        assert_eq!(
+            log.fetch_by_key(b"bbbb").unwrap().unwrap().as_ref(),
+            b"2222"
+        );
+
+        // Compaction keeps the expiry of the entries it copies.
+        let compacted = create_empty_temp_file();
+        fs::remove_file(&compacted).unwrap();
+        let mut log = log.compact(&compacted, |_, _| {}).unwrap();
+        let entry = log.fetch_entry(b"bbbb").unwrap().unwrap();
+        assert_eq!(entry.expires_at, Some(now + 60_000));
+        assert_eq!(log.index_len().unwrap(), 1);
+    }
+
+    #[test]
+    fn log_compacts_segments() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let set_len = LogEntry::new(LogCommand::Set, b"a", Some(b"1"), None)
+            .write_to(&mut Vec::new(), Compression::None, Encoding::Bincode)
+            .unwrap();
+        let open = || {
+            InnerAppendLog::<RandomState>::load_segmented(dir.path(), "log", 4 * set_len).unwrap()
+        };
+
+        {
+            let mut log = open();
+            for key in [b"a", b"d", b"e", b"f"] {
+                log.append(LogCommand::Set, key, Some(b"1"), None).unwrap();
+            }
+            log.append(LogCommand::Remove, b"a", None, None).unwrap();
+            for _ in 0..5 {
+                log.append(LogCommand::Set, b"b", Some(b"1"), None).unwrap();
+            }
+            let entries: Vec<_> = log.segments.iter().map(|s| s.entry_count).collect();
+            assert_eq!(entries, vec![4, 5, 1]);
+
+            // Only the second segment is mostly dead, but its remove still hides the value in
+            // the first.
+            assert_eq!(log.compact_segments(2, |_, _| {}).unwrap(), 1);
+            let entries: Vec<_> = log.segments.iter().map(|s| s.entry_count).collect();
+            assert_eq!(entries, vec![4, 1, 1]);
+            assert_eq!(log.fetch_by_key(b"a").unwrap(), None);
+            assert_eq!(log.fetch_by_key(b"d").unwrap().unwrap().as_ref(), b"1");
+        }
+
+        let mut log = open();
+        assert_eq!(log.len().unwrap(), 6);
+        assert_eq!(log.fetch_by_key(b"a").unwrap(), None);
+        assert_eq!(log.fetch_by_key(b"b").unwrap().unwrap().as_ref(), b"1");
+
+        // Once nothing older is left the remove is dropped, and the emptied segment with it.
+        log.append(LogCommand::Remove, b"d", None, None).unwrap();
+        log.append(LogCommand::Remove, b"e", None, None).unwrap();
+        log.append(LogCommand::Remove, b"f", None, None).unwrap();
+        assert_eq!(log.compact_segments(2, |_, _| {}).unwrap(), 2);
+        assert_eq!(log.segments.len(), 1);
+        assert_eq!(log.index_len().unwrap(), 1);
+    }
+
+    #[test]
+    fn log_drops_uncommitted_batch() {
+        let p = create_empty_temp_file();
+
+        let committed_len = {
+            let mut log = InnerAppendLog::<RandomState>::load(&p, false, false).unwrap();
+            log.append_batch(&[
+                (LogCommand::Set, b"aaaa", Some(b"1111")),
+                (LogCommand::Set, b"bbbb", Some(b"2222")),
+            ])
+            .unwrap();
+            let committed_len = log.active().len;
+
+            // Write a batch as if the process died before its last entry and CommitBatch.
+            log.write_entry(&LogEntry::marker(EntryCommand::BeginBatch(2)))
+                .unwrap();
+            log.write_entry(&LogEntry::new(LogCommand::Remove, b"aaaa", None, None))
+                .unwrap();
+            committed_len
+        };
+
+        {
+            let mut log = InnerAppendLog::<RandomState>::load(&p, false, false).unwrap();
             assert_eq!(
-                log.fetch_by_key(b"dddd").unwrap().unwrap().as_ref(),
-                b"4444"
+                log.fetch_by_key(b"aaaa").unwrap().unwrap().as_ref(),
+                b"1111"
             );
+            assert_eq!(log.len().unwrap(), 2);
+
+            // The partial batch is cut off, so it can't take in the entries appended after it.
+            assert_eq!(p.metadata().unwrap().len(), committed_len);
+            log.append(LogCommand::Remove, b"bbbb", None, None).unwrap();
         }
+
+        let mut log = InnerAppendLog::<RandomState>::load(&p, false, false).unwrap();
+        assert_eq!(log.index_len().unwrap(), 1);
+        assert_eq!(log.fetch_by_key(b"bbbb").unwrap(), None);
     }
 }
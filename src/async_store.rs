@@ -0,0 +1,51 @@
+//! An async facade over `KvStore` for use from a tokio runtime.
+
+use crate::{KvStore, Result};
+use std::path::PathBuf;
+use tokio::task;
+
+/// Wraps a `KvStore`, running each operation on tokio's blocking thread pool so the lock and
+/// file I/O never stall the executor.
+///
+/// Clones share the underlying store, as clones of `KvStore` do.
+#[derive(Clone)]
+pub struct AsyncKvStore {
+    store: KvStore,
+}
+
+impl AsyncKvStore {
+    /// Wraps an already open store.
+    pub fn new(store: KvStore) -> AsyncKvStore {
+        AsyncKvStore { store }
+    }
+
+    /// Opens a store for the given path, as `KvStore::open` does.
+    pub async fn open(path: impl Into<PathBuf>) -> Result<AsyncKvStore> {
+        let path = path.into();
+        let store = task::spawn_blocking(move || KvStore::open(&path)).await??;
+        Ok(AsyncKvStore::new(store))
+    }
+
+    /// Get the value associated with the provided key, or None otherwise.
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        let store = self.store.clone();
+        task::spawn_blocking(move || store.get(key)).await?
+    }
+
+    /// Set a value for a given key, overriding a previously set value if it exists.
+    pub async fn set(&mut self, key: String, val: String) -> Result<()> {
+        let mut store = self.store.clone();
+        task::spawn_blocking(move || store.set(key, val)).await?
+    }
+
+    /// Remove a key and value from the store.
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        let mut store = self.store.clone();
+        task::spawn_blocking(move || store.remove(key)).await?
+    }
+
+    /// Returns the wrapped store, for operations without an async counterpart.
+    pub fn into_inner(self) -> KvStore {
+        self.store
+    }
+}
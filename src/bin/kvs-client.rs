@@ -0,0 +1,93 @@
+use clap::{App, AppSettings, Arg, SubCommand};
+use kvs::client::Client;
+use kvs::{KeyNotFoundError, Result};
+
+fn main() -> Result<()> {
+    let addr_arg = Arg::with_name("addr")
+        .long("addr")
+        .value_name("IP:PORT")
+        .default_value("127.0.0.1:4000")
+        .help("The address of the server.");
+    let matches = App::new("kvs-client")
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("Talks to a kvs-server.")
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Gets a value from the KV store.")
+                .arg(
+                    Arg::with_name("KEY")
+                        .required(true)
+                        .help("The key to fetch."),
+                )
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("set")
+                .about("Sets a value for a key in the KV store.")
+                .arg(Arg::with_name("KEY").required(true).help("The key to set."))
+                .arg(
+                    Arg::with_name("VAL")
+                        .required(true)
+                        .help("The value to set."),
+                )
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Remove a value from the KV store.")
+                .arg(
+                    Arg::with_name("KEY")
+                        .required(true)
+                        .help("The key to remove."),
+                )
+                .arg(addr_arg),
+        )
+        .get_matches();
+
+    if let Some(cmd) = matches.subcommand_matches("get") {
+        let mut client = Client::connect(cmd.value_of("addr").unwrap())?;
+        let key = cmd.value_of("KEY").unwrap().to_string();
+        match client.get(key) {
+            Ok(Some(val)) => {
+                println!("{}", val);
+            }
+            Ok(None) => {
+                println!("Key not found");
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        };
+    }
+
+    if let Some(cmd) = matches.subcommand_matches("set") {
+        let mut client = Client::connect(cmd.value_of("addr").unwrap())?;
+        let key = cmd.value_of("KEY").unwrap().to_string();
+        let val = cmd.value_of("VAL").unwrap().to_string();
+        client.set(key, val)?;
+    }
+
+    if let Some(cmd) = matches.subcommand_matches("rm") {
+        let mut client = Client::connect(cmd.value_of("addr").unwrap())?;
+        let key = cmd.value_of("KEY").unwrap().to_string();
+        match client.remove(key) {
+            Ok(_) => {}
+            Err(e) => {
+                match e.downcast::<KeyNotFoundError>() {
+                    Ok(_) => {
+                        println!("Key not found");
+                    }
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                    }
+                };
+                std::process::exit(1);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,24 @@
+use clap::{App, Arg};
+use kvs::server::KvsServer;
+use kvs::{KvStore, Result};
+
+fn main() -> Result<()> {
+    let matches = App::new("kvs-server")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("Serves the KV store in the current directory over TCP.")
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .value_name("IP:PORT")
+                .default_value("127.0.0.1:4000")
+                .help("The address to listen on."),
+        )
+        .get_matches();
+
+    let addr = matches.value_of("addr").unwrap();
+    let kv_store = KvStore::open(std::env::current_dir()?.as_path())?;
+
+    eprintln!("Listening on {}", addr);
+    KvsServer::new(kv_store).run(addr)
+}
@@ -1,5 +1,19 @@
 use clap::{App, AppSettings, Arg, SubCommand};
 use kvs::{KeyNotFoundError, KvStore, Result};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+/// The output of `get --format json`, the key and value are only present if the key was found.
+#[derive(Serialize)]
+struct GetOutput {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    found: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+}
 
 fn main() -> Result<()> {
     let matches = App::new(env!("CARGO_PKG_NAME"))
@@ -7,6 +21,14 @@ fn main() -> Result<()> {
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
+        .arg(
+            Arg::with_name("dir")
+                .short("d")
+                .long("dir")
+                .value_name("PATH")
+                .global(true)
+                .help("The directory of the KV store, the current directory if not given."),
+        )
         .subcommand(
             SubCommand::with_name("get")
                 .about("Gets a value from the KV store.")
@@ -14,6 +36,14 @@ fn main() -> Result<()> {
                     Arg::with_name("KEY")
                         .required(true)
                         .help("The key to fetch."),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["plain", "json"])
+                        .default_value("plain")
+                        .help("How to print the value."),
                 ),
         )
         .subcommand(
@@ -26,6 +56,21 @@ fn main() -> Result<()> {
                         .help("The value to set."),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("incr")
+                .about("Adds to the integer value of a key in the KV store.")
+                .setting(AppSettings::AllowNegativeNumbers)
+                .arg(
+                    Arg::with_name("KEY")
+                        .required(true)
+                        .help("The key to increment."),
+                )
+                .arg(
+                    Arg::with_name("DELTA")
+                        .required(true)
+                        .help("The amount to add, which may be negative."),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("rm")
                 .about("Remove a value from the KV store.")
@@ -36,16 +81,71 @@ fn main() -> Result<()> {
                 ),
         )
         .subcommand(
-            SubCommand::with_name("compact")
-                .about("Compacts the KV Store file."),
+            SubCommand::with_name("keys")
+                .about("Lists every key in the KV store, in sorted order.")
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .takes_value(true)
+                        .help("Only list the keys starting with this prefix."),
+                ),
+        )
+        .subcommand(SubCommand::with_name("compact").about("Compacts the KV Store file."))
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Checks every record in the KV store file, without changing it."),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Writes every key and value in the KV store to a file.")
+                .arg(
+                    Arg::with_name("FILE")
+                        .required(true)
+                        .help("The file to write."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Sets every key and value from a file written by export.")
+                .arg(
+                    Arg::with_name("FILE")
+                        .required(true)
+                        .help("The file to read."),
+                ),
         )
         .get_matches();
 
-    let mut kv_store = KvStore::open(std::env::current_dir()?.as_path())?;
+    let dir = match matches.value_of("dir") {
+        Some(dir) => PathBuf::from(dir),
+        None => std::env::current_dir()?,
+    };
+
+    if matches.subcommand_matches("verify").is_some() {
+        // The store isn't opened, so a damaged log is checked as it is rather than truncated.
+        let report = KvStore::verify_path(&dir)?;
+        println!("Good entries: {}", report.good_entries);
+        println!("Corrupt entries: {}", report.corrupt_entries);
+        println!("Orphaned entries: {}", report.orphaned_entries);
+        if !report.is_ok() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let mut kv_store = KvStore::open(&dir)?;
 
     if let Some(cmd) = matches.subcommand_matches("get") {
         let key = cmd.value_of("KEY").unwrap().to_string();
-        match kv_store.get(key) {
+        let json = cmd.value_of("format") == Some("json");
+        match kv_store.get(key.clone()) {
+            Ok(val) if json => {
+                let output = GetOutput {
+                    key: val.as_ref().map(|_| key),
+                    found: val.is_some(),
+                    value: val,
+                };
+                println!("{}", serde_json::to_string(&output)?);
+            }
             Ok(Some(val)) => {
                 println!("{}", val);
             }
@@ -65,6 +165,12 @@ fn main() -> Result<()> {
         kv_store.set(key, val)?;
     }
 
+    if let Some(cmd) = matches.subcommand_matches("incr") {
+        let key = cmd.value_of("KEY").unwrap().to_string();
+        let delta: i64 = cmd.value_of("DELTA").unwrap().parse()?;
+        println!("{}", kv_store.increment(key, delta)?);
+    }
+
     if let Some(cmd) = matches.subcommand_matches("rm") {
         let key = cmd.value_of("KEY").unwrap().to_string();
         match kv_store.remove(key) {
@@ -83,8 +189,49 @@ fn main() -> Result<()> {
         }
     }
 
-    if let Some(_) = matches.subcommand_matches("compact") {
-        kv_store.compact_log()?;
+    if let Some(cmd) = matches.subcommand_matches("keys") {
+        let mut keys = match cmd.value_of("prefix") {
+            Some(prefix) => kv_store
+                .scan_prefix(prefix.as_bytes())?
+                .into_iter()
+                .map(|(k, _)| String::from_utf8_lossy(&k).into_owned())
+                .collect(),
+            None => kv_store.keys()?,
+        };
+        keys.sort();
+        for key in keys {
+            println!("{}", key);
+        }
+    }
+
+    if matches.subcommand_matches("compact").is_some() {
+        let mut percent = None;
+        let compacted = kv_store.compact_log_with_progress(|done, total| {
+            let p = done * 100 / total;
+            if percent != Some(p) {
+                percent = Some(p);
+                eprint!("\rCompacting: {}%", p);
+            }
+        });
+        if percent.is_some() {
+            eprintln!();
+        }
+        if let Err(e) = compacted {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(cmd) = matches.subcommand_matches("export") {
+        let mut f = BufWriter::new(File::create(cmd.value_of("FILE").unwrap())?);
+        kv_store.export(&mut f)?;
+        f.flush()?;
+    }
+
+    if let Some(cmd) = matches.subcommand_matches("import") {
+        let mut f = File::open(cmd.value_of("FILE").unwrap())?;
+        let count = kv_store.import(&mut f)?;
+        eprintln!("Imported {} keys", count);
     }
 
     Ok(())
@@ -0,0 +1,73 @@
+//! A client for a `KvsServer`.
+
+use crate::protocol::{read_message, write_message, Request, Response};
+use crate::{KeyNotFoundError, Result};
+use failure::{Error, Fail};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+#[derive(Fail, Debug)]
+#[fail(display = "Server error: {}", message)]
+/// Error returned when the server fails a request, or answers it with the wrong kind of response.
+pub struct ServerError {
+    message: String,
+}
+
+/// A connection to a `KvsServer`, requests are answered by the server in the order they are sent.
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl Client {
+    /// Connects to the server at the given address.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Client> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Client {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    /// Get the value associated with the provided key, or None otherwise.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(&Request::Get { key })? {
+            Response::Value(val) => Ok(val),
+            r => Err(unexpected(r)),
+        }
+    }
+
+    /// Set a value for a given key, overriding a previously set value if it exists.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self.request(&Request::Set { key, value })? {
+            Response::Ok => Ok(()),
+            r => Err(unexpected(r)),
+        }
+    }
+
+    /// Remove a key and value from the store, returning a `KeyNotFoundError` if it is not set.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        match self.request(&Request::Remove { key: key.clone() })? {
+            Response::Ok => Ok(()),
+            Response::KeyNotFound => Err(Error::from(KeyNotFoundError { key })),
+            r => Err(unexpected(r)),
+        }
+    }
+
+    /// Sends the request and waits for its response.
+    fn request(&mut self, request: &Request) -> Result<Response> {
+        write_message(&mut self.writer, request)?;
+        self.writer.flush()?;
+        read_message(&mut self.reader)
+    }
+}
+
+/// Converts a response the request did not expect into an error, carrying the server's message
+/// if it is one.
+fn unexpected(response: Response) -> Error {
+    let message = match response {
+        Response::Err(message) => message,
+        r => format!("unexpected response {:?}", r),
+    };
+    Error::from(ServerError { message })
+}
@@ -0,0 +1,64 @@
+//! The time that entry expiry is measured against.
+//!
+//! A log reads the time from its `Clock`, a `SystemClock` unless another is set with
+//! `KvStore::set_clock`. Tests can set a `MockClock` and move it forward by hand rather than
+//! waiting for keys to expire.
+
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A source of the current time, in milliseconds since the unix epoch.
+pub trait Clock: Debug + Send + Sync {
+    /// Returns the current time in milliseconds since the unix epoch.
+    fn now_millis(&self) -> u64;
+}
+
+/// The clock of the system, the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64)
+    }
+}
+
+/// A clock that only moves when it is told to, starting at the time it was created.
+#[derive(Debug)]
+pub struct MockClock {
+    now: AtomicU64,
+}
+
+impl MockClock {
+    /// Creates a clock reading the current time of the system.
+    pub fn new() -> MockClock {
+        MockClock::at(SystemClock.now_millis())
+    }
+
+    /// Creates a clock reading `millis` since the unix epoch.
+    pub fn at(millis: u64) -> MockClock {
+        MockClock {
+            now: AtomicU64::new(millis),
+        }
+    }
+
+    /// Moves the clock forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        self.now.fetch_add(by.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now_millis(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
@@ -1,105 +1,579 @@
 #![deny(missing_docs)]
+// failure_derive generates its impls inside anonymous consts.
+#![allow(non_local_definitions)]
 
 //! A Key-Value store, using an on-disk serialized log for persistence.
 
 pub mod append_log;
+#[cfg(feature = "async")]
+pub mod async_store;
+pub mod client;
+pub mod clock;
+pub mod protocol;
+pub mod server;
+pub mod storage;
 
-use append_log::{AppendLog, LogCommand};
+#[cfg(feature = "encryption")]
+pub use append_log::DecryptionError;
+pub use append_log::LogCommand;
+use append_log::{AppendLog, BatchEntry};
+pub use append_log::{
+    Compression, Encoding, EntryTooLargeError, KeyPage, ReadOnlyError, UnorderedIndexError,
+    VerifyReport, DEFAULT_WRITE_BUFFER_SIZE,
+};
+pub use clock::{Clock, MockClock, SystemClock};
 use failure::{Error, Fail};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
 use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+#[cfg(feature = "tempdir")]
+use tempfile::TempDir;
 
 /// The result type used for KvStore.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// The senders notified of writes to each watched key.
+type Watchers = HashMap<Vec<u8>, Vec<Sender<Option<String>>>>;
+
+/// A write applied to a KvStore, as sent to the receivers from `KvStore::subscribe`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogEvent {
+    /// Whether the key was set or removed.
+    pub cmd: LogCommand,
+    /// The key written.
+    pub key: Vec<u8>,
+    /// The value set, None for a remove.
+    pub val: Option<Vec<u8>>,
+    /// When the value set expires, in milliseconds since the epoch by the store's clock, None if
+    /// it doesn't or for a remove.
+    pub expires_at: Option<u64>,
+}
+
+/// The senders sent every write, and the writes that are not on disk yet.
+#[derive(Default)]
+struct Subscribers {
+    senders: Vec<Sender<LogEvent>>,
+    /// Writes made since the log was last synced, in the order they were made.
+    unsynced: Vec<LogEvent>,
+}
+
+impl Subscribers {
+    /// Sends the unsynced writes, which the caller has just synced, dropping the senders that
+    /// hung up.
+    fn publish(&mut self) {
+        for event in self.unsynced.drain(..) {
+            self.senders
+                .retain(|sender| sender.send(event.clone()).is_ok());
+        }
+    }
+}
+
 #[derive(Fail, Debug)]
 #[fail(display = "Key not found: {}", key)]
-/// Error returned for get and remove when the key is not found.
+/// Error returned when the requested key is not in the store.
 pub struct KeyNotFoundError {
     key: String,
 }
 
 #[derive(Fail, Debug)]
-#[fail(display = "Internal Key-Value Error")]
-/// Error returned for get and remove when the key is not found.
-pub struct InternalKvError;
+#[fail(display = "Key is empty")]
+/// Error returned when getting, setting, removing or looking up the empty key.
+pub struct EmptyKeyError;
 
 #[derive(Fail, Debug)]
-#[fail(display = "Path is not a directory: {:?}", dir)]
-/// Error returned for get and remove when the key is not found.
-pub struct InvalidPathError {
-    dir: PathBuf,
+#[fail(display = "Path not found: {:?}", path)]
+/// Error returned when opening a store in a directory that does not exist, or a log file in one.
+pub struct PathNotFoundError {
+    path: PathBuf,
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "Path is not a directory: {:?}", path)]
+/// Error returned when the directory a store is opened in exists but is not a directory.
+pub struct NotADirectoryError {
+    path: PathBuf,
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "Index is inconsistent with the log for key: {}", key)]
+/// Error returned when the index holds a key but the log has no value for it.
+///
+/// The store is internally inconsistent, reopening it rebuilds the index from the log.
+pub struct IndexInconsistencyError {
+    key: String,
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "The store lock was poisoned by a thread that panicked while holding it")]
+/// Error returned when another thread panicked while it held the lock on the log.
+///
+/// The log may have been left part way through an update, reopening the store rebuilds it from
+/// the file.
+pub struct PoisonedLockError;
+
+#[derive(Fail, Debug)]
+#[fail(display = "Column family name contains a NUL byte: {:?}", cf)]
+/// Error returned when a column family name holds the separator put between it and the key.
+pub struct InvalidColumnFamilyError {
+    cf: String,
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "No store found at: {:?}", path)]
+/// Error returned when opening a store that does not exist with `create_if_missing` turned off.
+pub struct StoreNotFoundError {
+    path: PathBuf,
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "A store already exists at: {:?}", path)]
+/// Error returned by `KvStore::snapshot_to` when the directory already holds a store's log.
+pub struct StoreExistsError {
+    path: PathBuf,
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "Log file name does not end in a number: {}", name)]
+/// Error returned when compacting a store whose log file name has no number to advance to the
+/// name of the next log file.
+pub struct MalformedLogNameError {
+    name: String,
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "Value is not an integer for key: {}", key)]
+/// Error returned by `KvStore::increment` when the key's value does not parse as an `i64`.
+pub struct NotAnIntegerError {
+    key: String,
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "Increment overflows the value for key: {}", key)]
+/// Error returned by `KvStore::increment` when the new total does not fit in an `i64`.
+pub struct IntegerOverflowError {
+    key: String,
+}
+
+#[derive(Fail, Debug)]
+#[fail(
+    display = "Value of {} bytes is over the limit of {} bytes",
+    size, max_size
+)]
+/// Error returned when a value is larger than the limit set by `KvStore::set_max_value_size`.
+pub struct ValueTooLargeError {
+    size: usize,
+    max_size: usize,
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "Set event has no value for key: {}", key)]
+/// Error returned by `KvStore::apply_event` for a `LogCommand::Set` event without a value.
+pub struct MissingEventValueError {
+    key: String,
 }
 
 const KV_FILE_PREFIX: &str = "kv_store.log";
 
+/// Separates the column family name from the key in the keys stored for `set_cf` and friends.
+const CF_SEPARATOR: char = '\0';
+
+/// The default ratio of log entries to live keys at which the log is compacted.
+pub const DEFAULT_COMPACTION_RATIO: usize = 10;
+
+/// A single key and value in the dump written by `KvStore::export`.
+#[derive(Serialize, Deserialize)]
+struct DumpEntry {
+    key: String,
+    value: String,
+}
+
+/// Statistics about a KvStore, covering every clone of it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of live keys in the store.
+    pub live_entries: usize,
+    /// The number of entries in the log, including overwritten and removed ones.
+    pub total_entries: usize,
+    /// The number of values fetched since the store was opened.
+    pub reads: u64,
+    /// The number of entries written since the store was opened.
+    pub writes: u64,
+    /// The number of compactions since the store was opened.
+    pub compactions: u64,
+}
+
+impl Stats {
+    /// The fraction of the log's entries that are dead, i.e. overwritten or removed.
+    pub fn dead_ratio(&self) -> f64 {
+        if self.total_entries == 0 {
+            return 0.0;
+        }
+        1.0 - self.live_entries as f64 / self.total_entries as f64
+    }
+}
+
+/// What compacting a KvStore would do, as reported by `KvStore::compaction_estimate`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompactionEstimate {
+    /// The number of live keys, whose entries a compaction copies.
+    pub live_entries: usize,
+    /// The number of overwritten, removed and expired entries, which a compaction drops.
+    pub dead_entries: usize,
+    /// The size of the log in bytes.
+    pub current_bytes: u64,
+    /// The size of the log in bytes once compacted.
+    pub projected_bytes: u64,
+}
+
+/// When writes to a KvStore are synced to disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DurabilityMode {
+    /// Writes are buffered and synced when the buffer fills, on compaction and when the store is
+    /// dropped. Writes still in the buffer are lost on a crash or power loss.
+    #[default]
+    Buffered,
+    /// Every `set` and `remove` is synced to disk before it returns.
+    SyncEachWrite,
+}
+
 /// A persistant Sting based Key-Value store.
+///
+/// Clones of a store share its log. Each write holds the write lock on the log from start to
+/// finish, so a method that reads a key and then writes it, such as `compare_and_swap` or
+/// `update`, sees no write from another clone in between, and a method writing several keys,
+/// such as `rename_key` or `remove_prefix`, is seen by other clones all at once or not at all.
 pub struct KvStore {
-    /// Log representation of the on-disk file.
+    /// Log representation of the on-disk file, shared between clones.
+    ///
+    /// The log also tracks its own file path so that a compaction through one clone is seen by all.
     log: Arc<RwLock<AppendLog>>,
-    log_file: PathBuf,
+    /// The directory holding the log files.
+    dir: PathBuf,
+    /// The file name prefix of the log files, they are named `<prefix>.N`, or `<prefix>.N.seg`
+    /// for the segments of a segmented log.
+    prefix: String,
+    /// Set when the store was opened on a log file rather than a directory, the file then keeps
+    /// its name across compactions.
+    fixed_log_file: bool,
+    /// Compact once the log holds this many entries per live key.
+    compaction_ratio: usize,
+    /// Whether writes and drops compact the log once it reaches the compaction ratio.
+    auto_compact: bool,
+    /// When writes through this handle are synced to disk.
+    durability: DurabilityMode,
+    /// Values longer than this many bytes are rejected by writes through this handle.
+    max_value_size: Option<usize>,
+    /// Writes through this handle that grow the log file past this many bytes compact it to a
+    /// new file.
+    max_segment_bytes: Option<u64>,
+    /// The length of the log file when it was opened or last rewritten, shared between clones.
+    rewritten_len: Arc<AtomicU64>,
+    /// Whether sets through this handle skip writing a value that is already stored.
+    dedupe_writes: bool,
+    /// The senders for each watched key, shared between clones.
+    watchers: Arc<Mutex<Watchers>>,
+    /// The senders for every write, shared between clones.
+    subscribers: Arc<Mutex<Subscribers>>,
+    /// The temporary directory backing a `KvStore::default()`, removed once the last clone is dropped.
+    #[cfg(feature = "tempdir")]
+    temp_dir: Option<Arc<TempDir>>,
 }
 
 impl KvStore {
-    /// Finds all files in the dir that have the prefix of KV_FILE_PREFIX, and returns the path to the one with the largest suffix.
-    fn locate_kv_file(dir: &Path) -> Result<Option<PathBuf>> {
-        let mut candidates = Vec::new();
+    /// Finds all files in the dir named `<prefix>.N`, and returns the path to the one with the largest suffix.
+    fn locate_kv_file(dir: &Path, prefix: &str) -> Result<Option<PathBuf>> {
+        let files = KvStore::kv_files(dir, prefix)?;
+        Ok(files
+            .into_iter()
+            .max_by_key(|(idx, _)| *idx)
+            .map(|(_, p)| p))
+    }
+
+    /// Finds all files in the dir named `<prefix>.N`, returning each with its suffix N.
+    ///
+    /// Files that start with the prefix but aren't followed by just a number, such as saved
+    /// indexes or leftover compactions, are skipped.
+    fn kv_files(dir: &Path, prefix: &str) -> Result<Vec<(u64, PathBuf)>> {
+        let mut files = Vec::new();
         for dent in dir.read_dir()? {
             let p = dent?.path();
-            if let Some(s) = p.file_name() {
-                if let Some(s) = s.to_str() {
-                    if s.starts_with(KV_FILE_PREFIX) {
-                        candidates.push(p);
-                    }
-                }
-            };
+            let idx = p
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| KvStore::log_file_index(name, prefix));
+            if let Some(idx) = idx {
+                files.push((idx, p));
+            }
         }
 
-        let mut p = None;
-        let mut max = 0;
+        Ok(files)
+    }
 
-        for c in candidates {
-            let c_name = c.to_string_lossy();
-            let s: Vec<&str> = c_name.rsplit('.').collect();
-            if s.len() > 1 {
-                if let Ok(idx) = s[0].parse() {
-                    if idx > max {
-                        max = idx;
-                        let mut pb = dir.to_path_buf();
-                        pb.push(c);
-                        p = Some(pb);
-                    }
-                }
-            }
+    /// Returns N for a log file named `<prefix>.N`, or None if the name is anything else.
+    ///
+    /// Only the file name is looked at, never the directories above it, which may have dots of
+    /// their own.
+    fn log_file_index(name: &str, prefix: &str) -> Option<u64> {
+        let suffix = name.strip_prefix(prefix)?.strip_prefix('.')?;
+        if suffix.is_empty() || !suffix.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
         }
+        suffix.parse().ok()
+    }
+
+    /// Removes a log file along with its saved index, if it has one.
+    fn remove_log_file(path: &Path) -> Result<()> {
+        fs::remove_file(path)?;
+        match fs::remove_file(AppendLog::index_path(path)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Error::from(e)),
+            _ => Ok(()),
+        }
+    }
 
-        Ok(p)
+    /// Removes the log files with the prefix that are older than the one in use.
+    ///
+    /// A compaction that died after writing the new log but before removing the old one leaves
+    /// the old one behind, only the newest log is ever opened so the rest are dead weight.
+    fn remove_stale_kv_files(dir: &Path, prefix: &str, log_file: &Path) -> Result<()> {
+        let files = KvStore::kv_files(dir, prefix)?;
+        let current = match files.iter().find(|(_, p)| p == log_file) {
+            Some((idx, _)) => *idx,
+            None => return Ok(()),
+        };
+        for (idx, p) in files {
+            if idx < current {
+                info!("Removing stale log file: {:?}", p);
+                KvStore::remove_log_file(&p)?;
+            }
+        }
+        Ok(())
     }
 
     /// Open a KvStore for a given path. If the path is a directory then a file will be created in this directory.
     /// If the path does not exist then a file will be created and initialized at that location.
+    ///
+    /// Opening a file, rather than a directory, lets several stores share a directory.
+    ///
+    /// Returns a `PathNotFoundError` if the directory the file would be created in does not
+    /// exist, and a `NotADirectoryError` if it, or the path itself, is neither a file nor a
+    /// directory.
     pub fn open(path: &Path) -> Result<KvStore> {
-        // TODO - this should just take a directory and we will create multiple files in there for the log.
-        if !path.exists() || !path.is_dir() {
-            return Err(Error::from(InvalidPathError {
-                dir: path.to_owned(),
-            }));
+        if path.is_dir() {
+            return KvStore::open_dir(path, KV_FILE_PREFIX);
+        }
+
+        let dir = KvStore::file_dir(path);
+        if !path.is_file() {
+            // Something other than a file or a directory.
+            if path.exists() {
+                return Err(Error::from(NotADirectoryError {
+                    path: path.to_owned(),
+                }));
+            }
+            KvStore::check_dir(dir)?;
+        }
+
+        KvStore::open_log_file(dir, path.to_path_buf(), KV_FILE_PREFIX, true)
+    }
+
+    /// Creates an empty KvStore kept in memory, with nothing written to disk.
+    ///
+    /// It behaves as a store opened with `open`, compactions included, except that everything
+    /// in it is lost once the last handle on it is dropped. `path` is empty and
+    /// `log_file_path` only names the log.
+    pub fn in_memory() -> Result<KvStore> {
+        let log_file = PathBuf::from(format!("{}.0", KV_FILE_PREFIX));
+        let log = AppendLog::in_memory(&log_file)?;
+        Ok(KvStore::with_log(Path::new(""), log, KV_FILE_PREFIX, false))
+    }
+
+    /// Returns a builder for opening a store with settings other than the defaults of `open`.
+    pub fn builder() -> KvStoreBuilder {
+        KvStoreBuilder::new()
+    }
+
+    /// Open a KvStore for a given path that compacts once the log holds `ratio` entries per live key.
+    pub fn open_with_compaction_ratio(path: &Path, ratio: usize) -> Result<KvStore> {
+        let mut store = KvStore::open(path)?;
+        store.set_compaction_ratio(ratio);
+        Ok(store)
+    }
+
+    /// Open a KvStore for a given path that compresses the values it writes.
+    pub fn open_with_compression(path: &Path, compression: Compression) -> Result<KvStore> {
+        let mut store = KvStore::open(path)?;
+        store.set_compression(compression);
+        Ok(store)
+    }
+
+    /// Open a KvStore for a given path that keeps up to `capacity` recently read values in memory.
+    pub fn open_with_cache_capacity(path: &Path, capacity: usize) -> Result<KvStore> {
+        let mut store = KvStore::open(path)?;
+        store.set_cache_capacity(capacity)?;
+        Ok(store)
+    }
+
+    /// Open a KvStore for a given path that encrypts the values it writes with the key, and
+    /// decrypts the values it reads.
+    ///
+    /// Keys are stored unencrypted. Reading a value written without this key, including one
+    /// written before the store was encrypted, fails with a `DecryptionError`.
+    #[cfg(feature = "encryption")]
+    pub fn open_encrypted(path: &Path, key: [u8; 32]) -> Result<KvStore> {
+        let store = KvStore::open(path)?;
+        store.write_log()?.set_encryption_key(&key);
+        Ok(store)
+    }
+
+    /// Open a KvStore in a directory, naming its log files `<prefix>.N`.
+    ///
+    /// Stores with different prefixes can share a directory, and are compacted independently.
+    pub fn open_with_prefix(dir: &Path, prefix: &str) -> Result<KvStore> {
+        KvStore::check_dir(dir)?;
+        KvStore::open_dir(dir, prefix)
+    }
+
+    /// Open a KvStore in a directory with its log split into segment files of about
+    /// `segment_bytes` bytes each, named `kv_store.log.<seq>.seg`.
+    ///
+    /// Compacting a segmented store rewrites only the segments holding more than the compaction
+    /// ratio of entries per entry still needed, so a compaction never copies more than a segment
+    /// at a time. A directory holds either a segmented log or the single log file of `open`, the
+    /// store should be opened the same way each time.
+    pub fn open_with_segment_size(dir: &Path, segment_bytes: u64) -> Result<KvStore> {
+        KvStore::check_dir(dir)?;
+
+        let log = AppendLog::open_segmented(dir, KV_FILE_PREFIX, segment_bytes)?;
+        Ok(KvStore::with_log(dir, log, KV_FILE_PREFIX, false))
+    }
+
+    /// Opens a store for reading only, from a directory or a log file as with `open`.
+    ///
+    /// The log file is not locked, so this works while another process has the store open, but
+    /// only sees the keys written before it was opened. Nothing is created or removed on disk, and
+    /// `set`, `remove`, `compact_log` and the other writes return a `ReadOnlyError`.
+    pub fn open_read_only(path: &Path) -> Result<KvStore> {
+        let (dir, log_file, fixed_log_file) = KvStore::read_only_log_file(path)?;
+
+        info!("Using KV Log File read-only: {:?}", log_file);
+        let log = AppendLog::open_read_only(&log_file)?;
+        Ok(KvStore::with_log(dir, log, KV_FILE_PREFIX, fixed_log_file))
+    }
+
+    /// Finds the log file of the store at the path, a directory or a log file as with `open`,
+    /// without creating one. Returns the store's directory, the log file, and whether the path
+    /// named the log file itself.
+    fn read_only_log_file(path: &Path) -> Result<(&Path, PathBuf, bool)> {
+        if path.is_dir() {
+            match KvStore::locate_kv_file(path, KV_FILE_PREFIX)? {
+                Some(f) => Ok((path, f, false)),
+                None => Err(Error::from(StoreNotFoundError {
+                    path: path.to_owned(),
+                })),
+            }
+        } else if path.is_file() {
+            Ok((KvStore::file_dir(path), path.to_path_buf(), true))
+        } else if path.exists() {
+            Err(Error::from(NotADirectoryError {
+                path: path.to_owned(),
+            }))
+        } else {
+            Err(Error::from(PathNotFoundError {
+                path: path.to_owned(),
+            }))
         }
+    }
+
+    /// Opens a store from a directory or a log file as with `open`, skipping over entries in the
+    /// log that can't be read rather than failing, e.g. after the file was damaged on disk.
+    ///
+    /// Returns the store along with the number of unreadable runs of entries skipped, each of
+    /// which may have held several entries. When anything was skipped the log is compacted
+    /// straight away, so the store can be opened with `open` from then on.
+    pub fn open_with_recovery(path: &Path) -> Result<(KvStore, usize)> {
+        let (dir, log_file, fixed_log_file) = if path.is_dir() {
+            match KvStore::locate_kv_file(path, KV_FILE_PREFIX)? {
+                Some(f) => (path, f, false),
+                // There is no log to recover, open creates a new one.
+                None => return Ok((KvStore::open(path)?, 0)),
+            }
+        } else if path.is_file() {
+            (KvStore::file_dir(path), path.to_path_buf(), true)
+        } else {
+            return Ok((KvStore::open(path)?, 0));
+        };
+
+        info!("Recovering KV Log File: {:?}", log_file);
+        let (log, dropped) = AppendLog::open_with_recovery(&log_file)?;
+        let mut store = KvStore::with_log(dir, log, KV_FILE_PREFIX, fixed_log_file);
+        if !fixed_log_file {
+            KvStore::remove_stale_kv_files(dir, KV_FILE_PREFIX, &log_file)?;
+        }
+        if dropped > 0 {
+            store.compact_log()?;
+        }
+        Ok((store, dropped))
+    }
 
-        let log_file = match KvStore::locate_kv_file(&path)? {
+    /// Returns a `PathNotFoundError` or a `NotADirectoryError` if the path is not a directory.
+    fn check_dir(dir: &Path) -> Result<()> {
+        if dir.is_dir() {
+            Ok(())
+        } else if dir.exists() {
+            Err(Error::from(NotADirectoryError {
+                path: dir.to_owned(),
+            }))
+        } else {
+            Err(Error::from(PathNotFoundError {
+                path: dir.to_owned(),
+            }))
+        }
+    }
+
+    /// Returns the directory holding a log file, a relative file name without a directory lives
+    /// in the current directory.
+    fn file_dir(path: &Path) -> &Path {
+        match path.parent() {
+            Some(p) if p.as_os_str().is_empty() => Path::new("."),
+            Some(p) => p,
+            None => Path::new(""),
+        }
+    }
+
+    /// Opens the store in a directory, using the newest log file with the prefix in it.
+    fn open_dir(path: &Path, prefix: &str) -> Result<KvStore> {
+        let log_file = match KvStore::locate_kv_file(path, prefix)? {
             Some(f) => f,
             None => {
                 let mut pb = path.to_owned();
-                let mut filename = String::from(KV_FILE_PREFIX);
+                let mut filename = String::from(prefix);
                 filename.push_str(".0");
                 pb.push(filename);
-                eprintln!("No files found, starting new one: {:?}", pb);
+                info!("No files found, starting new one: {:?}", pb);
                 pb
             }
         };
 
-        eprintln!("Using KV Log File: {:?}", log_file);
+        let store = KvStore::open_log_file(path, log_file, prefix, false)?;
+        KvStore::remove_stale_kv_files(path, prefix, &store.log_file_path())?;
+        Ok(store)
+    }
+
+    /// Opens the store on the given log file in `dir`, creating the file if it does not exist.
+    fn open_log_file(
+        dir: &Path,
+        log_file: PathBuf,
+        prefix: &str,
+        fixed_log_file: bool,
+    ) -> Result<KvStore> {
+        info!("Using KV Log File: {:?}", log_file);
         if !log_file.exists() {
             OpenOptions::new()
                 .create(true)
@@ -108,93 +582,1824 @@ impl KvStore {
         }
 
         let log = AppendLog::load(&log_file)?;
+        Ok(KvStore::with_log(dir, log, prefix, fixed_log_file))
+    }
 
-        let store = KvStore {
+    /// Creates a store around an opened log, with the default settings.
+    fn with_log(dir: &Path, log: AppendLog, prefix: &str, fixed_log_file: bool) -> KvStore {
+        let rewritten_len = Arc::new(AtomicU64::new(log.byte_len()));
+        KvStore {
             log: Arc::new(RwLock::new(log)),
-            log_file,
-        };
-        // store.compact_log()?;
-        Ok(store)
+            dir: dir.to_path_buf(),
+            prefix: prefix.to_owned(),
+            fixed_log_file,
+            compaction_ratio: DEFAULT_COMPACTION_RATIO,
+            auto_compact: true,
+            durability: DurabilityMode::default(),
+            max_value_size: None,
+            max_segment_bytes: None,
+            rewritten_len,
+            dedupe_writes: false,
+            watchers: Arc::new(Mutex::new(HashMap::new())),
+            subscribers: Arc::new(Mutex::new(Subscribers::default())),
+            #[cfg(feature = "tempdir")]
+            temp_dir: None,
+        }
     }
 
-    /// Get the value associated with the provided key, or None otherwise.
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        match self.log.read().unwrap().fetch_by_key(key.as_bytes())? {
-            Some(bytes) => Ok(Some(String::from_utf8(bytes.to_vec())?)),
-            None => Ok(None),
-        }
+    /// Takes the read lock on the log, failing rather than panicking if it is poisoned.
+    fn read_log(&self) -> Result<RwLockReadGuard<'_, AppendLog>> {
+        self.log
+            .read()
+            .map_err(|_| Error::from(PoisonedLockError {}))
     }
 
-    /// Set a value for a given key, overriding a previously set value if it exists.
-    pub fn set(&mut self, key: String, val: String) -> Result<()> {
+    /// Takes the write lock on the log, failing rather than panicking if it is poisoned.
+    fn write_log(&self) -> Result<RwLockWriteGuard<'_, AppendLog>> {
         self.log
             .write()
-            .unwrap()
-            .append(LogCommand::Set, key.as_bytes(), Some(val.as_bytes()))?;
-        self.try_compact()
+            .map_err(|_| Error::from(PoisonedLockError {}))
     }
 
-    /// Remove a key and value from the store.
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        let k = key.as_bytes();
+    /// Sets the ratio of log entries to live keys at which writes compact the log.
+    pub fn set_compaction_ratio(&mut self, ratio: usize) {
+        self.compaction_ratio = ratio;
+    }
 
-        {
-            let mut l = self.log.write().unwrap();
+    /// Enables or disables automatic compaction, when disabled the log is only compacted by
+    /// calling `compact_log`.
+    pub fn set_auto_compact(&mut self, auto_compact: bool) {
+        self.auto_compact = auto_compact;
+    }
+
+    /// Sets the compression of values written from now on, for every clone of the store.
+    ///
+    /// Values already in the log keep their compression until they are rewritten by a compaction.
+    pub fn set_compression(&mut self, compression: Compression) {
+        // Setting the compression is a single assignment, the log is usable even if poisoned.
+        self.log
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .set_compression(compression);
+    }
+
+    /// Sets the encoding of values written from now on, for every clone of the store.
+    ///
+    /// Values already in the log keep their encoding until they are rewritten by a compaction.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        // Setting the encoding is a single assignment, the log is usable even if poisoned.
+        self.log
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .set_encoding(encoding);
+    }
+
+    /// Sets the number of recently read values kept in memory for every clone of the store, zero
+    /// disables the cache. Caching is disabled by default.
+    pub fn set_cache_capacity(&mut self, capacity: usize) -> Result<()> {
+        self.write_log()?.set_cache_capacity(capacity);
+        Ok(())
+    }
+
+    /// Sets the number of bytes of writes held in memory before they are written to the log file,
+    /// for every clone of the store. Unless writes are synced as they are made, this turns many
+    /// small writes into a few large ones.
+    pub fn set_write_buffer_size(&mut self, size: usize) -> Result<()> {
+        self.write_log()?.set_write_buffer_size(size);
+        Ok(())
+    }
+
+    /// Sets the clock TTLs are measured against for every clone of the store, e.g. a `MockClock`
+    /// for tests that expire keys without waiting. The system clock is used by default.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) -> Result<()> {
+        self.write_log()?.set_clock(clock);
+        Ok(())
+    }
+
+    /// Sets whether the index keeps its keys in order, for every clone of the store.
+    ///
+    /// An ordered index makes `scan_range` visit only the keys in the range, but looking up a
+    /// key is slower than with the default hashed index.
+    pub fn set_ordered_index(&mut self, ordered: bool) -> Result<()> {
+        self.write_log()?.set_ordered_index(ordered);
+        Ok(())
+    }
+
+    /// Writes out any buffered writes and syncs them to disk, through any clone of the store.
+    ///
+    /// With `DurabilityMode::Buffered` this lets a batch of writes be made durable at once,
+    /// rather than on each write or only when the store is dropped.
+    pub fn flush(&mut self) -> Result<()> {
+        let mut l = self.write_log()?;
+        l.flush()?;
+        self.publish_synced();
+        Ok(())
+    }
+
+    /// Sets when writes through this handle are synced to disk, trading throughput for safety.
+    pub fn set_durability_mode(&mut self, mode: DurabilityMode) {
+        self.durability = mode;
+    }
+
+    /// Sets the largest value in bytes that writes through this handle accept, `None` removes
+    /// the limit. Larger values are rejected with a `ValueTooLargeError` before anything is
+    /// written.
+    pub fn set_max_value_size(&mut self, max_size: Option<usize>) {
+        self.max_value_size = max_size;
+    }
+
+    /// Sets the largest the log file may grow to in bytes from writes through this handle, `None`
+    /// removes the limit.
+    ///
+    /// A write that takes the log file past the limit then compacts it into a new file, whatever
+    /// the compaction ratio and even with automatic compaction disabled, once the file has also
+    /// grown by half the limit since it was last compacted. A file whose live entries fit in half
+    /// the limit so never grows past it, and one holding more live entries than that is moved to
+    /// a new file each time it grows by half the limit, rather than on every write. A segmented
+    /// log already keeps each of its segments under its own segment size, and is not affected.
+    pub fn set_max_segment_bytes(&mut self, max_bytes: Option<u64>) {
+        self.max_segment_bytes = max_bytes;
+    }
+
+    /// Sets whether `set`, `set_bytes`, `set_and_return`, `set_many` and committed transactions
+    /// through this handle check the stored value first, and skip writing the new one if it is
+    /// the same.
+    ///
+    /// This keeps repeated sets from growing the log, at the cost of a read for every set. A set
+    /// in a batch is compared with the value the key holds after the writes before it.
+    pub fn set_dedupe_writes(&mut self, dedupe_writes: bool) {
+        self.dedupe_writes = dedupe_writes;
+    }
+
+    /// Fails with a `ValueTooLargeError` if the value is over the limit of this handle.
+    fn check_value_size(&self, val: &[u8]) -> Result<()> {
+        match self.max_value_size {
+            Some(max_size) if val.len() > max_size => Err(Error::from(ValueTooLargeError {
+                size: val.len(),
+                max_size,
+            })),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails with an `EmptyKeyError` if the key is empty.
+    fn check_key(key: &[u8]) -> Result<()> {
+        if key.is_empty() {
+            return Err(Error::from(EmptyKeyError));
+        }
+        Ok(())
+    }
+
+    /// Returns a receiver that is sent the new value of `key` every time it is set, or None
+    /// every time it is removed, through any clone of the store.
+    ///
+    /// Values are sent in the order they are written. A dropped receiver is forgotten the next
+    /// time the key is written. Values that are not valid UTF-8 are sent lossily converted.
+    pub fn watch(&self, key: String) -> Receiver<Option<String>> {
+        let (sender, receiver) = mpsc::channel();
+        self.watchers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(key.into_bytes())
+            .or_default()
+            .push(sender);
+        receiver
+    }
+
+    /// Returns a receiver that is sent every write made through any clone of the store, in the
+    /// order they are applied, e.g. to replay them into a replica.
+    ///
+    /// A write is only sent once it is synced to disk: straight away with
+    /// `DurabilityMode::SyncEachWrite`, otherwise on the next `flush` or `close`, or when the last
+    /// clone of the store is dropped. Each call returns a receiver of its own, a dropped receiver
+    /// is forgotten the next time writes are sent.
+    pub fn subscribe(&self) -> Receiver<LogEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .senders
+            .push(sender);
+        receiver
+    }
+
+    /// Finishes a write of the key appended under the write lock `l`, as `after_batch` does.
+    fn after_write(&self, l: &mut AppendLog, key: &[u8], val: Option<&[u8]>) -> Result<()> {
+        let cmd = match val {
+            Some(_) => LogCommand::Set,
+            None => LogCommand::Remove,
+        };
+        self.after_batch(l, &[(cmd, key, val)])
+    }
 
-            if !l.contains(k) {
-                return Err(Error::from(KeyNotFoundError { key }));
+    /// Finishes the writes appended under the write lock `l`: syncs them if the durability mode
+    /// asks for it, then notifies the watchers and subscribers of each in order.
+    ///
+    /// Every write goes through this while the lock is still held, and then through
+    /// `finish_write` once it is released.
+    fn after_batch(&self, l: &mut AppendLog, writes: &[BatchEntry]) -> Result<()> {
+        if !writes.is_empty() && self.durability == DurabilityMode::SyncEachWrite {
+            l.flush()?;
+        }
+        for (_, key, val) in writes {
+            self.notify(key, *val, None);
+        }
+        Ok(())
+    }
+
+    /// Finishes a set of the key that expires, appended under the write lock `l`, as
+    /// `after_batch` does for writes that don't.
+    fn after_expiring_set(
+        &self,
+        l: &mut AppendLog,
+        key: &[u8],
+        val: &[u8],
+        expires_at: u64,
+    ) -> Result<()> {
+        if self.durability == DurabilityMode::SyncEachWrite {
+            l.flush()?;
+        }
+        self.notify(key, Some(val), Some(expires_at));
+        Ok(())
+    }
+
+    /// Drops the sets in the batch that write the value the key already holds, when
+    /// `dedupe_writes` is on, as `set` does for a single set.
+    ///
+    /// A key written earlier in the batch is compared with the value written there.
+    fn dedupe_batch<'a>(
+        &self,
+        l: &AppendLog,
+        batch: Vec<BatchEntry<'a>>,
+    ) -> Result<Vec<BatchEntry<'a>>> {
+        if !self.dedupe_writes {
+            return Ok(batch);
+        }
+        let mut written: HashMap<&[u8], Option<&[u8]>> = HashMap::new();
+        let mut deduped = Vec::with_capacity(batch.len());
+        for (cmd, key, val) in batch {
+            let same = match (&cmd, val) {
+                (LogCommand::Set, Some(val)) => match written.get(key) {
+                    Some(current) => *current == Some(val),
+                    None => l.fetch_by_key(key)?.as_deref() == Some(val),
+                },
+                _ => false,
+            };
+            written.insert(key, val);
+            if !same {
+                deduped.push((cmd, key, val));
             }
+        }
+        Ok(deduped)
+    }
 
-            l.append(LogCommand::Remove, k, None)?;
+    /// Counts the sets and removes of a write whose lock has been released, and compacts the log
+    /// if it is due.
+    fn finish_write(&mut self, sets: usize, removes: usize) -> Result<()> {
+        #[cfg(feature = "metrics")]
+        {
+            metrics::counter!("kvs.set.count").increment(sets as u64);
+            metrics::counter!("kvs.remove.count").increment(removes as u64);
         }
+        #[cfg(not(feature = "metrics"))]
+        let _ = (sets, removes);
+
         self.try_compact()
     }
 
-    fn try_compact(&mut self) -> Result<()> {
-        // Compact when the log is more than 10x the index entries.
-        {
-            let l = self.log.read().unwrap();
-            if l.len() < 10 * l.index_len() {
-                return Ok(());
+    /// Sends the written value of the key to its watchers, dropping those that hung up, and
+    /// queues the write for the subscribers.
+    ///
+    /// Called with the write lock on the log still held, so watchers see writes in log order.
+    fn notify(&self, key: &[u8], val: Option<&[u8]>, expires_at: Option<u64>) {
+        // Each update of the map is complete, it is usable even if poisoned.
+        let mut watchers = self.watchers.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(senders) = watchers.get_mut(key) {
+            let val = val.map(|v| String::from_utf8_lossy(v).into_owned());
+            senders.retain(|sender| sender.send(val.clone()).is_ok());
+            if senders.is_empty() {
+                watchers.remove(key);
             }
         }
-        self.compact_log()
+        drop(watchers);
+
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if subscribers.senders.is_empty() {
+            return;
+        }
+        subscribers.unsynced.push(LogEvent {
+            cmd: match val {
+                Some(_) => LogCommand::Set,
+                None => LogCommand::Remove,
+            },
+            key: key.to_vec(),
+            val: val.map(<[u8]>::to_vec),
+            expires_at,
+        });
+        if self.durability == DurabilityMode::SyncEachWrite {
+            // The write was synced before the watchers were notified.
+            subscribers.publish();
+        }
     }
 
-    /// Compacts the log to a new file.
-    pub fn compact_log(&mut self) -> Result<()> {
-        let name = self.log_file.file_name().unwrap().to_string_lossy();
-        let s: Vec<&str> = name.rsplit('.').collect();
-        let mut idx: u64 = s[0].parse()?;
-        idx += 1;
-        let i = idx.to_string();
-        let mut new_name = String::from(KV_FILE_PREFIX);
-        new_name.push_str(".");
-        new_name.push_str(i.as_str());
-        eprintln!("New Log Name: {}", new_name);
-
-        let mut new_log = PathBuf::from(&self.log_file);
-        new_log.set_file_name(new_name);
-        self.log.write().unwrap().compact(&new_log)?;
-
-        fs::remove_file(self.log_file.to_owned())?;
-        self.log_file = new_log;
+    /// Sends the subscribers the writes made before the log was last synced.
+    fn publish_synced(&self) {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .publish();
+    }
 
-        Ok(())
+    /// Returns the directory the store keeps its log files in.
+    pub fn path(&self) -> &Path {
+        &self.dir
     }
-}
 
-impl Clone for KvStore {
-    fn clone(&self) -> Self {
-        KvStore {
-            log: self.log.clone(),
-            log_file: self.log_file.clone(),
+    /// Returns the path of the log file currently in use, this changes on every compaction.
+    pub fn log_file_path(&self) -> PathBuf {
+        // The path is only changed once a rename succeeds, so it is valid even if poisoned.
+        self.log
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .path()
+    }
+
+    /// Get the value associated with the provided key, or None otherwise.
+    ///
+    /// Returns an error if the stored value is not valid UTF-8, use `get_bytes` for binary values.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(bytes) => Ok(Some(String::from_utf8(bytes)?)),
+            None => Ok(None),
         }
     }
-}
 
-impl Drop for KvStore {
-    fn drop(&mut self) {
-        self.try_compact().unwrap();
+    /// Get the value associated with the provided key, or a `KeyNotFoundError` if it is not set.
+    ///
+    /// This mirrors `remove`, for callers that treat a missing key as an error.
+    pub fn get_strict(&self, key: String) -> Result<String> {
+        match self.get(key.clone())? {
+            Some(val) => Ok(val),
+            None => Err(Error::from(KeyNotFoundError { key })),
+        }
+    }
+
+    /// Get the value associated with the provided key, or `default` if it is not set.
+    pub fn get_or(&self, key: String, default: String) -> Result<String> {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+
+    /// Get the value associated with the provided key, or the result of `f` if it is not set.
+    ///
+    /// `f` is only called when the key is not set.
+    pub fn get_or_else<F: FnOnce() -> String>(&self, key: String, f: F) -> Result<String> {
+        Ok(self.get(key)?.unwrap_or_else(f))
+    }
+
+    /// Get the raw bytes of the value associated with the provided key, or None otherwise.
+    pub fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        KvStore::check_key(&key)?;
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+
+        let val = {
+            let l = self.read_log()?;
+            match l.fetch_by_key(&key)? {
+                None if l.contains(&key)? => {
+                    return Err(Error::from(IndexInconsistencyError {
+                        key: String::from_utf8_lossy(&key).into_owned(),
+                    }));
+                }
+                val => val,
+            }
+        };
+
+        #[cfg(feature = "metrics")]
+        metrics::histogram!("kvs.get.latency_ns").record(start.elapsed().as_nanos() as f64);
+
+        Ok(val.map(Vec::from))
+    }
+
+    /// Get a reader over the value associated with the provided key, or None otherwise.
+    ///
+    /// Values written without compression or encryption are read out of the log as the reader
+    /// is read from, so a large value never has to be held in memory whole. Corruption of such a
+    /// value is only detected once all of it has been read, and is returned as an
+    /// `InvalidData` error from the final read.
+    pub fn get_reader(&self, key: String) -> Result<Option<impl Read>> {
+        let key = key.into_bytes();
+        KvStore::check_key(&key)?;
+        self.read_log()?.value_reader(&key)
+    }
+
+    /// Get the values of each of the keys, with None for those that are not set.
+    ///
+    /// The values are fetched under a single read lock and returned in the order of the keys.
+    /// Returns an error if any of the values is not valid UTF-8.
+    pub fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        for key in &keys {
+            KvStore::check_key(key.as_bytes())?;
+        }
+        let vals = {
+            let l = self.read_log()?;
+            let keys: Vec<&[u8]> = keys.iter().map(|k| k.as_bytes()).collect();
+            let vals = l.fetch_many(&keys)?;
+            for (key, val) in keys.iter().zip(&vals) {
+                if val.is_none() && l.contains(key)? {
+                    return Err(Error::from(IndexInconsistencyError {
+                        key: String::from_utf8_lossy(key).into_owned(),
+                    }));
+                }
+            }
+            vals
+        };
+
+        vals.into_iter()
+            .map(|val| match val {
+                Some(bytes) => Ok(Some(String::from_utf8(Vec::from(bytes))?)),
+                None => Ok(None),
+            })
+            .collect()
+    }
+
+    /// Returns the number of live keys in the store.
+    pub fn count(&self) -> Result<usize> {
+        self.read_log()?.live_len()
+    }
+
+    /// Returns true if there are no live keys in the store.
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.count()? == 0)
+    }
+
+    /// Returns statistics about the store and the operations on it.
+    pub fn stats(&self) -> Result<Stats> {
+        let l = self.read_log()?;
+        let counters = l.counters();
+        Ok(Stats {
+            live_entries: l.live_len()?,
+            total_entries: l.len()?,
+            reads: counters.reads,
+            writes: counters.writes,
+            compactions: counters.compactions,
+        })
+    }
+
+    /// Returns the size of the current log file in bytes, counting writes that are still buffered.
+    ///
+    /// This grows with every write, including overwrites and removes, until the log is compacted.
+    pub fn disk_usage(&self) -> Result<u64> {
+        Ok(self.read_log()?.byte_len())
+    }
+
+    /// Returns the number of removes recorded in the log, which stay in it until it is compacted.
+    ///
+    /// Together with overwritten values, these make up the gap between the entries in the log
+    /// and the live keys reported by `stats`.
+    pub fn tombstone_count(&self) -> Result<usize> {
+        self.read_log()?.tombstone_count()
+    }
+
+    /// Checks every record in the log for a valid length and checksum, and that every key points
+    /// at one, without changing anything.
+    ///
+    /// Damage found this way can be skipped over by `open_with_recovery`.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        self.read_log()?.verify()
+    }
+
+    /// Checks every record in the store at the path, a directory or a log file as with `open`, as
+    /// `verify` does, without opening the store.
+    ///
+    /// Nothing is locked, created or truncated, and a log too damaged to open is still checked.
+    /// No index is built, so no orphaned entries are reported.
+    pub fn verify_path(path: &Path) -> Result<VerifyReport> {
+        let (_, log_file, _) = KvStore::read_only_log_file(path)?;
+        AppendLog::verify_file(&log_file)
+    }
+
+    /// Returns the offset of the key's current record in its log file, or None if the key is not
+    /// set, for tools that inspect the log file directly.
+    ///
+    /// The offset is only meaningful until the next compaction, which may happen on any write.
+    pub fn debug_offset_of(&self, key: String) -> Result<Option<u64>> {
+        KvStore::check_key(key.as_bytes())?;
+        self.read_log()?.offset_of(key.as_bytes())
+    }
+
+    /// Returns the number of bytes of the log taken up by overwritten, removed and expired values,
+    /// which compacting the log would free.
+    pub fn reclaimable_bytes(&self) -> Result<u64> {
+        self.read_log()?.reclaimable_bytes()
+    }
+
+    /// Returns what compacting the log would do, without writing anything.
+    ///
+    /// The projection assumes the live entries are copied as they are, it is off by however much
+    /// re-encoding them with the current compression and encoding changes their size.
+    pub fn compaction_estimate(&self) -> Result<CompactionEstimate> {
+        let l = self.read_log()?;
+        let live_entries = l.live_len()?;
+        let current_bytes = l.byte_len();
+        Ok(CompactionEstimate {
+            live_entries,
+            dead_entries: l.len()? - live_entries,
+            current_bytes,
+            projected_bytes: current_bytes - l.reclaimable_bytes()?,
+        })
+    }
+
+    /// Returns true if the key is in the store, this only checks the index and does not read the value.
+    pub fn contains_key(&self, key: String) -> Result<bool> {
+        KvStore::check_key(key.as_bytes())?;
+        self.read_log()?.contains(key.as_bytes())
+    }
+
+    /// Returns every key in the store, in no particular order.
+    ///
+    /// Returns an error if a key is not valid UTF-8.
+    pub fn keys(&self) -> Result<Vec<String>> {
+        let keys = self.read_log()?.keys()?;
+        let mut strings = Vec::with_capacity(keys.len());
+        for k in keys {
+            strings.push(String::from_utf8(k)?);
+        }
+        Ok(strings)
+    }
+
+    /// Returns the key stored for `key` in the column family, the name and key separated by a
+    /// NUL byte, which can't appear in the name.
+    fn cf_key(cf: &str, key: &str) -> Result<String> {
+        if cf.contains(CF_SEPARATOR) {
+            return Err(Error::from(InvalidColumnFamilyError { cf: cf.to_owned() }));
+        }
+        Ok(format!("{}{}{}", cf, CF_SEPARATOR, key))
+    }
+
+    /// Sets a value for a key in the column family `cf`, as `set` does.
+    ///
+    /// Each column family is a separate keyspace within the one log, the same key can hold
+    /// different values in different families. Returns an `InvalidColumnFamilyError` if the name
+    /// holds a NUL byte.
+    pub fn set_cf(&mut self, cf: &str, key: String, val: String) -> Result<()> {
+        self.set(KvStore::cf_key(cf, &key)?, val)
+    }
+
+    /// Gets the value of a key in the column family `cf`, or None if it is not set there.
+    pub fn get_cf(&self, cf: &str, key: String) -> Result<Option<String>> {
+        self.get(KvStore::cf_key(cf, &key)?)
+    }
+
+    /// Removes a key from the column family `cf`, returning a `KeyNotFoundError` if it is not
+    /// set there.
+    pub fn remove_cf(&mut self, cf: &str, key: String) -> Result<()> {
+        self.remove(KvStore::cf_key(cf, &key)?)
+    }
+
+    /// Returns the names of the column families with at least one key set, sorted.
+    ///
+    /// Keys set with `set_bytes` that hold a NUL byte are taken as in the family named by the
+    /// bytes before it.
+    pub fn list_cf(&self) -> Result<Vec<String>> {
+        let separator = CF_SEPARATOR as u8;
+        let mut families = BTreeSet::new();
+        for key in self.read_log()?.keys()? {
+            if let Some(end) = key.iter().position(|b| *b == separator) {
+                families.insert(String::from_utf8_lossy(&key[..end]).into_owned());
+            }
+        }
+        Ok(families.into_iter().collect())
+    }
+
+    /// Returns every key and value where the key starts with `prefix`, sorted by key.
+    ///
+    /// An empty prefix matches every key in the store.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let l = self.read_log()?;
+        let mut pairs = Vec::new();
+        for key in l.keys()? {
+            if !key.starts_with(prefix) {
+                continue;
+            }
+            if let Some(val) = l.fetch_by_key(&key)? {
+                pairs.push((key, val.into_vec()));
+            }
+        }
+        pairs.sort();
+        Ok(pairs)
+    }
+
+    /// Returns every key and value with a key from `start`, inclusive, to `end`, exclusive,
+    /// sorted by key.
+    ///
+    /// This works with either index, but only an ordered one avoids checking every key.
+    pub fn scan_range(&self, start: &[u8], end: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let l = self.read_log()?;
+        let mut pairs = Vec::new();
+        for key in l.keys_in_range(start, end)? {
+            if let Some(val) = l.fetch_by_key(&key)? {
+                pairs.push((key, val.into_vec()));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Returns the smallest key in the store, or None if it is empty, e.g. as the start of a
+    /// `scan_range` over every key.
+    ///
+    /// Needs an ordered index, see `set_ordered_index`, returning an `UnorderedIndexError`
+    /// otherwise.
+    pub fn first_key(&self) -> Result<Option<Vec<u8>>> {
+        self.read_log()?.first_key()
+    }
+
+    /// Returns the largest key in the store, or None if it is empty, as `first_key` does.
+    pub fn last_key(&self) -> Result<Option<Vec<u8>>> {
+        self.read_log()?.last_key()
+    }
+
+    /// Returns a page of up to `limit` keys after `start_after` in sorted order, and the cursor to
+    /// pass as `start_after` for the next page, None once every key has been listed.
+    ///
+    /// Needs an ordered index, see `set_ordered_index`, returning an `UnorderedIndexError`
+    /// otherwise. Keys written between pages are listed if they sort after the cursor.
+    pub fn list_keys(&self, start_after: Option<&[u8]>, limit: usize) -> Result<KeyPage> {
+        self.read_log()?.list_keys(start_after, limit)
+    }
+
+    /// Returns every value in the store, without their keys, in no particular order.
+    ///
+    /// The values are read in the order they are stored in the log, which keeps reads of a large
+    /// store sequential.
+    pub fn values(&self) -> Result<Vec<Vec<u8>>> {
+        let values = self.read_log()?.values()?;
+        Ok(values.into_iter().map(|v| v.into_vec()).collect())
+    }
+
+    /// Returns an iterator over every key and value in the store, in no particular order.
+    ///
+    /// The keys are taken when this is called and each value is only read from the log as the
+    /// iterator reaches it, so the store as a whole is never held in memory. Keys removed in the
+    /// meantime are skipped, and a value that can't be read is yielded as an error.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_ {
+        let (keys, error) = match self.read_log().and_then(|l| l.keys()) {
+            Ok(keys) => (keys, None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
+        error
+            .map(Err)
+            .into_iter()
+            .chain(keys.into_iter().filter_map(move |key| {
+                match self.read_log().and_then(|l| l.fetch_by_key(&key)) {
+                    Ok(Some(val)) => Some(Ok((key, val.into_vec()))),
+                    Ok(None) => None,
+                    Err(e) => Some(Err(e)),
+                }
+            }))
+    }
+
+    /// Writes a compacted copy of the store to a new log file in `dir`, returning its path.
+    ///
+    /// The copy holds only the live keys, and can be opened as a store of its own while this
+    /// store carries on unchanged. Nothing can be written to the store while the copy is made.
+    pub fn snapshot_to(&self, dir: &Path) -> Result<PathBuf> {
+        KvStore::check_dir(dir)?;
+        if let Some(path) = KvStore::locate_kv_file(dir, &self.prefix)? {
+            return Err(Error::from(StoreExistsError { path }));
+        }
+
+        // Written under a name that is never taken for a log file, and moved into place once
+        // complete, so a failed snapshot can't be opened as a store.
+        let path = dir.join(format!("{}.0", self.prefix));
+        let mut tmp_name = path.clone().into_os_string();
+        tmp_name.push(".compact");
+        let tmp_path = PathBuf::from(tmp_name);
+        if tmp_path.exists() {
+            fs::remove_file(&tmp_path)?;
+        }
+        info!("Writing snapshot to: {:?}", path);
+        self.read_log()?.snapshot_to(&tmp_path)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(path)
+    }
+
+    /// Writes every key and value in the store as a JSON object per line, sorted by key.
+    ///
+    /// Returns an error if a key or value is not valid UTF-8.
+    pub fn export(&self, w: &mut impl Write) -> Result<()> {
+        for (key, value) in self.scan_prefix(b"")? {
+            let entry = DumpEntry {
+                key: String::from_utf8(key)?,
+                value: String::from_utf8(value)?,
+            };
+            serde_json::to_writer(&mut *w, &entry)?;
+            w.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// Sets every key and value from a dump written by `export`, returning the number imported.
+    ///
+    /// Keys already in the store are overwritten, and keys not in the dump are left alone.
+    pub fn import(&mut self, r: &mut impl Read) -> Result<usize> {
+        let mut count = 0;
+        for line in BufReader::new(r).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: DumpEntry = serde_json::from_str(&line)?;
+            self.set(entry.key, entry.value)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Set a value for a given key, overriding a previously set value if it exists.
+    pub fn set(&mut self, key: String, val: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), val.into_bytes())
+    }
+
+    /// Set a raw byte value for a given key, overriding a previously set value if it exists.
+    pub fn set_bytes(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        KvStore::check_key(&key)?;
+        self.check_value_size(&val)?;
+        {
+            let mut l = self.write_log()?;
+            if self.dedupe_writes && l.fetch_by_key(&key)?.as_deref() == Some(&val[..]) {
+                return Ok(());
+            }
+            l.append(LogCommand::Set, &key, Some(&val))?;
+            self.after_write(&mut l, &key, Some(&val))?;
+        }
+
+        self.finish_write(1, 0)
+    }
+
+    /// Set a value for a given key, returning the value it replaced if there was one.
+    ///
+    /// Returns an error without setting the new value if the replaced value is not valid UTF-8.
+    /// With `set_dedupe_writes` on, a value the same as the stored one is returned without
+    /// writing anything, as `set` does.
+    pub fn set_and_return(&mut self, key: String, val: String) -> Result<Option<String>> {
+        KvStore::check_key(key.as_bytes())?;
+        self.check_value_size(val.as_bytes())?;
+        let old = {
+            let mut l = self.write_log()?;
+            let old = match l.fetch_by_key(key.as_bytes())? {
+                Some(bytes) => Some(String::from_utf8(bytes.into_vec())?),
+                None => None,
+            };
+            if self.dedupe_writes && old.as_ref() == Some(&val) {
+                return Ok(old);
+            }
+            l.append(LogCommand::Set, key.as_bytes(), Some(val.as_bytes()))?;
+            self.after_write(&mut l, key.as_bytes(), Some(val.as_bytes()))?;
+            old
+        };
+
+        self.finish_write(1, 0)?;
+        Ok(old)
+    }
+
+    /// Set a value for a given key that expires after `ttl`, overriding a previously set value.
+    ///
+    /// Once expired the key reads as absent, and it is dropped from the log on compaction. The TTL
+    /// is measured against the clock set with `set_clock`.
+    pub fn set_with_ttl(&mut self, key: String, val: String, ttl: Duration) -> Result<()> {
+        self.set_expiring(key.as_bytes(), val.as_bytes(), |now| {
+            now + ttl.as_millis() as u64
+        })
+    }
+
+    /// Sets the value for the key to expire at the time `expires_at` returns given the time now,
+    /// both in milliseconds since the epoch by the store's clock.
+    fn set_expiring(
+        &mut self,
+        key: &[u8],
+        val: &[u8],
+        expires_at: impl FnOnce(u64) -> u64,
+    ) -> Result<()> {
+        KvStore::check_key(key)?;
+        self.check_value_size(val)?;
+        {
+            let mut l = self.write_log()?;
+            let expires_at = expires_at(l.now_millis());
+            l.append_with_expiry(LogCommand::Set, key, Some(val), Some(expires_at))?;
+            self.after_expiring_set(&mut l, key, val, expires_at)?;
+        }
+
+        self.finish_write(1, 0)
+    }
+
+    /// Set the value for each of the given keys, in order, as if by calling `set` for each.
+    ///
+    /// The log is locked once for the whole batch and only checked for compaction at the end,
+    /// which is considerably faster for bulk loads.
+    ///
+    /// The batch is written as a unit, none of it is applied on reopen if the process dies part
+    /// way through writing it.
+    pub fn set_many(&mut self, entries: Vec<(String, String)>) -> Result<()> {
+        for (k, v) in &entries {
+            KvStore::check_key(k.as_bytes())?;
+            self.check_value_size(v.as_bytes())?;
+        }
+        let sets = {
+            let batch: Vec<BatchEntry> = entries
+                .iter()
+                .map(|(k, v)| (LogCommand::Set, k.as_bytes(), Some(v.as_bytes())))
+                .collect();
+            let mut l = self.write_log()?;
+            let batch = self.dedupe_batch(&l, batch)?;
+            l.append_batch(&batch)?;
+            self.after_batch(&mut l, &batch)?;
+            batch.len()
+        };
+
+        self.finish_write(sets, 0)
+    }
+
+    /// Starts a transaction, whose sets and removes are applied to the store all together when it
+    /// is committed.
+    pub fn transaction(&mut self) -> Transaction<'_> {
+        Transaction {
+            store: self,
+            ops: Vec::new(),
+        }
+    }
+
+    /// Sets `key` to `new` only if its current value is `expected`, where None means the key is
+    /// not set, and returns whether the value was swapped.
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: String,
+    ) -> Result<bool> {
+        KvStore::check_key(key.as_bytes())?;
+        self.check_value_size(new.as_bytes())?;
+        {
+            let mut l = self.write_log()?;
+            let current = l.fetch_by_key(key.as_bytes())?;
+            if current.as_deref() != expected.as_ref().map(|e| e.as_bytes()) {
+                return Ok(false);
+            }
+
+            l.append(LogCommand::Set, key.as_bytes(), Some(new.as_bytes()))?;
+            self.after_write(&mut l, key.as_bytes(), Some(new.as_bytes()))?;
+        }
+
+        self.finish_write(1, 0)?;
+        Ok(true)
+    }
+
+    /// Returns the value of `key`, first setting it to the value returned by `f` if the key is
+    /// not set.
+    ///
+    /// Returns an error if the stored value is not valid UTF-8.
+    pub fn get_or_insert_with(
+        &mut self,
+        key: String,
+        f: impl FnOnce() -> String,
+    ) -> Result<String> {
+        KvStore::check_key(key.as_bytes())?;
+        let val = {
+            let mut l = self.write_log()?;
+            if let Some(val) = l.fetch_by_key(key.as_bytes())? {
+                return Ok(String::from_utf8(val.into_vec())?);
+            }
+
+            let val = f();
+            self.check_value_size(val.as_bytes())?;
+            l.append(LogCommand::Set, key.as_bytes(), Some(val.as_bytes()))?;
+            self.after_write(&mut l, key.as_bytes(), Some(val.as_bytes()))?;
+            val
+        };
+
+        self.finish_write(1, 0)?;
+        Ok(val)
+    }
+
+    /// Adds `delta` to the integer value of `key` and returns the new total, a key that is not
+    /// set counts as 0.
+    ///
+    /// Returns a `NotAnIntegerError` if the value does not parse as an `i64`, and an
+    /// `IntegerOverflowError` if the total does not fit in one.
+    pub fn increment(&mut self, key: String, delta: i64) -> Result<i64> {
+        KvStore::check_key(key.as_bytes())?;
+        let total = {
+            let mut l = self.write_log()?;
+            let current = match l.fetch_by_key(key.as_bytes())? {
+                Some(val) => std::str::from_utf8(&val)
+                    .ok()
+                    .and_then(|v| v.parse::<i64>().ok())
+                    .ok_or_else(|| NotAnIntegerError { key: key.clone() })?,
+                None => 0,
+            };
+            let total = current
+                .checked_add(delta)
+                .ok_or_else(|| IntegerOverflowError { key: key.clone() })?;
+
+            let val = total.to_string();
+            self.check_value_size(val.as_bytes())?;
+            l.append(LogCommand::Set, key.as_bytes(), Some(val.as_bytes()))?;
+            self.after_write(&mut l, key.as_bytes(), Some(val.as_bytes()))?;
+            total
+        };
+
+        self.finish_write(1, 0)?;
+        Ok(total)
+    }
+
+    /// Replaces the value of the key with what `f` returns given its current value, or None if it
+    /// is not set. The key is removed if `f` returns None, nothing is written if it was not set.
+    ///
+    /// Returns an error without calling `f` if the current value is not valid UTF-8.
+    pub fn update(
+        &mut self,
+        key: String,
+        f: impl FnOnce(Option<String>) -> Option<String>,
+    ) -> Result<()> {
+        KvStore::check_key(key.as_bytes())?;
+        let set = {
+            let mut l = self.write_log()?;
+            let current = match l.fetch_by_key(key.as_bytes())? {
+                Some(bytes) => Some(String::from_utf8(bytes.into_vec())?),
+                None => None,
+            };
+            let existed = current.is_some();
+            let new = f(current);
+            match &new {
+                Some(val) => {
+                    self.check_value_size(val.as_bytes())?;
+                    l.append(LogCommand::Set, key.as_bytes(), Some(val.as_bytes()))?;
+                }
+                None if existed => l.append(LogCommand::Remove, key.as_bytes(), None)?,
+                None => return Ok(()),
+            }
+            self.after_write(&mut l, key.as_bytes(), new.as_deref().map(str::as_bytes))?;
+            new.is_some()
+        };
+
+        match set {
+            true => self.finish_write(1, 0),
+            false => self.finish_write(0, 1),
+        }
+    }
+
+    /// Moves the value of `from` to `to`, replacing any value `to` had.
+    ///
+    /// Returns a `KeyNotFoundError` if `from` is not set, and does nothing if `from` and `to` are
+    /// the same key. The value under `to` does not keep any expiry `from` had.
+    pub fn rename_key(&mut self, from: String, to: String) -> Result<()> {
+        KvStore::check_key(from.as_bytes())?;
+        KvStore::check_key(to.as_bytes())?;
+        if from == to {
+            return Ok(());
+        }
+
+        {
+            let mut l = self.write_log()?;
+            let val = match l.fetch_by_key(from.as_bytes())? {
+                Some(val) => val,
+                None => return Err(Error::from(KeyNotFoundError { key: from })),
+            };
+
+            let batch = [
+                (LogCommand::Set, to.as_bytes(), Some(&val[..])),
+                (LogCommand::Remove, from.as_bytes(), None),
+            ];
+            l.append_batch(&batch)?;
+            self.after_batch(&mut l, &batch)?;
+        }
+
+        self.finish_write(1, 1)
+    }
+
+    /// Remove a key and value from the store.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        if self.remove_if_exists(key.clone())? {
+            return Ok(());
+        }
+        Err(Error::from(KeyNotFoundError { key }))
+    }
+
+    /// Remove a key and value from the store if the key is set, returning whether it was.
+    ///
+    /// Unlike `remove` a missing key is not an error, and nothing is written for it.
+    pub fn remove_if_exists(&mut self, key: String) -> Result<bool> {
+        self.remove_bytes_if_exists(key.as_bytes())
+    }
+
+    /// Removes a raw byte key from the store if it is set, as `remove_if_exists` does.
+    fn remove_bytes_if_exists(&mut self, k: &[u8]) -> Result<bool> {
+        KvStore::check_key(k)?;
+
+        {
+            let mut l = self.write_log()?;
+
+            if !l.contains(k)? {
+                return Ok(false);
+            }
+
+            l.append(LogCommand::Remove, k, None)?;
+            self.after_write(&mut l, k, None)?;
+        }
+
+        self.finish_write(0, 1)?;
+        Ok(true)
+    }
+
+    /// Applies a write sent by `subscribe` on another store to this one, as if it were made here,
+    /// so a follower replaying a leader's events stays in step with it.
+    ///
+    /// Applying an event again is harmless: a set writes the same value, a remove of a key that
+    /// is not set does nothing. A set that expires expires at the same time here, by this store's
+    /// clock. A set without a value returns a `MissingEventValueError`.
+    pub fn apply_event(&mut self, event: LogEvent) -> Result<()> {
+        match (event.cmd, event.val) {
+            (LogCommand::Set, Some(val)) => match event.expires_at {
+                Some(expires_at) => self.set_expiring(&event.key, &val, |_| expires_at),
+                None => self.set_bytes(event.key, val),
+            },
+            (LogCommand::Set, None) => Err(Error::from(MissingEventValueError {
+                key: String::from_utf8_lossy(&event.key).into_owned(),
+            })),
+            (LogCommand::Remove, _) => self.remove_bytes_if_exists(&event.key).map(|_| ()),
+        }
+    }
+
+    /// Remove every key whose TTL has passed, returning how many were removed.
+    ///
+    /// Expired keys read as absent, but are otherwise only dropped from the index when next
+    /// read. Calling this periodically, e.g. from a thread holding a clone of the store, keeps
+    /// keys that are never read again from lingering in the index and the log.
+    pub fn purge_expired(&mut self) -> Result<usize> {
+        let purged = {
+            let mut l = self.write_log()?;
+            let keys = l.purge_expired()?;
+            let removes: Vec<BatchEntry> = keys
+                .iter()
+                .map(|k| (LogCommand::Remove, &k[..], None))
+                .collect();
+            self.after_batch(&mut l, &removes)?;
+            keys.len()
+        };
+
+        self.finish_write(0, purged)?;
+        Ok(purged)
+    }
+
+    /// Remove every key for which `f` returns false given the key and its value, returning how
+    /// many were removed.
+    ///
+    /// Every value is read to be passed to `f`, `retain_keys` avoids that when the key is enough
+    /// to decide. The removes are written as one batch, as `remove_prefix` writes them.
+    pub fn retain(&mut self, f: impl Fn(&[u8], &[u8]) -> bool) -> Result<usize> {
+        self.remove_where(|l, key| {
+            Ok(match l.fetch_by_key(key)? {
+                Some(val) => !f(key, &val),
+                None => false,
+            })
+        })
+    }
+
+    /// Remove every key for which `f` returns false, returning how many were removed.
+    ///
+    /// Unlike `retain`, no values are read. The removes are written as one batch.
+    pub fn retain_keys(&mut self, f: impl Fn(&[u8]) -> bool) -> Result<usize> {
+        self.remove_where(|_, key| Ok(!f(key)))
+    }
+
+    /// Remove every key starting with `prefix`, returning how many were removed.
+    ///
+    /// The removes are written as one batch, none of them are applied on reopen if the process
+    /// dies part way through writing them. No values are read. An empty prefix matches, and so
+    /// removes, every key in the store.
+    pub fn remove_prefix(&mut self, prefix: &[u8]) -> Result<usize> {
+        self.remove_where(|_, key| Ok(key.starts_with(prefix)))
+    }
+
+    /// Removes every key `remove` returns true for, under a single write lock.
+    ///
+    /// The removes are appended as one batch, so none of them are applied if any fails to write
+    /// or the process dies part way through writing them.
+    fn remove_where(
+        &mut self,
+        mut remove: impl FnMut(&AppendLog, &[u8]) -> Result<bool>,
+    ) -> Result<usize> {
+        let removed = {
+            let mut l = self.write_log()?;
+            let mut removed = Vec::new();
+            for key in l.keys()? {
+                if remove(&l, &key)? {
+                    removed.push(key);
+                }
+            }
+            let removes: Vec<BatchEntry> = removed
+                .iter()
+                .map(|k| (LogCommand::Remove, &k[..], None))
+                .collect();
+            l.append_batch(&removes)?;
+            self.after_batch(&mut l, &removes)?;
+            removed.len()
+        };
+
+        self.finish_write(0, removed)?;
+        Ok(removed)
+    }
+
+    /// Remove a key from the store, returning its value, or None without writing anything if the
+    /// key is not in the store.
+    ///
+    /// Returns an error if the removed value is not valid UTF-8, the key is still removed.
+    pub fn remove_and_return(&mut self, key: String) -> Result<Option<String>> {
+        KvStore::check_key(key.as_bytes())?;
+        let old = {
+            let mut l = self.write_log()?;
+            let old = match l.fetch_by_key(key.as_bytes())? {
+                Some(old) => old,
+                None => return Ok(None),
+            };
+            l.append(LogCommand::Remove, key.as_bytes(), None)?;
+            self.after_write(&mut l, key.as_bytes(), None)?;
+            old
+        };
+
+        self.finish_write(0, 1)?;
+        Ok(Some(String::from_utf8(old.into_vec())?))
+    }
+
+    fn try_compact(&mut self) -> Result<()> {
+        if !self.auto_compact && self.max_segment_bytes.is_none() {
+            return Ok(());
+        }
+
+        // Compact when the log holds dead entries and compaction_ratio times the index entries,
+        // or when it has grown past the maximum file size and by half of it since it was last
+        // rewritten, so live entries alone over the limit aren't copied again on every write.
+        {
+            let l = self.read_log()?;
+            if l.writable().is_err() {
+                // A read-only store is left as it is, even when dropped.
+                return Ok(());
+            }
+            let (len, index_len) = (l.len()?, l.index_len()?);
+            let too_large = match self.max_segment_bytes {
+                Some(max) => {
+                    let grown = l
+                        .byte_len()
+                        .saturating_sub(self.rewritten_len.load(Ordering::SeqCst));
+                    !l.is_segmented() && l.byte_len() > max && grown >= max / 2
+                }
+                None => false,
+            };
+            let dead = len > index_len;
+            if !too_large
+                && (!self.auto_compact || !dead || len < self.compaction_ratio * index_len)
+            {
+                return Ok(());
+            }
+        }
+        self.compact_log()
+    }
+
+    /// Compacts the log to a new file.
+    ///
+    /// The write lock is held from picking the new file name until the old file is removed, so
+    /// neither writes nor compactions from other clones can interleave with it.
+    pub fn compact_log(&mut self) -> Result<()> {
+        self.compact_log_with_progress(|_, _| {})
+    }
+
+    /// Compacts the log as `compact_log` does, calling `progress` with the number of entries
+    /// done so far and the total, so a long compaction can report how far along it is.
+    ///
+    /// The entries are the live ones copied to the new file, or for a segmented log the entries
+    /// of the segments checked for compaction, and the last call reports all of them done.
+    pub fn compact_log_with_progress(&mut self, progress: impl FnMut(usize, usize)) -> Result<()> {
+        let mut log = self.write_log()?;
+        if log.is_segmented() {
+            // Each segment is rewritten in place, there is no new log to move to.
+            log.compact_segments_with_progress(self.compaction_ratio, progress)?;
+            #[cfg(feature = "metrics")]
+            KvStore::record_log_size(&log)?;
+            return Ok(());
+        }
+        self.replace_log(&mut log, |log, path| {
+            log.compact_with_progress(path, progress)
+        })
+    }
+
+    /// Closes this handle on the store, first compacting the log if it holds any dead entries
+    /// and flushing it.
+    ///
+    /// Unlike dropping the store, this compacts whatever the compaction ratio, so the next open
+    /// indexes as small a log as it can, and returns any error instead of logging it. A
+    /// read-only store is only closed.
+    pub fn close(mut self) -> Result<()> {
+        let dead = {
+            let l = self.read_log()?;
+            l.writable().is_ok() && l.len()? > l.index_len()?
+        };
+        if dead {
+            self.compact_log()?;
+        }
+        self.flush()
+    }
+
+    /// Removes every key from the store, replacing the log with an empty one.
+    ///
+    /// The watchers and subscribers are told of a remove of each key that was set, the
+    /// subscribers straight away as the empty log is already synced. Clearing a store with
+    /// nothing in its log does nothing.
+    pub fn clear(&mut self) -> Result<()> {
+        let removed = {
+            let mut log = self.write_log()?;
+            if log.is_empty()? {
+                return Ok(());
+            }
+
+            let keys = log.keys()?;
+            if log.is_segmented() {
+                log.clear_segments()?;
+                #[cfg(feature = "metrics")]
+                KvStore::record_log_size(&log)?;
+            } else {
+                self.replace_log(&mut log, |log, path| log.clear(path))?;
+            }
+            let removes: Vec<BatchEntry> = keys
+                .iter()
+                .map(|k| (LogCommand::Remove, &k[..], None))
+                .collect();
+            self.after_batch(&mut log, &removes)?;
+            self.publish_synced();
+            keys.len()
+        };
+
+        self.finish_write(0, removed)
+    }
+
+    /// Moves the log to the next log file, with `write` writing the new file from the old log.
+    ///
+    /// The caller holds the write lock from picking the new file name until the old file is
+    /// removed, so neither writes nor compactions from other clones can interleave with it.
+    fn replace_log(
+        &self,
+        log: &mut AppendLog,
+        write: impl FnOnce(&mut AppendLog, &Path) -> Result<()>,
+    ) -> Result<()> {
+        // Checked first, as a leftover compaction is removed before writing.
+        log.writable()?;
+        let log_file = log.path();
+        if log.is_in_memory() {
+            // There are no files to make way for or clean up, the new log is only named.
+            write(log, &log_file)?;
+            self.rewritten_len.store(log.byte_len(), Ordering::SeqCst);
+            return Ok(());
+        }
+
+        let new_log = if self.fixed_log_file {
+            log_file.clone()
+        } else {
+            let name = log_file.file_name().unwrap().to_string_lossy();
+            let mut idx = match KvStore::log_file_index(&name, &self.prefix) {
+                Some(idx) => idx,
+                None => {
+                    return Err(Error::from(MalformedLogNameError {
+                        name: name.into_owned(),
+                    }))
+                }
+            };
+            idx += 1;
+            let i = idx.to_string();
+            let mut new_name = self.prefix.clone();
+            new_name.push('.');
+            new_name.push_str(i.as_str());
+            debug!("New Log Name: {}", new_name);
+
+            let mut new_log = PathBuf::from(&log_file);
+            new_log.set_file_name(new_name);
+            new_log
+        };
+
+        // Write next to the new file and move the result into place once it is complete and
+        // synced, so a failure part way through never leaves a partial log that a reopen would
+        // pick over the old one. The temporary name doesn't end in a number, so it is never
+        // taken for a log file.
+        let mut tmp_name = new_log.clone().into_os_string();
+        tmp_name.push(".compact");
+        let tmp_log = PathBuf::from(tmp_name);
+        if tmp_log.exists() {
+            // Left behind by a compaction that did not complete.
+            fs::remove_file(&tmp_log)?;
+        }
+        write(log, &tmp_log)?;
+        log.flush()?;
+        log.rename(&new_log)?;
+        if new_log != log_file {
+            KvStore::remove_log_file(&log_file)?;
+        }
+        log.save_index()?;
+        self.rewritten_len.store(log.byte_len(), Ordering::SeqCst);
+
+        #[cfg(feature = "metrics")]
+        KvStore::record_log_size(log)?;
+
+        Ok(())
+    }
+
+    /// Records the size of the log after it has been replaced or compacted.
+    #[cfg(feature = "metrics")]
+    fn record_log_size(log: &AppendLog) -> Result<()> {
+        metrics::gauge!("kvs.live_keys").set(log.index_len()? as f64);
+        metrics::gauge!("kvs.log_bytes").set(log.byte_len() as f64);
+        Ok(())
+    }
+}
+
+/// Opens a KvStore with the given settings, for when the defaults of `KvStore::open` don't do.
+#[derive(Clone, Debug)]
+pub struct KvStoreBuilder {
+    /// Whether a store is created when there is none at the path.
+    create_if_missing: bool,
+    /// Whether a path that does not exist is created as a directory to open the store in.
+    create_dir: bool,
+    /// Compact once the log holds this many entries per live key.
+    compaction_ratio: usize,
+    /// Whether writes and drops compact the log once it reaches the compaction ratio.
+    auto_compact: bool,
+    /// When writes are synced to disk.
+    durability: DurabilityMode,
+    /// The compression of values written.
+    compression: Compression,
+    /// The encoding of values written.
+    encoding: Encoding,
+    /// The number of recently read values kept in memory.
+    cache_capacity: usize,
+    /// The number of most recently written values read into the cache on open.
+    warm_cache: usize,
+    /// Whether the index keeps its keys in order.
+    ordered_index: bool,
+    /// The number of bytes of writes buffered before they are written to the log file.
+    write_buffer_size: usize,
+    /// The size in bytes past which writes compact the log file, or None for no limit.
+    max_segment_bytes: Option<u64>,
+    /// The clock TTLs are measured against, or None for the system clock.
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl KvStoreBuilder {
+    /// Creates a builder with the same settings as `KvStore::open`.
+    pub fn new() -> KvStoreBuilder {
+        KvStoreBuilder {
+            create_if_missing: true,
+            create_dir: false,
+            compaction_ratio: DEFAULT_COMPACTION_RATIO,
+            auto_compact: true,
+            durability: DurabilityMode::default(),
+            compression: Compression::default(),
+            encoding: Encoding::default(),
+            cache_capacity: 0,
+            warm_cache: 0,
+            ordered_index: false,
+            write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+            max_segment_bytes: None,
+            clock: None,
+        }
+    }
+
+    /// Sets whether a new store is created when there is no log at the path, on by default.
+    ///
+    /// When off, opening a directory without a log file in it, or a log file that does not
+    /// exist, returns a `StoreNotFoundError`.
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> KvStoreBuilder {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// Sets whether a path that does not exist is created, along with any missing parents, as a
+    /// directory to open the store in, rather than returning a `PathNotFoundError`.
+    pub fn create_dir(mut self, create_dir: bool) -> KvStoreBuilder {
+        self.create_dir = create_dir;
+        self
+    }
+
+    /// Sets the number of entries per live key at which the log is compacted.
+    pub fn compaction_ratio(mut self, ratio: usize) -> KvStoreBuilder {
+        self.compaction_ratio = ratio;
+        self
+    }
+
+    /// Sets whether the log is compacted automatically, as `KvStore::set_auto_compact` does.
+    pub fn auto_compact(mut self, auto_compact: bool) -> KvStoreBuilder {
+        self.auto_compact = auto_compact;
+        self
+    }
+
+    /// Sets when writes are synced to disk, as `KvStore::set_durability_mode` does.
+    pub fn durability_mode(mut self, mode: DurabilityMode) -> KvStoreBuilder {
+        self.durability = mode;
+        self
+    }
+
+    /// Sets the compression of values written, as `KvStore::set_compression` does.
+    pub fn compression(mut self, compression: Compression) -> KvStoreBuilder {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the encoding of values written, as `KvStore::set_encoding` does.
+    pub fn encoding(mut self, encoding: Encoding) -> KvStoreBuilder {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Sets the number of recently read values kept in memory, as
+    /// `KvStore::set_cache_capacity` does.
+    pub fn cache_capacity(mut self, capacity: usize) -> KvStoreBuilder {
+        self.cache_capacity = capacity;
+        self
+    }
+
+    /// Sets the number of most recently written values read into the cache on open, so the first
+    /// gets of them need not read the log file. The cache is made large enough to hold them.
+    pub fn warm_cache(mut self, count: usize) -> KvStoreBuilder {
+        self.warm_cache = count;
+        self
+    }
+
+    /// Sets whether the index keeps its keys in order, as `KvStore::set_ordered_index` does.
+    pub fn ordered_index(mut self, ordered: bool) -> KvStoreBuilder {
+        self.ordered_index = ordered;
+        self
+    }
+
+    /// Sets the number of bytes of writes buffered before they are written to the log file, as
+    /// `KvStore::set_write_buffer_size` does.
+    pub fn write_buffer_size(mut self, size: usize) -> KvStoreBuilder {
+        self.write_buffer_size = size;
+        self
+    }
+
+    /// Sets the largest the log file grows to before writes compact it, as
+    /// `KvStore::set_max_segment_bytes` does.
+    pub fn max_segment_bytes(mut self, max_bytes: u64) -> KvStoreBuilder {
+        self.max_segment_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Sets the clock TTLs are measured against, as `KvStore::set_clock` does.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> KvStoreBuilder {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Opens the store for a directory or log file, as `KvStore::open` does, with these settings.
+    pub fn open(self, path: &Path) -> Result<KvStore> {
+        if !self.create_if_missing {
+            let exists = if path.is_dir() {
+                KvStore::locate_kv_file(path, KV_FILE_PREFIX)?.is_some()
+            } else {
+                path.is_file()
+            };
+            if !exists {
+                return Err(Error::from(StoreNotFoundError {
+                    path: path.to_owned(),
+                }));
+            }
+        }
+
+        if self.create_dir && !path.exists() {
+            fs::create_dir_all(path)?;
+        }
+
+        let mut store = KvStore::open(path)?;
+        store.set_compaction_ratio(self.compaction_ratio);
+        store.set_auto_compact(self.auto_compact);
+        store.set_durability_mode(self.durability);
+        store.set_compression(self.compression);
+        store.set_encoding(self.encoding);
+        store.set_cache_capacity(self.cache_capacity.max(self.warm_cache))?;
+        store.set_ordered_index(self.ordered_index)?;
+        store.set_write_buffer_size(self.write_buffer_size)?;
+        store.set_max_segment_bytes(self.max_segment_bytes);
+        if let Some(clock) = self.clock {
+            store.set_clock(clock)?;
+        }
+        if self.warm_cache > 0 {
+            store.read_log()?.warm_cache(self.warm_cache)?;
+        }
+        Ok(store)
+    }
+}
+
+impl Default for KvStoreBuilder {
+    fn default() -> Self {
+        KvStoreBuilder::new()
+    }
+}
+
+/// Sets and removes on a KvStore that are applied together or not at all.
+///
+/// The writes are buffered until `commit`, dropping the transaction without committing it is the
+/// same as `rollback`.
+pub struct Transaction<'a> {
+    store: &'a mut KvStore,
+    ops: Vec<(LogCommand, String, Option<String>)>,
+}
+
+impl Transaction<'_> {
+    /// Sets the value of the key when the transaction is committed.
+    pub fn set(&mut self, key: String, value: String) {
+        self.ops.push((LogCommand::Set, key, Some(value)));
+    }
+
+    /// Removes the key when the transaction is committed.
+    pub fn remove(&mut self, key: String) {
+        self.ops.push((LogCommand::Remove, key, None));
+    }
+
+    /// Applies every set and remove in the transaction, in order.
+    ///
+    /// They are appended to the log as one batch under a single write lock, so no clone of the
+    /// store sees only some of them, and none of them are applied on reopen if the process dies
+    /// part way through writing them. Returns a `KeyNotFoundError`, without applying anything,
+    /// if a removed key would not be set at that point.
+    pub fn commit(self) -> Result<()> {
+        let store = self.store;
+        for (_, k, v) in &self.ops {
+            KvStore::check_key(k.as_bytes())?;
+            if let Some(v) = v {
+                store.check_value_size(v.as_bytes())?;
+            }
+        }
+        let (sets, removes) = {
+            let mut l = store.write_log()?;
+
+            // Check the removes against the keys set and removed earlier in the transaction.
+            let mut set: HashMap<&str, bool> = HashMap::new();
+            for (cmd, key, _) in &self.ops {
+                match cmd {
+                    LogCommand::Set => {
+                        set.insert(key, true);
+                    }
+                    LogCommand::Remove => {
+                        let is_set = match set.get(key.as_str()) {
+                            Some(is_set) => *is_set,
+                            None => l.contains(key.as_bytes())?,
+                        };
+                        if !is_set {
+                            return Err(Error::from(KeyNotFoundError { key: key.clone() }));
+                        }
+                        set.insert(key, false);
+                    }
+                }
+            }
+
+            let batch: Vec<BatchEntry> = self
+                .ops
+                .iter()
+                .map(|(cmd, k, v)| (cmd.clone(), k.as_bytes(), v.as_ref().map(|v| v.as_bytes())))
+                .collect();
+            let batch = store.dedupe_batch(&l, batch)?;
+            l.append_batch(&batch)?;
+            store.after_batch(&mut l, &batch)?;
+            let sets = batch
+                .iter()
+                .filter(|(cmd, _, _)| *cmd == LogCommand::Set)
+                .count();
+            (sets, batch.len() - sets)
+        };
+
+        store.finish_write(sets, removes)
+    }
+
+    /// Discards every set and remove in the transaction.
+    pub fn rollback(self) {}
+}
+
+impl Clone for KvStore {
+    fn clone(&self) -> Self {
+        KvStore {
+            log: self.log.clone(),
+            dir: self.dir.clone(),
+            prefix: self.prefix.clone(),
+            fixed_log_file: self.fixed_log_file,
+            compaction_ratio: self.compaction_ratio,
+            auto_compact: self.auto_compact,
+            durability: self.durability,
+            max_value_size: self.max_value_size,
+            max_segment_bytes: self.max_segment_bytes,
+            rewritten_len: self.rewritten_len.clone(),
+            dedupe_writes: self.dedupe_writes,
+            watchers: self.watchers.clone(),
+            subscribers: self.subscribers.clone(),
+            #[cfg(feature = "tempdir")]
+            temp_dir: self.temp_dir.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "tempdir")]
+impl Default for KvStore {
+    /// Opens a store in a new temporary directory, which is removed when the store is dropped.
+    fn default() -> Self {
+        let temp_dir = TempDir::new().expect("unable to create temporary directory");
+        let mut store = KvStore::open(temp_dir.path()).expect("unable to open KvStore");
+        store.temp_dir = Some(Arc::new(temp_dir));
+        store
+    }
+}
+
+impl Drop for KvStore {
+    /// Compacts the log if it is due, when the last handle on it is dropped.
+    ///
+    /// A failed compaction is logged rather than panicking, the log is left as it was and
+    /// compacted on a later open.
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.log) > 1 {
+            // Other clones still use the log, the last of them to be dropped compacts it.
+            return;
+        }
+        if self.log.is_poisoned() {
+            // The log may be part way through an update, leave it to be rebuilt on the next open.
+            return;
+        }
+        let has_unsynced = !self
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .unsynced
+            .is_empty();
+        if has_unsynced {
+            if let Err(e) = self.flush() {
+                warn!("Flushing the log on drop failed: {}", e);
+            }
+        }
+        if self.log.read().map_or(true, |l| l.is_in_memory()) {
+            // The log is about to be dropped along with everything in it.
+            return;
+        }
+        if let Err(e) = self.try_compact() {
+            warn!("Compacting the log on drop failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+    use tempfile::TempDir;
+
+    #[test]
+    fn locate_kv_file_returns_absolute_path() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        assert!(dir.is_absolute());
+        for name in &["kv_store.log.1", "kv_store.log.3", "other.log.7"] {
+            File::create(dir.join(name)).unwrap();
+        }
+
+        let located = KvStore::locate_kv_file(dir, KV_FILE_PREFIX)
+            .unwrap()
+            .unwrap();
+        assert!(located.is_absolute());
+        assert_eq!(located, dir.join("kv_store.log.3"));
+        assert!(located.is_file());
+    }
+
+    #[test]
+    fn get_strict_returns_key_not_found() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        assert_eq!(store.get_strict("key1".to_owned()).unwrap(), "value1");
+
+        let err = store.get_strict("key2".to_owned()).err().unwrap();
+        let err = err.downcast::<KeyNotFoundError>().unwrap();
+        assert_eq!(err.key, "key2");
+    }
+
+    #[test]
+    fn warm_cache_reads_most_recent_values() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        for key_id in 0..10 {
+            store
+                .set(format!("key{}", key_id), format!("value{}", key_id))
+                .unwrap();
+        }
+        drop(store);
+
+        let store = KvStore::builder()
+            .warm_cache(3)
+            .open(temp_dir.path())
+            .unwrap();
+        let reads = store.read_log().unwrap().disk_reads();
+        for key_id in 7..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id)).unwrap(),
+                Some(format!("value{}", key_id))
+            );
+        }
+        assert_eq!(store.read_log().unwrap().disk_reads(), reads);
+
+        assert_eq!(
+            store.get("key6".to_owned()).unwrap(),
+            Some("value6".to_owned())
+        );
+        assert!(store.read_log().unwrap().disk_reads() > reads);
+    }
+
+    #[test]
+    fn first_and_last_key_read_no_values() {
+        let temp_dir = TempDir::new().unwrap();
+        let clock = Arc::new(MockClock::new());
+        let mut store = KvStore::builder()
+            .ordered_index(true)
+            .clock(clock.clone())
+            .open(temp_dir.path())
+            .unwrap();
+        store
+            .set_with_ttl("a".to_owned(), "1".to_owned(), Duration::from_secs(1))
+            .unwrap();
+        for key in ["b", "c", "d"] {
+            store.set(key.to_owned(), "1".to_owned()).unwrap();
+        }
+        store
+            .set_with_ttl("e".to_owned(), "1".to_owned(), Duration::from_secs(1))
+            .unwrap();
+        store.flush().unwrap();
+        clock.advance(Duration::from_secs(2));
+
+        let reads = store.read_log().unwrap().disk_reads();
+        assert_eq!(store.first_key().unwrap(), Some(b"b".to_vec()));
+        assert_eq!(store.last_key().unwrap(), Some(b"d".to_vec()));
+        assert_eq!(store.read_log().unwrap().disk_reads(), reads);
+    }
+
+    #[test]
+    fn compact_reports_malformed_log_name() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let log_file = dir.join("kv_store.log.notanumber");
+        let mut store =
+            KvStore::open_log_file(dir, log_file.clone(), KV_FILE_PREFIX, false).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+        let err = store.compact_log().err().unwrap();
+        let err = err.downcast::<MalformedLogNameError>().unwrap();
+        assert_eq!(err.name, "kv_store.log.notanumber");
+        assert_eq!(store.log_file_path(), log_file);
+        assert_eq!(
+            store.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+    }
+
+    #[test]
+    fn poisoned_lock_returns_error() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+        let log = store.log.clone();
+        let result = std::thread::spawn(move || {
+            let _guard = log.write().unwrap();
+            panic!("panicking while holding the write lock");
+        })
+        .join();
+        assert!(result.is_err());
+
+        let clone = store.clone();
+        let err = clone.get("key1".to_owned()).err().unwrap();
+        assert!(err.downcast::<PoisonedLockError>().is_ok());
+        let err = store
+            .set("key2".to_owned(), "value2".to_owned())
+            .err()
+            .unwrap();
+        assert!(err.downcast::<PoisonedLockError>().is_ok());
+        assert!(store.log_file_path().is_file());
+    }
+
+    #[cfg(feature = "tempdir")]
+    #[test]
+    fn default_store_removes_temp_dir() {
+        let mut store = KvStore::default();
+        let dir = store.path().to_path_buf();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        assert_eq!(
+            store.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+        assert!(dir.exists());
+
+        drop(store);
+        assert!(!dir.exists());
     }
 }
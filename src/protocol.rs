@@ -0,0 +1,95 @@
+//! The request/response protocol spoken between a `KvsServer` and its clients.
+//!
+//! Each message is framed as a big-endian u32 length followed by the bincode encoded message.
+
+use crate::Result;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use failure::{Error, Fail};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+/// The longest encoded message in bytes that is read or written, so a bad length prefix can't
+/// make a reader allocate more than this.
+pub const MAX_MESSAGE_LEN: u32 = 64 * 1024 * 1024;
+
+#[derive(Fail, Debug)]
+#[fail(
+    display = "Message of {} bytes is over the limit of {} bytes",
+    len, max_len
+)]
+/// Error returned when a message is longer than `MAX_MESSAGE_LEN`.
+pub struct MessageTooLargeError {
+    len: usize,
+    max_len: u32,
+}
+
+/// A request sent from a client to the server.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Request {
+    /// Get the value of a key.
+    Get {
+        /// The key to fetch.
+        key: String,
+    },
+    /// Set the value of a key.
+    Set {
+        /// The key to set.
+        key: String,
+        /// The value to set.
+        value: String,
+    },
+    /// Remove a key.
+    Remove {
+        /// The key to remove.
+        key: String,
+    },
+}
+
+/// The server's response to a single request.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Response {
+    /// The value of the key from a `Get`, or None if it is not set.
+    Value(Option<String>),
+    /// A `Set` or `Remove` succeeded.
+    Ok,
+    /// The key of a `Remove` is not in the store.
+    KeyNotFound,
+    /// The request failed with the given error.
+    Err(String),
+}
+
+/// Writes a single length-prefixed message.
+///
+/// Returns a `MessageTooLargeError`, without writing anything, if the message encodes to more
+/// than `MAX_MESSAGE_LEN` bytes.
+pub fn write_message<T: Serialize>(w: &mut impl Write, msg: &T) -> Result<()> {
+    let encoded = bincode::serialize(msg)?;
+    if encoded.len() > MAX_MESSAGE_LEN as usize {
+        return Err(Error::from(MessageTooLargeError {
+            len: encoded.len(),
+            max_len: MAX_MESSAGE_LEN,
+        }));
+    }
+    w.write_u32::<BigEndian>(encoded.len() as u32)?;
+    w.write_all(&encoded)?;
+    Ok(())
+}
+
+/// Reads a single length-prefixed message.
+///
+/// A connection closed between messages is reported as an `io::ErrorKind::UnexpectedEof`. A
+/// length prefix over `MAX_MESSAGE_LEN` is reported as a `MessageTooLargeError` before anything
+/// more is read.
+pub fn read_message<T: DeserializeOwned>(r: &mut impl Read) -> Result<T> {
+    let len = r.read_u32::<BigEndian>()?;
+    if len > MAX_MESSAGE_LEN {
+        return Err(Error::from(MessageTooLargeError {
+            len: len as usize,
+            max_len: MAX_MESSAGE_LEN,
+        }));
+    }
+    let mut data = vec![0u8; len as usize];
+    r.read_exact(&mut data)?;
+    Ok(bincode::deserialize(&data)?)
+}
@@ -0,0 +1,77 @@
+//! A TCP server keeping a single KvStore open for many clients.
+
+use crate::protocol::{read_message, write_message, Request, Response};
+use crate::{KeyNotFoundError, KvStore, Result};
+use log::error;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+
+/// Serves a KvStore over TCP, speaking the `protocol` module's requests and responses.
+///
+/// Each connection is handled on its own thread with a clone of the store, so the index is built
+/// once when the store is opened rather than for every request.
+pub struct KvsServer {
+    store: KvStore,
+}
+
+impl KvsServer {
+    /// Creates a server for the given store.
+    pub fn new(store: KvStore) -> KvsServer {
+        KvsServer { store }
+    }
+
+    /// Binds to the address and serves connections until accepting one fails.
+    pub fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// Serves connections from an already bound listener until accepting one fails.
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let store = self.store.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle_connection(store, stream) {
+                    error!("Error handling connection: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Answers requests from the stream until the client closes it.
+fn handle_connection(mut store: KvStore, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    loop {
+        let request = match read_message(&mut reader) {
+            Ok(r) => r,
+            Err(e) => match e.downcast_ref::<io::Error>() {
+                Some(io_err) if io_err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                _ => return Err(e),
+            },
+        };
+
+        let response = match request {
+            Request::Get { key } => match store.get(key) {
+                Ok(val) => Response::Value(val),
+                Err(e) => Response::Err(e.to_string()),
+            },
+            Request::Set { key, value } => match store.set(key, value) {
+                Ok(()) => Response::Ok,
+                Err(e) => Response::Err(e.to_string()),
+            },
+            Request::Remove { key } => match store.remove(key) {
+                Ok(()) => Response::Ok,
+                Err(e) => match e.downcast::<KeyNotFoundError>() {
+                    Ok(_) => Response::KeyNotFound,
+                    Err(e) => Response::Err(e.to_string()),
+                },
+            },
+        };
+        write_message(&mut writer, &response)?;
+        writer.flush()?;
+    }
+}
@@ -0,0 +1,248 @@
+//! Where the bytes of a log are kept.
+//!
+//! An `AppendLog` reads and appends to its segments through `LogStorage` handles. Logs loaded
+//! from a path use `FileStorage`, logs created with `AppendLog::in_memory` use `MemoryStorage`,
+//! and `AppendLog::with_storage` opens a log on any other implementation.
+
+use crate::append_log::LogLockedError;
+use crate::Result;
+use failure::Error;
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// The bytes of a log segment: its file, a buffer for a log kept in memory, or anything else
+/// that can be read from and appended to.
+///
+/// Each value is a handle on the bytes with a position of its own. Writes always go to the end,
+/// as they do to a file opened for appending, whatever the position.
+pub trait LogStorage: Read + Write + Seek + Send {
+    /// Returns the number of bytes stored.
+    fn len(&self) -> io::Result<u64>;
+
+    /// Returns true if nothing is stored.
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Opens another handle for reading the same bytes, positioned at the start.
+    fn open_reader(&self) -> io::Result<Box<dyn LogStorage>>;
+
+    /// Opens another handle for appending to the same bytes.
+    fn open_writer(&self) -> io::Result<Box<dyn LogStorage>>;
+
+    /// Truncates or extends the bytes to `len`.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Waits for the bytes written to be durable.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Creates new, empty storage of the same kind, for the log a compaction copies this one
+    /// into. None, the default, has the compaction write the new log to a file at its path.
+    fn create_empty(&self) -> io::Result<Option<Box<dyn LogStorage>>> {
+        Ok(None)
+    }
+}
+
+/// A handle on a segment file.
+///
+/// The handles opened with `open_reader` share the file descriptor, each reading at a position
+/// of its own, so they go on reading the file after it is renamed or removed.
+pub struct FileStorage {
+    file: Arc<File>,
+    pos: u64,
+    /// The path the file was opened at, for opening a write handle on it.
+    path: PathBuf,
+    /// Whether this handle holds the file's lock.
+    locked: bool,
+}
+
+impl FileStorage {
+    /// Opens the file for reading, taking its lock against other writers if `lock` is set.
+    ///
+    /// Returns a `LogLockedError` if another process holds the lock.
+    pub fn open(path: &Path, lock: bool) -> Result<FileStorage> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(false)
+            .create(false)
+            .open(path)?;
+        if lock {
+            lock_log_file(&file, path)?;
+        }
+        Ok(FileStorage {
+            file: Arc::new(file),
+            pos: 0,
+            path: path.to_path_buf(),
+            locked: lock,
+        })
+    }
+}
+
+impl Read for FileStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let n = std::os::unix::fs::FileExt::read_at(&*self.file, buf, self.pos)?;
+        #[cfg(windows)]
+        let n = std::os::windows::fs::FileExt::seek_read(&*self.file, buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for FileStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.file).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.file).flush()
+    }
+}
+
+impl Seek for FileStorage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_position(self.pos, self.len()?, pos)?;
+        Ok(self.pos)
+    }
+}
+
+impl LogStorage for FileStorage {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn open_reader(&self) -> io::Result<Box<dyn LogStorage>> {
+        Ok(Box::new(FileStorage {
+            file: self.file.clone(),
+            pos: 0,
+            path: self.path.clone(),
+            locked: false,
+        }))
+    }
+
+    fn open_writer(&self) -> io::Result<Box<dyn LogStorage>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(false)
+            .open(&self.path)?;
+        Ok(Box::new(FileStorage {
+            file: Arc::new(file),
+            pos: 0,
+            path: self.path.clone(),
+            locked: false,
+        }))
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+impl Drop for FileStorage {
+    fn drop(&mut self) {
+        if self.locked {
+            // Closing the file releases the lock anyway, this just doesn't leave it to the OS.
+            let _ = FileExt::unlock(&*self.file);
+        }
+    }
+}
+
+/// Returns the position a seek from `pos` in storage of `len` bytes moves to.
+fn seek_position(pos: u64, len: u64, seek: SeekFrom) -> io::Result<u64> {
+    let (base, offset) = match seek {
+        SeekFrom::Start(offset) => return Ok(offset),
+        SeekFrom::End(offset) => (len, offset),
+        SeekFrom::Current(offset) => (pos, offset),
+    };
+    base.checked_add_signed(offset).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        )
+    })
+}
+
+/// The bytes of a segment of a log kept in memory, shared by all of the handles on it.
+///
+/// A clone is another handle on the same bytes, at the same position.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    data: Arc<RwLock<Vec<u8>>>,
+    pos: u64,
+}
+
+impl Read for MemoryStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.read().unwrap();
+        let start = (self.pos as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for MemoryStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut data = self.data.write().unwrap();
+        data.extend_from_slice(buf);
+        self.pos = data.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryStorage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_position(self.pos, self.len()?, pos)?;
+        Ok(self.pos)
+    }
+}
+
+impl LogStorage for MemoryStorage {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data.read().unwrap().len() as u64)
+    }
+
+    fn open_reader(&self) -> io::Result<Box<dyn LogStorage>> {
+        Ok(Box::new(MemoryStorage {
+            data: self.data.clone(),
+            pos: 0,
+        }))
+    }
+
+    fn open_writer(&self) -> io::Result<Box<dyn LogStorage>> {
+        self.open_reader()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.data.write().unwrap().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn create_empty(&self) -> io::Result<Option<Box<dyn LogStorage>>> {
+        Ok(Some(Box::new(MemoryStorage::default())))
+    }
+}
+
+/// Takes an advisory lock on the log file, so no other process can write to it at the same time.
+///
+/// The lock is held until the file is closed.
+fn lock_log_file(file: &File, path: &Path) -> Result<()> {
+    FileExt::try_lock_exclusive(file).map_err(|_| Error::from(LogLockedError::new(path)))
+}
@@ -0,0 +1,2605 @@
+use kvs::append_log::{AppendLog, LogLockedError};
+use kvs::client::Client;
+use kvs::protocol::{
+    read_message, write_message, MessageTooLargeError, Request, Response, MAX_MESSAGE_LEN,
+};
+use kvs::server::KvsServer;
+use kvs::{
+    Compression, DurabilityMode, EmptyKeyError, InvalidColumnFamilyError, KeyNotFoundError,
+    KvStore, KvStoreBuilder, LogCommand, LogEvent, MissingEventValueError, MockClock,
+    NotADirectoryError, NotAnIntegerError, PathNotFoundError, ReadOnlyError, Result, Stats,
+    StoreExistsError, StoreNotFoundError, UnorderedIndexError, ValueTooLargeError,
+};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Entries written before a compaction, and the removals among them, should survive it and a
+// reopen of the store.
+#[test]
+fn test_compaction_preserves_all_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in (0..1000).step_by(2) {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.compact_log()?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        let key = format!("key{}", key_id);
+        if key_id % 2 == 0 {
+            assert_eq!(store.get(key)?, None);
+        } else {
+            assert_eq!(store.get(key)?, Some(format!("value{}", key_id)));
+        }
+    }
+
+    Ok(())
+}
+
+// The progress callback counts up through every live entry copied by a compaction.
+#[test]
+fn test_compaction_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_auto_compact(false);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..20 {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    let mut calls = Vec::new();
+    store.compact_log_with_progress(|done, total| calls.push((done, total)))?;
+    assert_eq!(calls.len(), 80);
+    assert_eq!(calls.first(), Some(&(1, 80)));
+    assert_eq!(calls.last(), Some(&(80, 80)));
+    assert_eq!(store.count()?, 80);
+    Ok(())
+}
+
+// Recovery skips a damaged entry in the middle of the log and keeps those around it.
+#[test]
+fn test_open_with_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let log_file = store.log_file_path();
+    drop(store);
+    // Without the index saved on drop, opening reads every entry.
+    fs::remove_file(temp_dir.path().join("kv_store.log.0.idx"))?;
+
+    // Break the checksum of the entry for key5, which follows its value and absent expiry.
+    let mut data = fs::read(&log_file)?;
+    let value = data.windows(6).position(|w| w == b"value5").unwrap();
+    data[value + 6 + 1] ^= 0xff;
+    fs::write(&log_file, data)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let (store, dropped) = KvStore::open_with_recovery(temp_dir.path())?;
+    assert_eq!(dropped, 1);
+    for key_id in 0..10 {
+        let expected = match key_id {
+            5 => None,
+            _ => Some(format!("value{}", key_id)),
+        };
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    drop(store);
+
+    // The damaged entry was compacted away.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.count()?, 9);
+    drop(store);
+    let (_, dropped) = KvStore::open_with_recovery(temp_dir.path())?;
+    assert_eq!(dropped, 0);
+    Ok(())
+}
+
+// Only dropping the last handle on a store compacts it, so it is compacted once however many
+// clones there were.
+#[test]
+fn test_drop_compacts_once() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let first_log = store.log_file_path();
+    store.set_compaction_ratio(100);
+    for iter in 0..20 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+    }
+
+    let mut clones: Vec<KvStore> = (0..3).map(|_| store.clone()).collect();
+    for clone in &mut clones {
+        clone.set_compaction_ratio(2);
+    }
+    for clone in clones {
+        drop(clone);
+        assert_eq!(store.log_file_path(), first_log);
+    }
+    store.set_compaction_ratio(2);
+    drop(store);
+
+    let mut names: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|e| e.map(|e| e.file_name()))
+        .collect::<std::io::Result<_>>()?;
+    names.sort();
+    assert_eq!(names, vec!["kv_store.log.1", "kv_store.log.1.idx"]);
+    Ok(())
+}
+
+// A compaction that fails part way through leaves the old log as the one opened.
+#[test]
+fn test_failed_compaction_keeps_old_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_auto_compact(false);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let log_file = store.log_file_path();
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        store.compact_log_with_progress(|done, _| {
+            if done == 50 {
+                panic!("compaction interrupted");
+            }
+        })
+    }));
+    assert!(result.is_err());
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.log_file_path(), log_file);
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    // The next compaction replaces the partial file left behind.
+    store.compact_log()?;
+    assert_ne!(store.log_file_path(), log_file);
+    // The new log and its saved index.
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), 2);
+    Ok(())
+}
+
+// Compaction holds the write lock while it copies the live entries and swaps in the new log, so
+// removes from another clone either land before it (and are dropped from the copy) or after it (in
+// the new log). A remove written to the old log during the copy would resurrect its key.
+#[test]
+fn test_remove_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let mut remover = store.clone();
+    let handle = thread::spawn(move || -> Result<()> {
+        for key_id in 0..500 {
+            remover.remove(format!("key{}", key_id))?;
+        }
+        Ok(())
+    });
+    for _ in 0..20 {
+        store.compact_log()?;
+    }
+    handle.join().unwrap()?;
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            if key_id < 500 {
+                assert_eq!(store.get(key)?, None);
+            } else {
+                assert_eq!(store.get(key)?, Some(format!("value{}", key_id)));
+            }
+        }
+        Ok(())
+    };
+    check(&mut store)?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    check(&mut store)
+}
+
+#[test]
+fn test_contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!store.contains_key("key1".to_owned())?);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.contains_key("key1".to_owned())?);
+    assert!(!store.contains_key("key2".to_owned())?);
+
+    store.remove("key1".to_owned())?;
+    assert!(!store.contains_key("key1".to_owned())?);
+    Ok(())
+}
+
+#[test]
+fn test_binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let val = vec![0x00, 0xff, 0xfe, 0x80, 0x01];
+
+    store.set_bytes(b"key1".to_vec(), val.clone())?;
+    assert_eq!(store.get_bytes(b"key1".to_vec())?, Some(val.clone()));
+    assert_eq!(store.get_bytes(b"key2".to_vec())?, None);
+    assert!(store.get("key1".to_owned()).is_err());
+
+    // String values are readable as bytes and vice versa.
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get_bytes(b"key2".to_vec())?, Some(b"value2".to_vec()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(b"key1".to_vec())?, Some(val));
+    Ok(())
+}
+
+// A partially written final entry should be dropped on open rather than failing the load.
+#[test]
+fn test_truncated_final_entry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let log_file = store.log_file_path();
+    drop(store);
+
+    // A length prefix promising far more bytes than follow it.
+    let complete_len = log_file.metadata()?.len();
+    let mut f = OpenOptions::new().append(true).open(&log_file)?;
+    f.write_all(&[0x80, 0x00, 0x10, 0x00])?;
+    f.write_all(b"garbage")?;
+    drop(f);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(log_file.metadata()?.len(), complete_len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// The newest log is found by the number at the end of its file name, whatever dots the directories
+// above it have, and files that don't end in just a number are never taken for logs.
+#[test]
+fn test_open_dotted_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().join("user.name").join("db.9");
+    fs::create_dir_all(&dir)?;
+
+    let mut store = KvStore::open(&dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact_log()?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.compact_log()?;
+    let log_file = store.log_file_path();
+    assert_eq!(log_file, dir.join("kv_store.log.2"));
+    drop(store);
+    fs::write(dir.join("kv_store.log.backup.10"), b"not a log")?;
+
+    let store = KvStore::open(&dir)?;
+    assert_eq!(store.log_file_path(), log_file);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Stores opened on files can share a directory, and keep their file names across compactions.
+#[test]
+fn test_open_log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let users_log = temp_dir.path().join("users.log");
+    let sessions_log = temp_dir.path().join("sessions.log");
+
+    let mut users = KvStore::open(&users_log)?;
+    let mut sessions = KvStore::open(&sessions_log)?;
+    assert_eq!(users.path(), temp_dir.path());
+    users.set("key1".to_owned(), "user1".to_owned())?;
+    users.set("key1".to_owned(), "user2".to_owned())?;
+    sessions.set("key2".to_owned(), "session1".to_owned())?;
+    assert_eq!(users.get("key2".to_owned())?, None);
+    assert_eq!(sessions.get("key1".to_owned())?, None);
+
+    users.compact_log()?;
+    assert_eq!(users.log_file_path(), users_log);
+    assert_eq!(users.get("key1".to_owned())?, Some("user2".to_owned()));
+
+    drop(users);
+    drop(sessions);
+    let users = KvStore::open(&users_log)?;
+    let sessions = KvStore::open(&sessions_log)?;
+    assert_eq!(users.get("key1".to_owned())?, Some("user2".to_owned()));
+    assert_eq!(
+        sessions.get("key2".to_owned())?,
+        Some("session1".to_owned())
+    );
+
+    let mut files: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        vec![
+            "sessions.log",
+            "sessions.log.idx",
+            "users.log",
+            "users.log.idx"
+        ]
+    );
+
+    let missing_dir = temp_dir.path().join("missing").join("db.log");
+    let err = KvStore::open(&missing_dir).err().unwrap();
+    assert!(err.downcast::<PathNotFoundError>().is_ok());
+    Ok(())
+}
+
+// A burst of overwrites that would trigger the default compaction should not with a higher ratio.
+#[test]
+fn test_compaction_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_compaction_ratio(temp_dir.path(), 100)?;
+    let first_log = store.log_file_path();
+
+    for iter in 0..50 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    assert_eq!(store.log_file_path(), first_log);
+
+    // Without automatic compaction the log is only compacted on request.
+    store.set_compaction_ratio(1);
+    store.set_auto_compact(false);
+    store.set("key0".to_owned(), "50".to_owned())?;
+    assert_eq!(store.log_file_path(), first_log);
+    store.compact_log()?;
+    assert_ne!(store.log_file_path(), first_log);
+    assert_eq!(store.get("key0".to_owned())?, Some("50".to_owned()));
+    Ok(())
+}
+
+// A log file grown past the maximum size is compacted to a new file, even with automatic
+// compaction off.
+#[test]
+fn test_max_segment_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStoreBuilder::new()
+        .auto_compact(false)
+        .max_segment_bytes(512)
+        .open(temp_dir.path())?;
+    let first_log = store.log_file_path();
+    assert_eq!(first_log, temp_dir.path().join("kv_store.log.0"));
+
+    for iter in 0..100 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+    }
+    let log = store.log_file_path();
+    assert_ne!(log, first_log);
+    assert!(!first_log.exists());
+    assert!(log.exists());
+    assert!(store.stats()?.total_entries < 100);
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+
+    // Live entries alone over the limit still move to new files, but not on every write.
+    let log_index = |store: &KvStore| -> u64 {
+        let name = store.log_file_path();
+        let name = name.file_name().unwrap().to_string_lossy().into_owned();
+        name.rsplit('.').next().unwrap().parse().unwrap()
+    };
+    let before = log_index(&store);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    let rotations = log_index(&store) - before;
+    assert!(rotations > 1 && rotations < 50, "{} rotations", rotations);
+    assert_eq!(store.count()?, 101);
+    let log = store.log_file_path();
+
+    // Without a limit the log grows until it is compacted by hand.
+    store.set_max_segment_bytes(None);
+    for iter in 0..100 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+    }
+    assert_eq!(store.log_file_path(), log);
+    Ok(())
+}
+
+// A store opened through the builder uses its settings, and is only created if allowed.
+#[test]
+fn test_builder() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let err = KvStoreBuilder::new()
+        .create_if_missing(false)
+        .open(temp_dir.path())
+        .err()
+        .unwrap();
+    assert!(err.downcast::<StoreNotFoundError>().is_ok());
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), 0);
+
+    let mut store = KvStore::builder()
+        .compaction_ratio(2)
+        .open(temp_dir.path())?;
+    let first_log = store.log_file_path();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.log_file_path(), first_log);
+    // The default ratio of 10 would leave a log of two entries for one key alone.
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_ne!(store.log_file_path(), first_log);
+    drop(store);
+
+    let store = KvStoreBuilder::new()
+        .create_if_missing(false)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn test_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.keys()?.is_empty());
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let mut keys = store.keys()?;
+    keys.sort();
+    assert_eq!(keys, vec!["key1", "key3"]);
+    Ok(())
+}
+
+#[test]
+fn test_scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user:123:name".to_owned(), "ryan".to_owned())?;
+    store.set("user:123:email".to_owned(), "ryan@example.com".to_owned())?;
+    store.set("user:124:name".to_owned(), "alex".to_owned())?;
+    store.set("session:1".to_owned(), "user:123".to_owned())?;
+
+    assert_eq!(
+        store.scan_prefix(b"user:123:")?,
+        vec![
+            (b"user:123:email".to_vec(), b"ryan@example.com".to_vec()),
+            (b"user:123:name".to_vec(), b"ryan".to_vec()),
+        ]
+    );
+
+    // An empty prefix matches everything.
+    let all = store.scan_prefix(b"")?;
+    assert_eq!(all.len(), 4);
+    assert_eq!(all[0].0, b"session:1".to_vec());
+
+    assert!(store.scan_prefix(b"order:")?.is_empty());
+
+    // A full key is a prefix of itself.
+    assert_eq!(
+        store.scan_prefix(b"user:124:name")?,
+        vec![(b"user:124:name".to_vec(), b"alex".to_vec())]
+    );
+    Ok(())
+}
+
+// Buffered writes reach the file on drop, synced writes before `set` and `remove` return.
+#[test]
+fn test_durability_mode() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let log_file = store.log_file_path();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    // Only the file header is on disk.
+    assert_eq!(log_file.metadata()?.len(), 5);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    store.set_durability_mode(DurabilityMode::SyncEachWrite);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let synced_len = log_file.metadata()?.len();
+    assert!(synced_len > 0);
+    store.remove("key1".to_owned())?;
+    assert!(log_file.metadata()?.len() > synced_len);
+
+    store.set_durability_mode(DurabilityMode::Buffered);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn test_set_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_many(
+        (0..1000)
+            .map(|key_id| (format!("key{}", key_id), format!("value{}", key_id)))
+            .collect(),
+    )?;
+
+    // Later entries in a batch win, as with separate sets.
+    store.set_many(vec![
+        ("key0".to_owned(), "first".to_owned()),
+        ("key0".to_owned(), "second".to_owned()),
+    ])?;
+    store.set_many(Vec::new())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("second".to_owned()));
+    for key_id in 1..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// Clones racing to take the same key through compare_and_swap should see exactly one winner.
+#[test]
+fn test_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert!(!store.compare_and_swap(
+        "key1".to_owned(),
+        Some("value0".to_owned()),
+        "value1".to_owned()
+    )?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.compare_and_swap("key1".to_owned(), None, "value1".to_owned())?);
+    assert!(!store.compare_and_swap("key1".to_owned(), None, "value2".to_owned())?);
+    assert!(store.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        "value2".to_owned()
+    )?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    for _ in 0..20 {
+        let barrier = Arc::new(Barrier::new(2));
+        let handles: Vec<_> = (0..2)
+            .map(|id| {
+                let mut store = store.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || -> Result<bool> {
+                    barrier.wait();
+                    store.compare_and_swap("lock".to_owned(), None, format!("owner{}", id))
+                })
+            })
+            .collect();
+        let won: Vec<bool> = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Result<_>>()?;
+        assert_eq!(won.iter().filter(|w| **w).count(), 1);
+
+        let winner = won.iter().position(|w| *w).unwrap();
+        assert_eq!(
+            store.get("lock".to_owned())?,
+            Some(format!("owner{}", winner))
+        );
+        store.remove("lock".to_owned())?;
+    }
+    Ok(())
+}
+
+// The default is only computed, and stored, when the key is not already set.
+#[test]
+fn test_get_or_insert_with() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let mut calls = 0;
+    let val = store.get_or_insert_with("key1".to_owned(), || {
+        calls += 1;
+        "value1".to_owned()
+    })?;
+    assert_eq!(val, "value1");
+    assert_eq!(calls, 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let val = store.get_or_insert_with("key1".to_owned(), || {
+        calls += 1;
+        "value2".to_owned()
+    })?;
+    assert_eq!(val, "value1");
+    assert_eq!(calls, 1);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Increments from many clones at once are applied under one lock, so none of them are lost.
+#[test]
+fn test_increment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.increment("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.increment("counter".to_owned(), -7)?, -2);
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let mut store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..100 {
+                    store.increment("counter".to_owned(), 3)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("2398".to_owned()));
+
+    store.set("name".to_owned(), "value".to_owned())?;
+    let err = store.increment("name".to_owned(), 1).err().unwrap();
+    assert!(err.downcast::<NotAnIntegerError>().is_ok());
+    assert_eq!(store.get("name".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A store, and the log under it, can be shared between threads as well as moved to one.
+#[test]
+fn test_send_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<KvStore>();
+    assert_send_sync::<AppendLog>();
+}
+
+// Gets take the store by shared reference, and readers sharing a store, or each holding a
+// clone of it, fetch in parallel.
+#[test]
+fn test_concurrent_gets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let barrier = Barrier::new(8);
+    thread::scope(|scope| -> Result<()> {
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let (store, clone, barrier) = (&store, store.clone(), &barrier);
+                scope.spawn(move || -> Result<()> {
+                    barrier.wait();
+                    for i in 0..200 {
+                        let reader = if (i + t) % 2 == 0 { store } else { &clone };
+                        let val = reader.get(format!("key{}", i))?;
+                        assert_eq!(val, Some(format!("value{}", i)));
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        Ok(())
+    })?;
+    assert_eq!(store.get("key199".to_owned())?, Some("value199".to_owned()));
+    Ok(())
+}
+
+// A snapshot holds the live keys of the store, and opens as a store of its own while the
+// source carries on being written to.
+#[test]
+fn test_snapshot_to() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        store.set(format!("key{}", i), format!("new{}", i))?;
+    }
+    store.remove("key7".to_owned())?;
+    let log_file = store.log_file_path();
+
+    let path = store.snapshot_to(snapshot_dir.path())?;
+    assert_eq!(path, snapshot_dir.path().join("kv_store.log.0"));
+    assert_eq!(store.log_file_path(), log_file);
+    store.set("after".to_owned(), "snapshot".to_owned())?;
+
+    let snapshot = KvStore::open(snapshot_dir.path())?;
+    assert_eq!(snapshot.stats()?.total_entries, 49);
+    assert_eq!(snapshot.scan_prefix(b"key")?, store.scan_prefix(b"key")?);
+    assert_eq!(snapshot.get("after".to_owned())?, None);
+
+    let err = store.snapshot_to(snapshot_dir.path()).err().unwrap();
+    assert!(err.downcast::<StoreExistsError>().is_ok());
+    Ok(())
+}
+
+// A large value read back through a reader in small buffers matches what was written, whether
+// it is streamed out of the log or, being compressed, fetched whole.
+#[test]
+fn test_get_reader() -> Result<()> {
+    let value: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    for compression in &[Compression::None, Compression::Zstd] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_compression(temp_dir.path(), *compression)?;
+        store.set_bytes(b"blob".to_vec(), value.clone())?;
+        store.set("small".to_owned(), "value".to_owned())?;
+
+        let mut reader = store.get_reader("blob".to_owned())?.unwrap();
+        let mut read = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            read.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(read, value);
+
+        let mut small = String::new();
+        store
+            .get_reader("small".to_owned())?
+            .unwrap()
+            .read_to_string(&mut small)?;
+        assert_eq!(small, "value");
+        assert!(store.get_reader("missing".to_owned())?.is_none());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_get_or() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert_eq!(
+        store.get_or("key1".to_owned(), "default".to_owned())?,
+        "value1"
+    );
+    assert_eq!(
+        store.get_or("key2".to_owned(), "default".to_owned())?,
+        "default"
+    );
+
+    let hit = store.get_or_else("key1".to_owned(), || panic!("called on a hit"))?;
+    assert_eq!(hit, "value1");
+    let mut calls = 0;
+    let miss = store.get_or_else("key2".to_owned(), || {
+        calls += 1;
+        "computed".to_owned()
+    })?;
+    assert_eq!(miss, "computed");
+    assert_eq!(calls, 1);
+    Ok(())
+}
+
+// The empty key is rejected by gets, sets, removes and lookups, rather than taking a slot in the
+// index.
+#[test]
+fn test_empty_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let err = store.set("".to_owned(), "value".to_owned()).err().unwrap();
+    assert!(err.downcast::<EmptyKeyError>().is_ok());
+    let err = store.get("".to_owned()).err().unwrap();
+    assert!(err.downcast::<EmptyKeyError>().is_ok());
+    let err = store.remove("".to_owned()).err().unwrap();
+    assert!(err.downcast::<EmptyKeyError>().is_ok());
+
+    // Every other way of writing a key checks it too.
+    let ttl = Duration::from_secs(60);
+    let err = store
+        .set_with_ttl("".to_owned(), "value".to_owned(), ttl)
+        .err()
+        .unwrap();
+    assert!(err.downcast::<EmptyKeyError>().is_ok());
+    let entries = vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("".to_owned(), "value".to_owned()),
+    ];
+    let err = store.set_many(entries).err().unwrap();
+    assert!(err.downcast::<EmptyKeyError>().is_ok());
+    let err = store.increment("".to_owned(), 1).err().unwrap();
+    assert!(err.downcast::<EmptyKeyError>().is_ok());
+    let mut tx = store.transaction();
+    tx.set("".to_owned(), "value".to_owned());
+    let err = tx.commit().err().unwrap();
+    assert!(err.downcast::<EmptyKeyError>().is_ok());
+    assert_eq!(store.stats()?.total_entries, 0);
+
+    // And so does every way of reading one.
+    let err = store
+        .get_many(vec!["key1".to_owned(), "".to_owned()])
+        .err()
+        .unwrap();
+    assert!(err.downcast::<EmptyKeyError>().is_ok());
+    let err = store.contains_key("".to_owned()).err().unwrap();
+    assert!(err.downcast::<EmptyKeyError>().is_ok());
+    let err = store.get_reader("".to_owned()).err().unwrap();
+    assert!(err.downcast::<EmptyKeyError>().is_ok());
+    let err = store.debug_offset_of("".to_owned()).err().unwrap();
+    assert!(err.downcast::<EmptyKeyError>().is_ok());
+    Ok(())
+}
+
+// Removing a missing key with `remove_if_exists` is not an error, and writes nothing.
+#[test]
+fn test_remove_if_exists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_auto_compact(false);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert!(store.remove_if_exists("key1".to_owned())?);
+    assert!(!store.remove_if_exists("key1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.stats()?.total_entries, 2);
+    Ok(())
+}
+
+// With dedupe_writes on, setting a key to the value it already has writes nothing.
+#[test]
+fn test_dedupe_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_auto_compact(false);
+    store.set_dedupe_writes(true);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.stats()?.total_entries, 1);
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.stats()?.total_entries, 2);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    store.set_dedupe_writes(false);
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.stats()?.total_entries, 3);
+
+    // Each set in a batch is compared with the value before it, which may be earlier in the batch.
+    store.set_dedupe_writes(true);
+    let set_many = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    };
+    store.set_many(set_many(&[
+        ("key1", "value2"),
+        ("key2", "value1"),
+        ("key1", "value3"),
+        ("key1", "value2"),
+    ]))?;
+    assert_eq!(store.stats()?.total_entries, 6);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set_many(set_many(&[("key1", "value2"), ("key2", "value1")]))?;
+    assert_eq!(store.stats()?.total_entries, 6);
+
+    let mut tx = store.transaction();
+    tx.set("key2".to_owned(), "value1".to_owned());
+    tx.remove("key2".to_owned());
+    tx.set("key2".to_owned(), "value1".to_owned());
+    tx.commit()?;
+    assert_eq!(store.stats()?.total_entries, 8);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A range scan returns the keys in the half-open range in order, with either index.
+#[test]
+fn test_scan_range() -> Result<()> {
+    for ordered in [true, false] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .ordered_index(ordered)
+            .open(temp_dir.path())?;
+        for key in ["d", "a", "f", "c", "b", "e", "ca"] {
+            store.set(key.to_owned(), format!("value-{}", key))?;
+        }
+        store.remove("e".to_owned())?;
+
+        let keys: Vec<Vec<u8>> = store
+            .scan_range(b"b", b"f")?
+            .into_iter()
+            .map(|(k, v)| {
+                assert_eq!(
+                    v,
+                    format!("value-{}", String::from_utf8_lossy(&k)).into_bytes()
+                );
+                k
+            })
+            .collect();
+        assert_eq!(
+            keys,
+            vec![b"b".to_vec(), b"c".to_vec(), b"ca".to_vec(), b"d".to_vec()]
+        );
+        assert!(store.scan_range(b"f", b"b")?.is_empty());
+
+        // Ranges are still found once compaction has moved every entry.
+        store.compact_log()?;
+        assert_eq!(store.scan_range(b"a", b"c")?.len(), 2);
+    }
+    Ok(())
+}
+
+// Closing a store compacts away every dead entry, even below the compaction ratio.
+#[test]
+fn test_close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("key1".to_owned(), "overwritten".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.close()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.live_entries, 9);
+    assert_eq!(stats.total_entries, stats.live_entries);
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("overwritten".to_owned())
+    );
+    Ok(())
+}
+
+// Overwritten and expired values count as reclaimable until a compaction frees them.
+#[test]
+fn test_reclaimable_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_auto_compact(false);
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.set("key1".to_owned(), "value0".to_owned())?;
+    assert_eq!(store.reclaimable_bytes()?, 0);
+
+    for i in 1..5 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    let reclaimable = store.reclaimable_bytes()?;
+    assert!(reclaimable > 0);
+    let before = store.disk_usage()?;
+
+    store.compact_log()?;
+    assert_eq!(store.reclaimable_bytes()?, 0);
+    assert_eq!(store.disk_usage()?, before - reclaimable);
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+
+    // An expired value is reclaimable once it expires, before it is read or purged.
+    let clock = Arc::new(MockClock::new());
+    store.set_clock(clock.clone())?;
+    store.set_with_ttl(
+        "session".to_owned(),
+        "token".to_owned(),
+        Duration::from_secs(1),
+    )?;
+    assert_eq!(store.reclaimable_bytes()?, 0);
+    clock.advance(Duration::from_secs(2));
+    let reclaimable = store.reclaimable_bytes()?;
+    assert!(reclaimable > 0);
+    let estimate = store.compaction_estimate()?;
+    assert_eq!(estimate.live_entries, 2);
+    assert_eq!(estimate.dead_entries, 1);
+    let before = store.disk_usage()?;
+    store.compact_log()?;
+    assert_eq!(store.disk_usage()?, before - reclaimable);
+    Ok(())
+}
+
+// The estimate of a compaction matches what compacting then does, and writes nothing itself.
+#[test]
+fn test_compaction_estimate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_auto_compact(false);
+    for iter in 0..5 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+
+    let usage = store.disk_usage()?;
+    let estimate = store.compaction_estimate()?;
+    assert_eq!(estimate.live_entries, 19);
+    assert_eq!(estimate.dead_entries, 82);
+    assert_eq!(estimate.current_bytes, usage);
+    assert!(estimate.projected_bytes < estimate.current_bytes);
+    assert_eq!(store.disk_usage()?, usage);
+
+    store.compact_log()?;
+    assert_eq!(store.disk_usage()?, estimate.projected_bytes);
+    let estimate = store.compaction_estimate()?;
+    assert_eq!(estimate.dead_entries, 0);
+    assert_eq!(estimate.projected_bytes, estimate.current_bytes);
+    Ok(())
+}
+
+#[test]
+fn test_set_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(MockClock::new());
+    let mut store = KvStore::builder()
+        .clock(clock.clone())
+        .open(temp_dir.path())?;
+    store.set_with_ttl(
+        "session".to_owned(),
+        "token".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    store.set_with_ttl(
+        "long".to_owned(),
+        "lived".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    assert_eq!(store.get("session".to_owned())?, Some("token".to_owned()));
+    assert!(store.debug_offset_of("session".to_owned())?.is_some());
+
+    clock.advance(Duration::from_millis(100));
+    assert_eq!(store.debug_offset_of("session".to_owned())?, None);
+    assert_eq!(store.get("session".to_owned())?, None);
+    assert!(!store.contains_key("session".to_owned())?);
+
+    // A plain set replaces the expiry.
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    clock.advance(Duration::from_millis(100));
+
+    store.compact_log()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("long".to_owned())?, Some("lived".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// An expired key is gone from every view of the store before anything reads its value, and
+// stays gone after reopening.
+#[test]
+fn test_expired_key_without_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(MockClock::new());
+    let mut store = KvStore::builder()
+        .clock(clock.clone())
+        .ordered_index(true)
+        .open(temp_dir.path())?;
+    store.set("kept".to_owned(), "value".to_owned())?;
+    for key in &["a", "b"] {
+        store.set_with_ttl(key.to_string(), "v".to_owned(), Duration::from_millis(50))?;
+    }
+    assert_eq!(store.count()?, 3);
+    clock.advance(Duration::from_millis(100));
+
+    assert!(!store.contains_key("a".to_owned())?);
+    assert_eq!(store.count()?, 1);
+    assert_eq!(store.keys()?, vec!["kept".to_owned()]);
+    assert_eq!(store.list_keys(None, 10)?.0, vec![b"kept".to_vec()]);
+    assert!(store.scan_prefix(b"a")?.is_empty());
+    match store.remove("a".to_owned()) {
+        Err(e) => assert!(e.downcast_ref::<KeyNotFoundError>().is_some()),
+        Ok(()) => panic!("removed an expired key"),
+    }
+    assert_eq!(store.stats()?.total_entries, 3);
+
+    drop(store);
+    let store = KvStore::builder()
+        .clock(clock.clone())
+        .open(temp_dir.path())?;
+    assert!(!store.contains_key("b".to_owned())?);
+    assert_eq!(store.count()?, 1);
+    Ok(())
+}
+
+// A key expires as soon as the store's clock passes its TTL, without waiting in real time.
+#[test]
+fn test_mock_clock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let clock = Arc::new(MockClock::new());
+    store.set_clock(clock.clone())?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(1000),
+    )?;
+
+    clock.advance(Duration::from_millis(999));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    clock.advance(Duration::from_millis(2));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// An ordered store knows its smallest and largest keys, a hashed one refuses to say.
+#[test]
+fn test_first_and_last_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("mango".to_owned(), "1".to_owned())?;
+    let err = store.first_key().unwrap_err();
+    assert!(err.downcast::<UnorderedIndexError>().is_ok());
+
+    store.set_ordered_index(true)?;
+    for key in ["kiwi", "apple", "pear", "banana"] {
+        store.set(key.to_owned(), "1".to_owned())?;
+    }
+    assert_eq!(store.first_key()?, Some(b"apple".to_vec()));
+    assert_eq!(store.last_key()?, Some(b"pear".to_vec()));
+
+    store.remove("apple".to_owned())?;
+    store.remove("pear".to_owned())?;
+    assert_eq!(store.first_key()?, Some(b"banana".to_vec()));
+    assert_eq!(store.last_key()?, Some(b"mango".to_vec()));
+
+    // An expired key is skipped over.
+    let clock = Arc::new(MockClock::new());
+    store.set_clock(clock.clone())?;
+    store.set_with_ttl(
+        "aardvark".to_owned(),
+        "1".to_owned(),
+        Duration::from_secs(1),
+    )?;
+    assert_eq!(store.first_key()?, Some(b"aardvark".to_vec()));
+    clock.advance(Duration::from_secs(2));
+    assert_eq!(store.first_key()?, Some(b"banana".to_vec()));
+
+    store.clear()?;
+    assert_eq!(store.first_key()?, None);
+    assert_eq!(store.last_key()?, None);
+    Ok(())
+}
+
+// Paging through the keys sees each of them exactly once, in order.
+#[test]
+fn test_list_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key00".to_owned(), "value".to_owned())?;
+    let err = store.list_keys(None, 10).unwrap_err();
+    assert!(err.downcast::<UnorderedIndexError>().is_ok());
+
+    store.set_ordered_index(true)?;
+    for key_id in 1..25 {
+        store.set(format!("key{:02}", key_id), "value".to_owned())?;
+    }
+
+    let mut seen = Vec::new();
+    let mut cursor = None;
+    let mut pages = 0;
+    loop {
+        let (keys, next) = store.list_keys(cursor.as_deref(), 10)?;
+        pages += 1;
+        seen.extend(keys);
+        match next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    assert_eq!(pages, 3);
+    let expected: Vec<Vec<u8>> = (0..25)
+        .map(|key_id| format!("key{:02}", key_id).into_bytes())
+        .collect();
+    assert_eq!(seen, expected);
+
+    // A page that ends on the last key has no next cursor.
+    assert_eq!(
+        store.list_keys(Some(b"key14"), 10)?,
+        (expected[15..].to_vec(), None)
+    );
+    assert_eq!(
+        store.list_keys(Some(b"key03"), 0)?,
+        (Vec::new(), Some(b"key03".to_vec()))
+    );
+    assert_eq!(store.list_keys(Some(b"zzz"), 10)?, (Vec::new(), None));
+    Ok(())
+}
+
+// An update sees the current value and replaces or removes it.
+#[test]
+fn test_update() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("list".to_owned(), "a".to_owned())?;
+
+    store.update("list".to_owned(), |val| val.map(|v| v + ",b"))?;
+    store.update("list".to_owned(), |val| val.map(|v| v + ",c"))?;
+    assert_eq!(store.get("list".to_owned())?, Some("a,b,c".to_owned()));
+
+    store.update("new".to_owned(), |val| {
+        assert_eq!(val, None);
+        Some("first".to_owned())
+    })?;
+    assert_eq!(store.get("new".to_owned())?, Some("first".to_owned()));
+
+    // Only delete the key if it holds the expected value.
+    let delete_if_b = |val: Option<String>| val.filter(|v| !v.ends_with('b'));
+    store.update("list".to_owned(), delete_if_b)?;
+    assert_eq!(store.get("list".to_owned())?, Some("a,b,c".to_owned()));
+    store.set("list".to_owned(), "a,b".to_owned())?;
+    store.update("list".to_owned(), delete_if_b)?;
+    assert_eq!(store.get("list".to_owned())?, None);
+
+    // Removing a key that is not set writes nothing.
+    let len = store.stats()?.total_entries;
+    store.update("missing".to_owned(), |_| None)?;
+    assert_eq!(store.stats()?.total_entries, len);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("list".to_owned())?, None);
+    assert_eq!(store.get("new".to_owned())?, Some("first".to_owned()));
+    Ok(())
+}
+
+// Expired keys that are never read again are dropped by a purge, which writes their removals so
+// they stay gone once the store is reopened.
+#[test]
+fn test_purge_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(MockClock::new());
+    let mut store = KvStore::builder()
+        .clock(clock.clone())
+        .open(temp_dir.path())?;
+    store.set_auto_compact(false);
+    for i in 0..5 {
+        store.set_with_ttl(
+            format!("short{}", i),
+            "value".to_owned(),
+            Duration::from_millis(50),
+        )?;
+    }
+    store.set_with_ttl(
+        "long".to_owned(),
+        "lived".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.set("forever".to_owned(), "value".to_owned())?;
+
+    clock.advance(Duration::from_millis(100));
+    // Expired keys are not counted even before they are purged.
+    assert_eq!(store.count()?, 2);
+    assert_eq!(store.purge_expired()?, 5);
+    assert_eq!(store.count()?, 2);
+    assert_eq!(store.purge_expired()?, 0);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.count()?, 2);
+    assert_eq!(store.get("long".to_owned())?, Some("lived".to_owned()));
+    Ok(())
+}
+
+// Verifying a healthy store finds nothing wrong, and a record damaged on disk afterwards is found
+// along with the key that points at it.
+#[test]
+fn test_verify() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let report = store.verify()?;
+    assert_eq!(report.good_entries, 10);
+    assert_eq!(report.corrupt_entries, 0);
+    assert_eq!(report.orphaned_entries, 0);
+    assert!(report.is_ok());
+
+    // Break the checksum of the entry for key5, which follows its value and absent expiry.
+    let log_file = store.log_file_path();
+    let data = fs::read(&log_file)?;
+    let value = data.windows(6).position(|w| w == b"value5").unwrap();
+    let mut f = OpenOptions::new().write(true).open(&log_file)?;
+    f.seek(SeekFrom::Start((value + 6 + 1) as u64))?;
+    f.write_all(&[data[value + 6 + 1] ^ 0xff])?;
+    drop(f);
+
+    let report = store.verify()?;
+    assert_eq!(report.good_entries, 9);
+    assert_eq!(report.corrupt_entries, 1);
+    assert_eq!(report.orphaned_entries, 1);
+    assert!(!report.is_ok());
+    Ok(())
+}
+
+// A log damaged before the store is opened can't be opened, but can be verified from its path,
+// which leaves it as it is and skips over the damage to the entries after it.
+#[test]
+fn test_verify_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let log_file = store.log_file_path();
+    drop(store);
+    fs::remove_file(temp_dir.path().join("kv_store.log.0.idx"))?;
+
+    // Break the checksum of the entry for key5, which follows its value and absent expiry.
+    let mut data = fs::read(&log_file)?;
+    let value = data.windows(6).position(|w| w == b"value5").unwrap();
+    data[value + 6 + 1] ^= 0xff;
+    fs::write(&log_file, &data)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let report = KvStore::verify_path(temp_dir.path())?;
+    assert_eq!(report.good_entries, 9);
+    assert_eq!(report.corrupt_entries, 1);
+    assert_eq!(report.orphaned_entries, 0);
+    assert!(!report.is_ok());
+    assert_eq!(KvStore::verify_path(&log_file)?.good_entries, 9);
+    assert_eq!(fs::read(&log_file)?, data);
+
+    let empty_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(KvStore::verify_path(empty_dir.path()).is_err());
+    assert_eq!(fs::read_dir(empty_dir.path())?.count(), 0);
+    Ok(())
+}
+
+// Retaining keeps only the keys the predicate accepts, by value or by key alone, and the removals
+// are written to the log.
+#[test]
+fn test_retain() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "v".repeat(key_id))?;
+    }
+    store.set("tmp:1".to_owned(), "vv".to_owned())?;
+    let watcher = store.watch("key1".to_owned());
+    let events = store.subscribe();
+
+    assert_eq!(store.retain(|_, val| val.len() % 2 == 0)?, 5);
+    assert_eq!(watcher.try_recv(), Ok(None));
+    store.flush()?;
+    assert_eq!(events.try_iter().count(), 5);
+    assert_eq!(store.count()?, 6);
+    for key_id in 0..10 {
+        let expected = match key_id % 2 {
+            0 => Some("v".repeat(key_id)),
+            _ => None,
+        };
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    assert_eq!(store.retain(|_, val| val.len() % 2 == 0)?, 0);
+
+    assert_eq!(store.retain_keys(|key| !key.starts_with(b"tmp:"))?, 1);
+    assert_eq!(store.get("tmp:1".to_owned())?, None);
+    store.flush()?;
+    assert_eq!(events.try_iter().count(), 1);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.count()?, 5);
+    assert_eq!(store.get("key4".to_owned())?, Some("vvvv".to_owned()));
+    Ok(())
+}
+
+// Removing a prefix clears only the keys under it.
+#[test]
+fn test_remove_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..5 {
+        store.set(format!("users:{}", key_id), "user".to_owned())?;
+        store.set(format!("sessions:{}", key_id), "session".to_owned())?;
+    }
+    store.set("users".to_owned(), "not under the prefix".to_owned())?;
+
+    assert_eq!(store.remove_prefix(b"sessions:")?, 5);
+    assert_eq!(store.remove_prefix(b"sessions:")?, 0);
+    assert!(store.scan_prefix(b"sessions:")?.is_empty());
+    assert_eq!(store.scan_prefix(b"users:")?.len(), 5);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.count()?, 6);
+    assert_eq!(store.get("sessions:0".to_owned())?, None);
+    assert_eq!(store.get("users:0".to_owned())?, Some("user".to_owned()));
+
+    // An empty prefix clears the whole store.
+    assert_eq!(store.remove_prefix(b"")?, 6);
+    assert_eq!(store.count()?, 0);
+    Ok(())
+}
+
+// The removes of a prefix are one batch: watchers hear of each of them, and a log cut off part
+// way through writing them comes back with none of them applied.
+#[test]
+fn test_remove_prefix_is_atomic() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_auto_compact(false);
+    for key_id in 0..5 {
+        store.set(format!("sessions:{}", key_id), "session".to_owned())?;
+    }
+    let watcher = store.watch("sessions:3".to_owned());
+
+    assert_eq!(store.remove_prefix(b"sessions:")?, 5);
+    assert_eq!(watcher.try_recv(), Ok(None));
+    let log_file = store.log_file_path();
+    drop(store);
+
+    // Cut off the end of the batch's closing marker.
+    let len = fs::metadata(&log_file)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log_file)?
+        .set_len(len - 2)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.count()?, 5);
+    assert_eq!(
+        store.get("sessions:3".to_owned())?,
+        Some("session".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn test_set_and_remove_return_previous_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(
+        store.set_and_return("key1".to_owned(), "value1".to_owned())?,
+        None
+    );
+    assert_eq!(
+        store.set_and_return("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    assert_eq!(
+        store.remove_and_return("key1".to_owned())?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.remove_and_return("key1".to_owned())?, None);
+
+    // A removed key starts over.
+    assert_eq!(
+        store.set_and_return("key1".to_owned(), "value3".to_owned())?,
+        None
+    );
+
+    // Nothing is written when the value is the same and writes are deduplicated, or when the
+    // replaced value can't be returned.
+    store.set_auto_compact(false);
+    store.set_dedupe_writes(true);
+    let len = store.stats()?.total_entries;
+    assert_eq!(
+        store.set_and_return("key1".to_owned(), "value3".to_owned())?,
+        Some("value3".to_owned())
+    );
+    assert_eq!(store.stats()?.total_entries, len);
+    store.set_bytes(b"binary".to_vec(), vec![0xff, 0xfe])?;
+    let len = store.stats()?.total_entries;
+    assert!(store
+        .set_and_return("binary".to_owned(), "text".to_owned())
+        .is_err());
+    assert_eq!(store.stats()?.total_entries, len);
+    assert_eq!(store.get_bytes(b"binary".to_vec())?, Some(vec![0xff, 0xfe]));
+    Ok(())
+}
+
+// A server should answer a sequence of requests on one connection, and serve other connections.
+#[test]
+fn test_server_protocol() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || KvsServer::new(store).serve(listener));
+
+    let mut stream = TcpStream::connect(addr)?;
+    let mut roundtrip = |request: Request| -> Result<Response> {
+        write_message(&mut stream, &request)?;
+        read_message(&mut stream)
+    };
+    assert_eq!(
+        roundtrip(Request::Get {
+            key: "key1".to_owned()
+        })?,
+        Response::Value(None)
+    );
+    assert_eq!(
+        roundtrip(Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned()
+        })?,
+        Response::Ok
+    );
+    assert_eq!(
+        roundtrip(Request::Remove {
+            key: "key2".to_owned()
+        })?,
+        Response::KeyNotFound
+    );
+
+    let mut other = TcpStream::connect(addr)?;
+    write_message(
+        &mut other,
+        &Request::Get {
+            key: "key1".to_owned(),
+        },
+    )?;
+    assert_eq!(
+        read_message::<Response>(&mut other)?,
+        Response::Value(Some("value1".to_owned()))
+    );
+    Ok(())
+}
+
+// A length prefix over the limit is rejected before its message is read, and the server drops
+// the connection sending it without it affecting others.
+#[test]
+fn test_oversized_message() -> Result<()> {
+    let prefix = (MAX_MESSAGE_LEN + 1).to_be_bytes();
+    match read_message::<Request>(&mut &prefix[..]) {
+        Err(e) => assert!(e.downcast_ref::<MessageTooLargeError>().is_some()),
+        Ok(r) => panic!("read an oversized message: {:?}", r),
+    }
+    let mut written = Vec::new();
+    let request = Request::Set {
+        key: "key1".to_owned(),
+        value: "v".repeat(MAX_MESSAGE_LEN as usize),
+    };
+    match write_message(&mut written, &request) {
+        Err(e) => assert!(e.downcast_ref::<MessageTooLargeError>().is_some()),
+        Ok(()) => panic!("wrote an oversized message"),
+    }
+    assert!(written.is_empty());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || KvsServer::new(store).serve(listener));
+
+    let mut bad = TcpStream::connect(addr)?;
+    bad.write_all(&u32::MAX.to_be_bytes())?;
+    let mut rest = Vec::new();
+    // The server closes the connection rather than waiting for 4GiB of message.
+    assert_eq!(bad.read_to_end(&mut rest)?, 0);
+
+    let mut good = TcpStream::connect(addr)?;
+    write_message(
+        &mut good,
+        &Request::Get {
+            key: "key1".to_owned(),
+        },
+    )?;
+    assert_eq!(read_message::<Response>(&mut good)?, Response::Value(None));
+    Ok(())
+}
+
+// Values set through a client should be readable from other clients and persist in the store.
+#[test]
+fn test_client_roundtrip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || KvsServer::new(store).serve(listener));
+
+    let mut client = Client::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let mut other = Client::connect(addr)?;
+    assert_eq!(other.get("key2".to_owned())?, Some("value2".to_owned()));
+    other.remove("key2".to_owned())?;
+    let err = other.remove("key2".to_owned()).err().unwrap();
+    assert!(err.downcast::<KeyNotFoundError>().is_ok());
+    assert_eq!(client.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Stores with different prefixes should share a directory without seeing each other's writes.
+#[test]
+fn test_open_with_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut users = KvStore::open_with_prefix(temp_dir.path(), "users")?;
+    let mut sessions = KvStore::open_with_prefix(temp_dir.path(), "sessions")?;
+    users.set("key1".to_owned(), "user1".to_owned())?;
+    sessions.set("key1".to_owned(), "session1".to_owned())?;
+    sessions.set("key2".to_owned(), "session2".to_owned())?;
+    assert_eq!(users.get("key2".to_owned())?, None);
+
+    users.compact_log()?;
+    assert_eq!(users.log_file_path(), temp_dir.path().join("users.1"));
+    assert_eq!(sessions.log_file_path(), temp_dir.path().join("sessions.0"));
+
+    drop(users);
+    drop(sessions);
+    let users = KvStore::open_with_prefix(temp_dir.path(), "users")?;
+    let sessions = KvStore::open_with_prefix(temp_dir.path(), "sessions")?;
+    assert_eq!(users.get("key1".to_owned())?, Some("user1".to_owned()));
+    assert_eq!(users.get("key2".to_owned())?, None);
+    assert_eq!(
+        sessions.get("key1".to_owned())?,
+        Some("session1".to_owned())
+    );
+    assert_eq!(users.keys()?.len(), 1);
+    assert_eq!(sessions.keys()?.len(), 2);
+
+    let missing_dir = temp_dir.path().join("missing");
+    let err = KvStore::open_with_prefix(&missing_dir, "users")
+        .err()
+        .unwrap();
+    assert!(err.downcast::<PathNotFoundError>().is_ok());
+    Ok(())
+}
+
+// A missing directory and a path that is not a directory are told apart, and the builder can
+// create a missing directory instead.
+#[test]
+fn test_open_path_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let missing = temp_dir.path().join("missing");
+    let file = temp_dir.path().join("file");
+    fs::write(&file, b"not a store")?;
+
+    let err = KvStore::open(&missing.join("db.log")).err().unwrap();
+    assert!(err.downcast::<PathNotFoundError>().is_ok());
+    let err = KvStore::open(&file.join("db.log")).err().unwrap();
+    assert!(err.downcast::<NotADirectoryError>().is_ok());
+    let err = KvStore::open_with_prefix(&missing, "users").err().unwrap();
+    assert!(err.downcast::<PathNotFoundError>().is_ok());
+    let err = KvStore::open_with_prefix(&file, "users").err().unwrap();
+    assert!(err.downcast::<NotADirectoryError>().is_ok());
+
+    let nested = missing.join("nested");
+    let mut store = KvStoreBuilder::new().create_dir(true).open(&nested)?;
+    assert!(nested.is_dir());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.log_file_path().parent(), Some(nested.as_path()));
+    Ok(())
+}
+
+// The index saved when a store is dropped is loaded on open in place of reading the log, with
+// the entries appended after it was saved read from the log, and one that is damaged ignored.
+#[test]
+fn test_saved_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_auto_compact(false);
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("new{}", key_id))?;
+        store.remove(format!("key{}", key_id + 900))?;
+    }
+    let log_file = store.log_file_path();
+    let index_file = temp_dir.path().join("kv_store.log.0.idx");
+    let expected: Vec<_> = store.scan_prefix(b"key")?;
+    let stats = store.stats()?;
+    drop(store);
+    assert!(index_file.is_file());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_auto_compact(false);
+    assert_eq!(store.scan_prefix(b"key")?, expected);
+    let loaded = store.stats()?;
+    assert_eq!(loaded.live_entries, stats.live_entries);
+    assert_eq!(loaded.total_entries, stats.total_entries);
+
+    // An index saved before more entries were appended only covers the start of the log.
+    let saved = fs::read(&index_file)?;
+    store.set("key1".to_owned(), "tail".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+    fs::write(&index_file, &saved)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("tail".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("new3".to_owned()));
+    assert_eq!(store.count()?, 899);
+    drop(store);
+
+    fs::write(&index_file, b"KVIX\x01garbage")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.log_file_path(), log_file);
+    assert_eq!(store.get("key1".to_owned())?, Some("tail".to_owned()));
+    assert_eq!(store.count()?, 899);
+    Ok(())
+}
+
+// Runs a sequence of operations against the store, returning what each observed.
+fn exercise_store(store: &mut KvStore) -> Result<Vec<String>> {
+    let mut seen = Vec::new();
+    store.set_compaction_ratio(2);
+    for iter in 0..3 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    seen.push(format!("{:?}", store.get("key1".to_owned())?));
+    seen.push(format!("{:?}", store.get("key0".to_owned())?));
+    seen.push(format!("{}", store.remove("key0".to_owned()).is_err()));
+    seen.push(format!("{}", store.increment("counter".to_owned(), 5)?));
+    let mut tx = store.transaction();
+    tx.set("tx1".to_owned(), "a".to_owned());
+    tx.remove("key2".to_owned());
+    tx.commit()?;
+
+    let mut keys = store.keys()?;
+    keys.sort();
+    seen.push(format!("{:?}", keys));
+    seen.push(format!("{:?}", store.scan_prefix(b"key1")?));
+    let mut val = String::new();
+    store
+        .get_reader("key3".to_owned())?
+        .unwrap()
+        .read_to_string(&mut val)?;
+    seen.push(val);
+
+    store.compact_log()?;
+    let stats = store.stats()?;
+    seen.push(format!("{} {}", stats.live_entries, stats.total_entries));
+    seen.push(format!("{}", store.disk_usage()?));
+    store.clear()?;
+    seen.push(format!(
+        "{} {:?}",
+        store.count()?,
+        store.get("key1".to_owned())?
+    ));
+    Ok(seen)
+}
+
+// A store in memory behaves as one on disk.
+#[test]
+fn test_in_memory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut on_disk = KvStore::open(temp_dir.path())?;
+    let mut in_memory = KvStore::in_memory()?;
+    assert_eq!(
+        exercise_store(&mut in_memory)?,
+        exercise_store(&mut on_disk)?
+    );
+
+    let clone = in_memory.clone();
+    in_memory.set("shared".to_owned(), "value".to_owned())?;
+    assert_eq!(clone.get("shared".to_owned())?, Some("value".to_owned()));
+    assert_eq!(in_memory.log_file_path(), Path::new("kv_store.log.0"));
+    assert!(!in_memory.log_file_path().exists());
+    Ok(())
+}
+
+// Log files left behind by compactions that died before removing them are cleaned up on open.
+#[test]
+fn test_open_removes_stale_log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for name in &[
+        "kv_store.log.0",
+        "kv_store.log.1",
+        "kv_store.log.2",
+        "other.log.0",
+    ] {
+        fs::File::create(temp_dir.path().join(name))?;
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.log_file_path(),
+        temp_dir.path().join("kv_store.log.2")
+    );
+    let mut files: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    files.sort();
+    assert_eq!(files, vec!["kv_store.log.2", "other.log.0"]);
+    Ok(())
+}
+
+#[test]
+fn test_count_and_disk_usage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_auto_compact(false);
+    assert_eq!(store.count()?, 0);
+    assert!(store.is_empty()?);
+    // Only the file header.
+    assert_eq!(store.disk_usage()?, 5);
+
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let written = store.disk_usage()?;
+    assert!(written > 0);
+    store.remove("key0".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key2".to_owned(), "overwritten".to_owned())?;
+    assert_eq!(store.count()?, 8);
+    assert!(!store.is_empty()?);
+    let before_compaction = store.disk_usage()?;
+    assert!(before_compaction > written);
+
+    store.compact_log()?;
+    assert_eq!(store.count()?, 8);
+    let compacted = store.disk_usage()?;
+    assert!(compacted < written);
+    assert_eq!(compacted, store.log_file_path().metadata()?.len());
+    Ok(())
+}
+
+// A highly compressible value should take up far less space compressed, and read back the same.
+#[test]
+fn test_compression() -> Result<()> {
+    let val = "{\"name\": \"ryan\"}".repeat(64 * 1024 / 16);
+    let mut sizes = Vec::new();
+    for compression in [Compression::None, Compression::Zstd, Compression::Lz4] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_compression(temp_dir.path(), compression)?;
+        store.set("key1".to_owned(), val.clone())?;
+        assert_eq!(store.get("key1".to_owned())?, Some(val.clone()));
+        sizes.push(store.disk_usage()?);
+
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some(val.clone()));
+    }
+
+    assert!(sizes[0] > 64 * 1024);
+    assert!(sizes[1] * 10 < sizes[0]);
+    assert!(sizes[2] * 10 < sizes[0]);
+    Ok(())
+}
+
+// Exporting a store and importing the dump into a fresh one should copy every key and value.
+#[test]
+fn test_export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value\n{}", key_id))?;
+    }
+    store.remove("key50".to_owned())?;
+
+    let mut dump = Vec::new();
+    store.export(&mut dump)?;
+    assert_eq!(String::from_utf8(dump.clone())?.lines().count(), 99);
+
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut other = KvStore::open(other_dir.path())?;
+    assert_eq!(other.import(&mut dump.as_slice())?, 99);
+
+    let mut keys = store.keys()?;
+    let mut other_keys = other.keys()?;
+    keys.sort();
+    other_keys.sort();
+    assert_eq!(keys, other_keys);
+    assert_eq!(other.get("key7".to_owned())?, Some("value\n7".to_owned()));
+    assert_eq!(other.get("key50".to_owned())?, None);
+
+    // A malformed dump is an error rather than a partial import being reported as complete.
+    assert!(other.import(&mut "not json\n".as_bytes()).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_cache_capacity(temp_dir.path(), 16)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // The cache carries over to the compacted log, starting out empty.
+    store.compact_log()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn test_clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.clear()?;
+    assert_eq!(
+        store.log_file_path(),
+        temp_dir.path().join("kv_store.log.0")
+    );
+
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.flush()?;
+    let watcher = store.watch("key1".to_owned());
+    let events = store.subscribe();
+    store.clear()?;
+    assert_eq!(store.count()?, 0);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(watcher.try_recv(), Ok(None));
+    // The removes are sent straight away, the empty log is already synced.
+    let mut removed: Vec<LogEvent> = events.try_iter().collect();
+    assert_eq!(removed.len(), 10);
+    assert!(removed
+        .iter()
+        .all(|e| e.cmd == LogCommand::Remove && e.val.is_none()));
+    removed.sort_by(|a, b| a.key.cmp(&b.key));
+    assert_eq!(removed[0].key, b"key0".to_vec());
+    assert_eq!(store.disk_usage()?, 5);
+    let files: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    assert_eq!(files, vec!["kv_store.log.1"]);
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // A store opened on a file keeps its file name.
+    let log_file = temp_dir.path().join("users.log");
+    let mut users = KvStore::open(&log_file)?;
+    users.set("key1".to_owned(), "value1".to_owned())?;
+    users.clear()?;
+    assert_eq!(users.log_file_path(), log_file);
+    assert_eq!(users.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// A log file can only be open in one store at a time, even within a single process.
+#[test]
+fn test_log_file_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(err.downcast::<LogLockedError>().is_ok());
+
+    // The compacted log is locked as well.
+    store.compact_log()?;
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(err.downcast::<LogLockedError>().is_ok());
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn test_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_compaction_ratio(temp_dir.path(), 3)?;
+    assert_eq!(store.stats()?, Stats::default());
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set("key2".to_owned(), "value4".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.get("key3".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(
+        stats,
+        Stats {
+            live_entries: 2,
+            total_entries: 4,
+            reads: 2,
+            writes: 4,
+            compactions: 0,
+        }
+    );
+    assert_eq!(stats.dead_ratio(), 0.5);
+
+    // The remove brings the log to three entries per live key, and compacts it.
+    store.remove("key2".to_owned())?;
+    assert_eq!(
+        store.stats()?,
+        Stats {
+            live_entries: 1,
+            total_entries: 1,
+            reads: 2,
+            writes: 5,
+            compactions: 1,
+        }
+    );
+    Ok(())
+}
+
+// Buffered writes reach the file once the store is flushed.
+#[test]
+fn test_flush() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    // Only the file header is on disk.
+    assert_eq!(fs::metadata(store.log_file_path())?.len(), 5);
+
+    store.flush()?;
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(
+            reader.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// A read-only store can be opened alongside the writer, and refuses writes.
+#[test]
+fn test_open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    // The reader only sees what has reached the file.
+    store.set_durability_mode(DurabilityMode::SyncEachWrite);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+
+    let mut reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value3".to_owned()));
+    let err = reader
+        .set("key2".to_owned(), "value2".to_owned())
+        .err()
+        .unwrap();
+    assert!(err.downcast::<ReadOnlyError>().is_ok());
+    let err = reader.remove("key1".to_owned()).err().unwrap();
+    assert!(err.downcast::<ReadOnlyError>().is_ok());
+    let err = reader.compact_log().err().unwrap();
+    assert!(err.downcast::<ReadOnlyError>().is_ok());
+
+    // Dropping it does not compact the log out from under the writer.
+    let log_file = store.log_file_path();
+    drop(reader);
+    assert!(log_file.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn test_rename_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    store.rename_key("key1".to_owned(), "key3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value1".to_owned()));
+
+    // The value moved over replaces the one already there.
+    store.rename_key("key3".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    let err = store
+        .rename_key("key1".to_owned(), "key4".to_owned())
+        .err()
+        .unwrap();
+    assert_eq!(
+        err.downcast::<KeyNotFoundError>()?.to_string(),
+        "Key not found: key1"
+    );
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    store.rename_key("key2".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+
+    // The rename survives a reopen.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A segmented store rolls over to new segment files as they fill, and reads resolve in any of
+// them, before and after a reopen.
+#[test]
+fn test_segmented_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let segment_files = || -> Vec<String> {
+        let mut files: Vec<String> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        files
+    };
+
+    let mut store = KvStore::open_with_segment_size(temp_dir.path(), 1024)?;
+    store.set_auto_compact(false);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert!(segment_files().len() > 2, "{:?}", segment_files());
+    assert!(segment_files()
+        .iter()
+        .all(|f| f.starts_with("kv_store.log.") && f.ends_with(".seg")));
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    drop(store);
+    let mut store = KvStore::open_with_segment_size(temp_dir.path(), 1024)?;
+    store.set_auto_compact(false);
+    assert_eq!(store.count()?, 100);
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+
+    // Overwriting the keys in the first segment leaves it all dead entries, compaction removes
+    // it and leaves the segments of live entries alone.
+    let first = segment_files()[0].clone();
+    let last_sealed = segment_files()[segment_files().len() - 2].clone();
+    let last_sealed_len = fs::metadata(temp_dir.path().join(&last_sealed))?.len();
+    for key_id in 0..30 {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    store.remove("key50".to_owned())?;
+    store.compact_log()?;
+    assert!(!segment_files().contains(&first));
+    assert_eq!(
+        fs::metadata(temp_dir.path().join(&last_sealed))?.len(),
+        last_sealed_len
+    );
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key50".to_owned())?, None);
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+
+    // The remove is still seen after the segments are rewritten and reopened.
+    drop(store);
+    let mut store = KvStore::open_with_segment_size(temp_dir.path(), 1024)?;
+    assert_eq!(store.count()?, 99);
+    assert_eq!(store.get("key50".to_owned())?, None);
+    assert_eq!(store.get("key29".to_owned())?, Some("new".to_owned()));
+
+    store.clear()?;
+    assert_eq!(segment_files().len(), 1);
+    assert_eq!(store.get("key99".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn test_get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    store.remove("key4".to_owned())?;
+
+    let keys = ["key3", "key2", "key1", "key4", "key1"];
+    let vals = store.get_many(keys.iter().map(|k| k.to_string()).collect())?;
+    assert_eq!(
+        vals,
+        vec![
+            Some("value3".to_owned()),
+            None,
+            Some("value1".to_owned()),
+            None,
+            Some("value1".to_owned()),
+        ]
+    );
+    assert_eq!(store.get_many(Vec::new())?, Vec::<Option<String>>::new());
+    Ok(())
+}
+
+#[test]
+fn test_transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+
+    // A transaction that is never committed leaves nothing behind, even after a reopen.
+    let mut txn = store.transaction();
+    for key_id in 1..4 {
+        txn.set(format!("key{}", key_id), format!("value{}", key_id));
+    }
+    drop(txn);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.count()?, 1);
+
+    let mut txn = store.transaction();
+    txn.set("key1".to_owned(), "value1".to_owned());
+    txn.remove("key1".to_owned());
+    txn.rollback();
+    assert_eq!(store.count()?, 1);
+
+    // A remove of a key that isn't set fails the whole transaction.
+    let mut txn = store.transaction();
+    txn.set("key1".to_owned(), "value1".to_owned());
+    txn.remove("key5".to_owned());
+    let err = txn.commit().err().unwrap();
+    assert!(err.downcast::<KeyNotFoundError>().is_ok());
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    let mut txn = store.transaction();
+    for key_id in 1..4 {
+        txn.set(format!("key{}", key_id), format!("value{}", key_id));
+    }
+    txn.remove("key0".to_owned());
+    txn.commit()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..4 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// Values over the configured limit are rejected without touching the log.
+#[test]
+fn test_max_value_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_durability_mode(DurabilityMode::SyncEachWrite);
+    store.set_max_value_size(Some(16));
+    store.set("key1".to_owned(), "a".repeat(16))?;
+
+    let log_len = fs::metadata(store.log_file_path())?.len();
+    let err = store.set("key2".to_owned(), "a".repeat(17)).err().unwrap();
+    assert!(err.downcast::<ValueTooLargeError>().is_ok());
+    assert_eq!(fs::metadata(store.log_file_path())?.len(), log_len);
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    store.set_max_value_size(None);
+    store.set("key2".to_owned(), "a".repeat(17))?;
+    assert_eq!(store.get("key2".to_owned())?, Some("a".repeat(17)));
+    Ok(())
+}
+
+// Encrypted values are not in the log file as plaintext, and only read back with the same key.
+#[cfg(feature = "encryption")]
+#[test]
+fn test_open_encrypted() -> Result<()> {
+    use kvs::DecryptionError;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_encrypted(temp_dir.path(), [7; 32])?;
+    store.set("key1".to_owned(), "secret value".to_owned())?;
+    store.compact_log()?;
+    store.set("key2".to_owned(), "other secret".to_owned())?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("secret value".to_owned())
+    );
+    drop(store);
+
+    let data = fs::read(KvStore::open_read_only(temp_dir.path())?.log_file_path())?;
+    assert!(!data.windows(6).any(|w| w == b"secret"));
+
+    let store = KvStore::open_encrypted(temp_dir.path(), [8; 32])?;
+    let err = store.get("key1".to_owned()).err().unwrap();
+    assert!(err.downcast::<DecryptionError>().is_ok());
+    drop(store);
+
+    let store = KvStore::open_encrypted(temp_dir.path(), [7; 32])?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("secret value".to_owned())
+    );
+    assert_eq!(
+        store.get("key2".to_owned())?,
+        Some("other secret".to_owned())
+    );
+    Ok(())
+}
+
+// Concurrent async sets and gets through clones of one store all see each other's writes.
+#[cfg(feature = "async")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_async_store() -> Result<()> {
+    use kvs::async_store::AsyncKvStore;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let store = AsyncKvStore::open(temp_dir.path()).await?;
+
+    let mut handles = Vec::new();
+    for key_id in 0..16 {
+        let mut store = store.clone();
+        handles.push(tokio::spawn(async move {
+            store
+                .set(format!("key{}", key_id), format!("value{}", key_id))
+                .await?;
+            store.get(format!("key{}", key_id)).await
+        }));
+    }
+    for (key_id, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.await??, Some(format!("value{}", key_id)));
+    }
+
+    let mut store = store;
+    store.remove("key0".to_owned()).await?;
+    assert_eq!(store.get("key0".to_owned()).await?, None);
+    assert_eq!(store.into_inner().count()?, 15);
+    Ok(())
+}
+
+// Values returns the current value of every key, once each.
+#[test]
+fn test_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.values()?.is_empty());
+
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("new{}", key_id))?;
+    }
+    for key_id in 40..50 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.set("copy".to_owned(), "value20".to_owned())?;
+
+    let mut expected: Vec<Vec<u8>> = (0..40)
+        .map(|key_id| match key_id {
+            0..=9 => format!("new{}", key_id),
+            _ => format!("value{}", key_id),
+        })
+        .chain(Some("value20".to_owned()))
+        .map(String::into_bytes)
+        .collect();
+    expected.sort();
+    let mut values = store.values()?;
+    values.sort();
+    assert_eq!(values, expected);
+    Ok(())
+}
+
+// Iterating the store yields exactly the keys and values that are set.
+#[test]
+fn test_iter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.iter().count(), 0);
+
+    let mut expected = HashMap::new();
+    for key_id in 0..100 {
+        let key = format!("key{}", key_id);
+        let value = format!("value{}", key_id);
+        store.set(key.clone(), value.clone())?;
+        expected.insert(key.into_bytes(), value.into_bytes());
+    }
+    for key_id in (0..100).step_by(3) {
+        let key = format!("key{}", key_id);
+        store.remove(key.clone())?;
+        expected.remove(key.as_bytes());
+    }
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    expected.insert(b"key1".to_vec(), b"changed".to_vec());
+
+    let pairs = store.iter().collect::<Result<HashMap<_, _>>>()?;
+    assert_eq!(pairs, expected);
+    Ok(())
+}
+
+// A watcher is sent every set and remove of its key, from any clone, and nothing for other keys.
+#[test]
+fn test_watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let watcher = store.watch("key1".to_owned());
+    let dropped = store.watch("key1".to_owned());
+    drop(dropped);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let mut other = store.clone();
+    other.set("key1".to_owned(), "value3".to_owned())?;
+    other.remove("key1".to_owned())?;
+
+    assert_eq!(watcher.try_recv(), Ok(Some("value1".to_owned())));
+    assert_eq!(watcher.try_recv(), Ok(Some("value3".to_owned())));
+    assert_eq!(watcher.try_recv(), Ok(None));
+    assert!(watcher.try_recv().is_err());
+
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    store.clear()?;
+    assert_eq!(watcher.try_recv(), Ok(Some("value4".to_owned())));
+    assert_eq!(watcher.try_recv(), Ok(None));
+    Ok(())
+}
+
+// Subscribers are sent every write in order, once it is synced to disk.
+#[test]
+fn test_subscribe() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let events = store.subscribe();
+    let other_events = store.clone().subscribe();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    // Buffered writes are only sent once flushed.
+    assert!(events.try_recv().is_err());
+    store.flush()?;
+
+    let set = LogEvent {
+        cmd: LogCommand::Set,
+        key: b"key1".to_vec(),
+        val: Some(b"value1".to_vec()),
+        expires_at: None,
+    };
+    let remove = LogEvent {
+        cmd: LogCommand::Remove,
+        key: b"key1".to_vec(),
+        val: None,
+        expires_at: None,
+    };
+    for receiver in [&events, &other_events] {
+        assert_eq!(receiver.try_recv(), Ok(set.clone()));
+        assert_eq!(receiver.try_recv(), Ok(remove.clone()));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    // Writes synced as they are made are sent straight away.
+    store.set_durability_mode(DurabilityMode::SyncEachWrite);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(events.try_recv()?.key, b"key2".to_vec());
+    Ok(())
+}
+
+// A follower applying the events of a leader ends up with the same contents, even when events
+// are applied twice, and its keys expire when the leader's do.
+#[test]
+fn test_apply_event() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary directory");
+    let clock = Arc::new(MockClock::new());
+    let mut leader = KvStore::builder()
+        .clock(clock.clone())
+        .open(leader_dir.path())?;
+    let mut follower = KvStore::builder()
+        .clock(clock.clone())
+        .open(follower_dir.path())?;
+    let events = leader.subscribe();
+
+    for key_id in 0..10 {
+        leader.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    leader.set("key3".to_owned(), "new3".to_owned())?;
+    leader.remove("key5".to_owned())?;
+    leader.set_many(vec![("key10".to_owned(), "value10".to_owned())])?;
+    leader.set_with_ttl(
+        "session".to_owned(),
+        "token".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    leader.flush()?;
+
+    let events: Vec<LogEvent> = events.try_iter().collect();
+    assert_eq!(events.len(), 14);
+    assert!(events[13].expires_at.is_some());
+    assert!(events[..13].iter().all(|e| e.expires_at.is_none()));
+    for event in events.iter().chain(&events) {
+        follower.apply_event(event.clone())?;
+    }
+
+    let mut keys = leader.keys()?;
+    keys.sort();
+    let mut follower_keys = follower.keys()?;
+    follower_keys.sort();
+    assert_eq!(follower_keys, keys);
+    for key in keys {
+        assert_eq!(follower.get(key.clone())?, leader.get(key)?);
+    }
+    assert_eq!(follower.get("key5".to_owned())?, None);
+    assert_eq!(
+        follower.get("session".to_owned())?,
+        Some("token".to_owned())
+    );
+    clock.advance(Duration::from_millis(100));
+    assert_eq!(follower.get("session".to_owned())?, None);
+
+    let err = follower
+        .apply_event(LogEvent {
+            cmd: LogCommand::Set,
+            key: b"key1".to_vec(),
+            val: None,
+            expires_at: None,
+        })
+        .unwrap_err();
+    assert!(err.downcast::<MissingEventValueError>().is_ok());
+    Ok(())
+}
+
+// The same key in two column families holds two separate values.
+#[test]
+fn test_column_families() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.list_cf()?.is_empty());
+
+    store.set("key1".to_owned(), "plain".to_owned())?;
+    store.set_cf("users", "key1".to_owned(), "user1".to_owned())?;
+    store.set_cf("orders", "key1".to_owned(), "order1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("plain".to_owned()));
+    assert_eq!(
+        store.get_cf("users", "key1".to_owned())?,
+        Some("user1".to_owned())
+    );
+    assert_eq!(
+        store.get_cf("orders", "key1".to_owned())?,
+        Some("order1".to_owned())
+    );
+    assert_eq!(store.list_cf()?, vec!["orders", "users"]);
+
+    store.remove_cf("users", "key1".to_owned())?;
+    assert_eq!(store.get_cf("users", "key1".to_owned())?, None);
+    assert_eq!(
+        store.get_cf("orders", "key1".to_owned())?,
+        Some("order1".to_owned())
+    );
+    assert_eq!(store.list_cf()?, vec!["orders"]);
+    let err = store.remove_cf("users", "key1".to_owned()).err().unwrap();
+    assert!(err.downcast::<KeyNotFoundError>().is_ok());
+
+    let err = store
+        .set_cf("bad\0name", "key1".to_owned(), "value".to_owned())
+        .err()
+        .unwrap();
+    assert!(err.downcast::<InvalidColumnFamilyError>().is_ok());
+    Ok(())
+}
@@ -1,8 +1,12 @@
 use assert_cmd::prelude::*;
+use kvs::server::KvsServer;
 use kvs::{KvStore, Result};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::fs;
+use std::net::TcpListener;
 use std::process::Command;
+use std::thread;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -17,7 +21,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -28,7 +32,7 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -41,7 +45,7 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -54,13 +58,34 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 }
 
+// The library logs through the `log` crate, so the CLI prints nothing to stderr of its own.
+#[test]
+fn cli_quiet_stderr() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim())
+        .stderr(is_empty());
+}
+
 #[test]
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -72,7 +97,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -80,7 +105,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -89,6 +114,41 @@ fn cli_get_stored() -> Result<()> {
     Ok(())
 }
 
+// `kvs get --format json <KEY>` should print a JSON object for both found and missing keys.
+#[test]
+fn cli_get_json() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "Key not found".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "--format", "json", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(r#"{"key":"key1","found":true,"value":"Key not found"}"#).trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2", "--format", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(r#"{"found":false}"#).trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--format", "yaml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
 // `kvs rm <KEY>` should print nothing and exit with zero.
 #[test]
 fn cli_rm_stored() -> Result<()> {
@@ -100,7 +160,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -108,7 +168,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -121,13 +181,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -136,19 +196,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -157,13 +217,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -172,9 +232,144 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
+        .assert()
+        .failure();
+}
+
+// `kvs-client` should run the `kvs` subcommands against the server at `--addr`.
+#[test]
+fn cli_client() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    thread::spawn(move || KvsServer::new(store).serve(listener));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", &addr]);
+        cmd
+    };
+    client(&["set", "key1", "value1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    client(&["rm", "key1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    client(&["rm", "key1"])
+        .assert()
+        .failure()
+        .stdout(eq("Key not found").trim());
+
+    Ok(())
+}
+
+// `kvs export` and `kvs import` should copy a store through a dump file.
+#[test]
+fn cli_export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dump = temp_dir.path().join("dump.json");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", dump.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", dump.to_str().unwrap()])
+        .current_dir(&other_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&other_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Ok(())
+}
+
+// `kvs verify` reports the records of a healthy store as good.
+#[test]
+fn cli_verify() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Good entries: 2"))
+        .stdout(contains("Corrupt entries: 0"));
+
+    Ok(())
+}
+
+// `kvs verify` checks a log damaged before the store is opened, without changing it, and doesn't
+// create a store where there is none.
+#[test]
+fn cli_verify_corrupt_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let log_file = store.log_file_path();
+    drop(store);
+    fs::remove_file(temp_dir.path().join("kv_store.log.0.idx"))?;
+
+    // Break the checksum of the entry for key1, which follows its value and absent expiry.
+    let mut data = fs::read(&log_file)?;
+    let value = data.windows(6).position(|w| w == b"value1").unwrap();
+    data[value + 6 + 1] ^= 0xff;
+    fs::write(&log_file, &data)?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("Good entries: 1"))
+        .stdout(contains("Corrupt entries: 1"));
+    assert_eq!(fs::read(&log_file)?, data);
+    assert!(!temp_dir.path().join("kv_store.log.0.idx").exists());
+
+    let empty_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&empty_dir)
         .assert()
         .failure();
+    assert_eq!(fs::read_dir(empty_dir.path())?.count(), 0);
+
+    Ok(())
 }
 
 // Should get previously stored value
@@ -191,7 +386,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -230,7 +425,7 @@ fn get_non_existent_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -289,7 +484,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -299,3 +494,146 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Compacting an empty store repeatedly should roll the log forward one file at a time.
+#[test]
+fn compaction_of_empty_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let log_files = || -> Vec<String> {
+        fs::read_dir(temp_dir.path())
+            .expect("unable to read temporary working directory")
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect()
+    };
+    assert_eq!(log_files(), vec!["kv_store.log.0"]);
+
+    for iter in 1..=10 {
+        store.compact_log()?;
+        assert_eq!(log_files(), vec![format!("kv_store.log.{}", iter)]);
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// `path` should be the store directory and `log_file_path` the current log file within it.
+#[test]
+fn store_paths() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.path(), temp_dir.path());
+    assert_eq!(
+        store.log_file_path(),
+        temp_dir.path().join("kv_store.log.0")
+    );
+
+    store.compact_log()?;
+    assert_eq!(store.path(), temp_dir.path());
+    assert_eq!(
+        store.log_file_path(),
+        temp_dir.path().join("kv_store.log.1")
+    );
+    assert!(store.log_file_path().is_file());
+
+    Ok(())
+}
+
+// `kvs keys` should print every key in sorted order, and `--prefix` only those starting with it.
+#[test]
+fn cli_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["keys"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user:2".to_owned(), "value2".to_owned())?;
+    store.set("order:1".to_owned(), "value3".to_owned())?;
+    store.set("user:1".to_owned(), "value1".to_owned())?;
+    store.set("user:3".to_owned(), "value4".to_owned())?;
+    store.remove("user:3".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["keys"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("order:1\nuser:1\nuser:2\n"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["keys", "--prefix", "user:"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("user:1\nuser:2\n"));
+    Ok(())
+}
+
+// `kvs incr` should print the new total, starting from 0 for a key that isn't set.
+#[test]
+fn cli_incr() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["incr", "counter", "5"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("5\n"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["incr", "counter", "-8"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("-3\n"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "counter"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("-3\n"));
+}
+
+// `kvs --dir <PATH>` should use the store in that directory rather than the current one.
+#[test]
+fn cli_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args([
+            "--dir",
+            data_dir.path().to_str().unwrap(),
+            "set",
+            "key1",
+            "value1",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    assert!(data_dir.path().join("kv_store.log.0").is_file());
+    assert_eq!(fs::read_dir(&temp_dir)?.count(), 0);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "-d", data_dir.path().to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1\n"));
+    Ok(())
+}